@@ -1,8 +1,8 @@
 use anyhow::Ok;
 use clap::{arg, command, Parser, Subcommand};
-use protobuf::{CodedInputStream, Message};
+use protobuf::{CodedInputStream, Message, MessageField};
 
-use rdeebee::wire_format::operation::Response;
+use rdeebee::wire_format::operation::{Metadata, Response};
 use std::{env, net::Ipv4Addr, str};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -16,9 +16,53 @@ use rdeebee_client::*;
 
 #[derive(Debug, Subcommand)]
 enum Action {
-    Read,
-    Write,
-    Delete,
+    /// Read the latest event of the key, or the latest one up to a sequence number.
+    Read {
+        #[arg(long)]
+        at: Option<u64>,
+    },
+    /// Write the payload, optionally only if the stream of the key is at the expected version.
+    Write {
+        #[arg(long)]
+        expect: Option<u64>,
+    },
+    /// Delete the key, optionally only if the stream of the key is at the expected version.
+    Delete {
+        #[arg(long)]
+        expect: Option<u64>,
+    },
+    /// Stream the events of the key, optionally bounded by (inclusive) sequence numbers.
+    Stream {
+        #[arg(long)]
+        start: Option<u64>,
+        #[arg(long)]
+        end: Option<u64>,
+    },
+    /// Scan the latest event of every key starting with the key,
+    /// or of every key within the (start inclusive, end exclusive) bounds.
+    Scan {
+        #[arg(long)]
+        start: Option<String>,
+        #[arg(long)]
+        end: Option<String>,
+    },
+    /// Read the events of every key in order of sequence numbers, from a sequence number on.
+    /// The key is ignored.
+    ReadAll {
+        #[arg(long)]
+        from: Option<u64>,
+        #[arg(long)]
+        limit: Option<u32>,
+    },
+    /// Receive every committed write and delete of the keys starting with the key, as they commit,
+    /// optionally of one event type only.
+    /// Pass the commit position of the last event received as the resume token to continue after it.
+    Subscribe {
+        #[arg(long = "type")]
+        event_type: Option<String>,
+        #[arg(long)]
+        resume: Option<u64>,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -30,6 +74,19 @@ struct Args {
     key: String,
     #[arg(short, long)]
     payload: Option<String>,
+    /// The type of the event written.
+    #[arg(short = 't', long)]
+    event_type: Option<String>,
+    /// The version of the schema of the event type the payload follows, the latest one by default.
+    #[arg(long)]
+    schema_version: Option<u32>,
+    #[arg(long)]
+    correlation_id: Option<String>,
+    #[arg(long)]
+    causation_id: Option<String>,
+    /// A header of the event written, as name=value. Repeat for more headers.
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
 }
 
 #[tokio::main]
@@ -67,11 +124,23 @@ async fn main() -> anyhow::Result<()> {
     println!("Created a new stream");
 
     let mut sequencer = SequenceSvc::new().await;
+    let streaming = matches!(
+        args.operation,
+        Action::Stream { .. } | Action::Scan { .. } | Action::ReadAll { .. }
+    );
+    let subscribing = matches!(args.operation, Action::Subscribe { .. });
 
     // let request = create_request(args.operation, &args.key, args.payload).await?;
-    let request = sequencer
+    let mut request = sequencer
         .create_request(args.operation, &args.key, args.payload)
         .await?;
+    let mut metadata = Metadata::new();
+    metadata.event_type = args.event_type.unwrap_or_default();
+    metadata.schema_version = args.schema_version.unwrap_or_default();
+    metadata.correlation_id = args.correlation_id.unwrap_or_default();
+    metadata.causation_id = args.causation_id.unwrap_or_default();
+    metadata.headers = args.headers.into_iter().collect();
+    request.metadata = MessageField::some(metadata);
 
     let request_bytes = request.write_length_delimited_to_bytes()?;
 
@@ -81,6 +150,47 @@ async fn main() -> anyhow::Result<()> {
 
     println!("awaiting reply...");
 
+    if subscribing {
+        // Every event is printed as it arrives, until the server hangs up.
+        let mut pending = Vec::new();
+        let mut buf = vec![0; 1024];
+        loop {
+            let n = stream
+                .read(&mut buf)
+                .await
+                .expect("Error reading from server");
+            if n == 0 {
+                return Ok(());
+            }
+            pending.extend_from_slice(&buf[..n]);
+            // A message split across reads waits for the rest of it.
+            let mut consumed = 0;
+            {
+                let mut input_stream = CodedInputStream::from_bytes(&pending);
+                while let Result::Ok(response) = input_stream.read_message::<Response>() {
+                    print_response(&response);
+                    consumed = input_stream.pos() as usize;
+                }
+            }
+            pending.drain(..consumed);
+        }
+    }
+
+    if streaming {
+        // The server closes the connection once the stream is exhausted.
+        let mut reply = Vec::new();
+        stream
+            .read_to_end(&mut reply)
+            .await
+            .expect("Error reading from server");
+        let mut input_stream = CodedInputStream::from_bytes(&reply);
+        while !input_stream.eof().expect("failed to read back") {
+            let response: Response = input_stream.read_message().expect("failed to read back");
+            print_response(&response);
+        }
+        return Ok(());
+    }
+
     let mut reply = vec![0; 1024];
     let n = stream
         .read(&mut reply)
@@ -89,18 +199,44 @@ async fn main() -> anyhow::Result<()> {
     if n != 0 {
         let mut input_stream = CodedInputStream::from_bytes(&reply);
         let response: Response = input_stream.read_message().expect("failed to read back");
-        println!("Response:");
-        println!("\tResponse Key: {:#?}", response.key);
-        println!(
-            "\tResponse Operation: {:#?}",
-            response.op.enum_value().unwrap()
-        );
-        println!("\tResponse Status: {:#?}", response.status);
-        if !response.payload.is_empty() {
-            let payload: String = bincode::deserialize(&response.payload).unwrap();
-            println!("\tPayload: {}", payload);
-        }
+        print_response(&response);
     }
 
     Ok(())
 }
+
+fn print_response(response: &Response) {
+    println!("Response:");
+    println!("\tResponse Key: {:#?}", response.key);
+    println!(
+        "\tResponse Operation: {:#?}",
+        response.op.enum_value().unwrap()
+    );
+    println!("\tResponse Status: {:#?}", response.status);
+    println!("\tResponse Sequence: {}", response.seq);
+    println!("\tResponse Version: {}", response.version);
+    if response.position > 0 {
+        println!("\tCommit Position: {}", response.position);
+    }
+    if let Some(metadata) = response.metadata.as_ref() {
+        println!("\tEvent Type: {}", metadata.event_type);
+        println!("\tSchema Version: {}", metadata.schema_version);
+        println!("\tTimestamp: {}", metadata.timestamp);
+        println!("\tCorrelation ID: {}", metadata.correlation_id);
+        println!("\tCausation ID: {}", metadata.causation_id);
+        for (name, value) in &metadata.headers {
+            println!("\tHeader {}: {}", name, value);
+        }
+    }
+    if !response.payload.is_empty() {
+        let payload: String = bincode::deserialize(&response.payload).unwrap();
+        println!("\tPayload: {}", payload);
+    }
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    header
+        .split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected name=value, got {header}"))
+}
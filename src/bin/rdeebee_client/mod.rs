@@ -38,9 +38,41 @@ impl SequenceSvc {
         let mut request = Request::new();
         request.key = key.to_string();
         request.op = match action {
-            Action::Read => EnumOrUnknown::new(Operation::Read),
-            Action::Write => EnumOrUnknown::new(Operation::Write),
-            Action::Delete => EnumOrUnknown::new(Operation::Delete),
+            Action::Read { at } => {
+                request.at_seq = at;
+                EnumOrUnknown::new(Operation::Read)
+            }
+            Action::Write { expect } => {
+                request.expected_version = expect;
+                EnumOrUnknown::new(Operation::Write)
+            }
+            Action::Delete { expect } => {
+                request.expected_version = expect;
+                EnumOrUnknown::new(Operation::Delete)
+            }
+            Action::Stream { start, end } => {
+                request.start_seq = start;
+                request.end_seq = end;
+                EnumOrUnknown::new(Operation::Stream)
+            }
+            Action::Scan { ref start, ref end } => {
+                request.start_key = start.clone();
+                request.end_key = end.clone();
+                EnumOrUnknown::new(Operation::Scan)
+            }
+            Action::ReadAll { from, limit } => {
+                request.start_seq = from;
+                request.limit = limit.unwrap_or_default();
+                EnumOrUnknown::new(Operation::ReadAll)
+            }
+            Action::Subscribe {
+                ref event_type,
+                resume,
+            } => {
+                request.event_type = event_type.clone().unwrap_or_default();
+                request.resume_token = resume;
+                EnumOrUnknown::new(Operation::Subscribe)
+            }
         };
 
         // If lock not released in 10 seconds,
@@ -85,14 +117,18 @@ impl SequenceSvc {
         }
 
         let seq = match action {
-            Action::Delete | Action::Write => match kv[0].value_str() {
+            Action::Delete { .. } | Action::Write { .. } => match kv[0].value_str() {
                 Ok(val) => match val.parse::<u64>() {
                     Ok(val) => val,
                     Err(e) => return Err(anyhow!("{e}")),
                 },
                 Err(e) => return Err(anyhow!("{e}")),
             },
-            Action::Read => 0,
+            Action::Read { .. }
+            | Action::Stream { .. }
+            | Action::Scan { .. }
+            | Action::ReadAll { .. }
+            | Action::Subscribe { .. } => 0,
         };
 
         request.seq = seq;
@@ -1,13 +1,26 @@
 use std::{
     borrow::{Borrow, BorrowMut},
+    ops::{Bound, RangeBounds},
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::anyhow;
+use futures_util::Stream;
 use parking_lot::RwLock;
-use rdeebee::{wire_format::operation, Node, RDeeBee, ServiceNode};
+use rdeebee::{
+    wire_format::operation, ChangeFilter, CompactionPolicy, CompressionOptions, Durability,
+    FilterOptions, Node, RDeeBee, ServiceNode, StorageEngineError, StorageMode, WriteBatch,
+};
+use tokio::task::spawn_blocking;
 use tracing::error;
 
+/// How long a request waits on the database lock before it fails.
+/// Group commits and table installs hold the write lock while they fsync the Wal or the manifest,
+/// so requests wait that out instead of failing.
+/// The wait blocks, so requests take the lock on the blocking pool and never on an async worker.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub(crate) struct RDeeBeeServer {
     rdeebee: Arc<RwLock<RDeeBee>>,
@@ -15,9 +28,23 @@ pub(crate) struct RDeeBeeServer {
 }
 
 impl RDeeBeeServer {
-    pub(crate) async fn new(compaction_size: usize, dir: String) -> anyhow::Result<Self> {
+    pub(crate) async fn new(
+        policy: Box<dyn CompactionPolicy>,
+        dir: String,
+        mode: StorageMode,
+        durability: Durability,
+        filter: FilterOptions,
+        compression: CompressionOptions,
+    ) -> anyhow::Result<Self> {
         Ok(Self {
-            rdeebee: Arc::new(RwLock::new(RDeeBee::new(compaction_size, dir)?)),
+            rdeebee: Arc::new(RwLock::new(RDeeBee::new(
+                policy,
+                dir,
+                mode,
+                durability,
+                filter,
+                compression,
+            )?)),
             cluster_node: Arc::new(RwLock::new(Node::new().await)),
         })
     }
@@ -33,14 +60,27 @@ impl RDeeBeeServer {
         }
     }
 
+    /// Flush the MemTable into an SSTable.
+    /// The new Wal and the table are created without the lock,
+    /// the write lock is only held to swap the MemTable and Wal and to install the SSTable,
+    /// so reads and writes continue while the flush runs.
     pub(crate) fn compact_memtable(&self) -> anyhow::Result<()> {
-        match self.rdeebee.as_ref().try_write() {
-            Some(mut guard) => match guard.try_memtable_compact() {
-                Ok(_) => Ok(()),
-                Err(e) => Err(anyhow!("{:#?}", e)),
-            },
-            None => Err(anyhow!("Failed to acquire lock in compact_memtable")),
+        let preparation = match self.rdeebee.as_ref().read().prepare_memtable_compact() {
+            Some(preparation) => preparation,
+            None => return Ok(()),
+        };
+        let files = preparation.run().map_err(|e| anyhow!("{:#?}", e))?;
+        let flush = self.rdeebee.as_ref().write().begin_memtable_compact(files);
+        let mut flush = match flush {
+            Ok(Some(flush)) => flush,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(anyhow!("{:#?}", e)),
+        };
+        let result = flush.run();
+        if let Err(e) = self.rdeebee.as_ref().write().finish_memtable_compact(flush) {
+            return Err(anyhow!("{:#?}", e));
         }
+        result.map_err(|e| anyhow!("{:#?}", e))
     }
 
     pub(crate) fn get_memtable_size(&self) -> Option<usize> {
@@ -50,49 +90,220 @@ impl RDeeBeeServer {
             .map(|guard| guard.get_memtable_size())
     }
 
+    pub(crate) fn memtable_compaction_due(&self) -> Option<bool> {
+        self.rdeebee
+            .as_ref()
+            .try_read()
+            .map(|guard| guard.memtable_compaction_due())
+    }
+
+    /// Run a compaction if the compaction policy picks one.
+    /// The tables are merged without holding the lock, and swapped in once merged.
     pub(crate) fn compact_sstables(&self) -> anyhow::Result<()> {
-        match self.rdeebee.as_ref().try_write() {
-            Some(mut guard) => match guard.try_sstables_compact() {
-                Ok(_) => Ok(()),
-                Err(e) => Err(anyhow!("{:#?}", e)),
-            },
-            None => Err(anyhow!("Failed to acquire lock in compact_memtable")),
+        let merge = self.rdeebee.as_ref().write().begin_sstables_compact();
+        let mut merge = match merge {
+            Ok(Some(merge)) => merge,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(anyhow!("{:#?}", e)),
+        };
+        let result = merge.run();
+        if let Err(e) = self.rdeebee.as_ref().write().finish_sstables_compact(merge) {
+            return Err(anyhow!("{:#?}", e));
         }
+        result.map_err(|e| anyhow!("{:#?}", e))
     }
 
-    pub(crate) fn get_event(&self, key: &str) -> Option<operation::Response> {
-        self.rdeebee
-            .as_ref()
-            .try_read()
-            .map(|guard| guard.get_event_by_key(key))
+    /// Get the latest event of the key, or the latest one up to the sequence number if there is one.
+    pub(crate) async fn get_event(
+        &self,
+        key: String,
+        at_seq: Option<u64>,
+    ) -> Option<operation::Response> {
+        let rdeebee = self.rdeebee.clone();
+        spawn_blocking(move || {
+            rdeebee
+                .as_ref()
+                .try_read_for(LOCK_TIMEOUT)
+                .map(|guard| match at_seq {
+                    Some(seq) => guard.get_event_by_key_at(&key, seq),
+                    None => guard.get_event_by_key(&key),
+                })
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Open a paged stream of the events of a key.
+    /// The read lock is only held while the stream is opened.
+    /// Returns None if the key doesn't exist.
+    pub(crate) async fn get_stream<R: RangeBounds<u64> + Send + 'static>(
+        &self,
+        key: String,
+        range: R,
+        page_size: usize,
+    ) -> anyhow::Result<
+        Option<impl Stream<Item = Result<Vec<operation::Response>, StorageEngineError>>>,
+    > {
+        let rdeebee = self.rdeebee.clone();
+        spawn_blocking(move || match rdeebee.as_ref().try_read_for(LOCK_TIMEOUT) {
+            Some(guard) => Ok(guard
+                .stream_by_key(&key, range)?
+                .map(|stream| stream.pages(page_size).into_async())),
+            None => Err(anyhow!("Failed to acquire lock in get_stream")),
+        })
+        .await?
+    }
+
+    /// Open a paged scan of the keys within the bounds, or of the keys starting with the prefix if there are none.
+    /// The read lock is only held while the scan takes its snapshot.
+    pub(crate) async fn scan(
+        &self,
+        prefix: String,
+        start: Option<String>,
+        end: Option<String>,
+        page_size: usize,
+    ) -> anyhow::Result<impl Stream<Item = Result<Vec<operation::Response>, StorageEngineError>>>
+    {
+        let rdeebee = self.rdeebee.clone();
+        spawn_blocking(move || {
+            let guard = match rdeebee.as_ref().try_read_for(LOCK_TIMEOUT) {
+                Some(guard) => guard,
+                None => return Err(anyhow!("Failed to acquire lock in scan")),
+            };
+            let scan = match (start.as_deref(), end.as_deref()) {
+                (None, None) => guard.scan_prefix(&prefix),
+                (start, end) => guard.scan((
+                    start.map_or(Bound::Unbounded, Bound::Included),
+                    end.map_or(Bound::Unbounded, Bound::Excluded),
+                )),
+            };
+            Ok(scan.pages(page_size).into_async())
+        })
+        .await?
+    }
+
+    /// Read the events of every key from the sequence number on, up to the limit.
+    pub(crate) async fn read_all(
+        &self,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<operation::Response>> {
+        let rdeebee = self.rdeebee.clone();
+        spawn_blocking(move || match rdeebee.as_ref().try_read_for(LOCK_TIMEOUT) {
+            Some(guard) => Ok(guard.read_all(from_seq, limit)?),
+            None => Err(anyhow!("Failed to acquire lock in read_all")),
+        })
+        .await?
+    }
+
+    /// Read the durable events the filter selects from the commit position on, up to the limit.
+    pub(crate) async fn read_changes(
+        &self,
+        filter: ChangeFilter,
+        from_position: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<operation::Response>> {
+        let rdeebee = self.rdeebee.clone();
+        spawn_blocking(move || match rdeebee.as_ref().try_read_for(LOCK_TIMEOUT) {
+            Some(guard) => Ok(guard.read_changes(&filter, from_position, limit)?),
+            None => Err(anyhow!("Failed to acquire lock in read_changes")),
+        })
+        .await?
+    }
+
+    /// Returns the response with the log sequence number to acknowledge it at.
+    /// Waits on the database lock, and fsyncs under `Durability::Sync`, so only call it on the blocking pool.
+    pub(crate) fn add_event(
+        &self,
+        request: operation::Request,
+    ) -> anyhow::Result<(operation::Response, u64)> {
+        match self.rdeebee.as_ref().try_write_for(LOCK_TIMEOUT) {
+            Some(mut guard) => {
+                let response = guard.add_event(request);
+                Ok((response, guard.appended_lsn()))
+            }
+            None => {
+                error!("Failed to acquire lock in add_event");
+                Err(anyhow!("Failed to acquire lock in add_event"))
+            }
+        }
     }
 
-    pub(crate) fn add_event(&self, request: operation::Request) -> anyhow::Result<()> {
-        match self.rdeebee.as_ref().try_write() {
+    /// Returns the response with the log sequence number to acknowledge it at.
+    /// Waits on the database lock, and fsyncs under `Durability::Sync`, so only call it on the blocking pool.
+    pub(crate) fn delete_event(
+        &self,
+        request: operation::Request,
+    ) -> anyhow::Result<(operation::Response, u64)> {
+        match self.rdeebee.as_ref().try_write_for(LOCK_TIMEOUT) {
             Some(mut guard) => {
-                guard.add_event(request);
-                Ok(())
+                let response = guard.delete_event(request);
+                Ok((response, guard.appended_lsn()))
             }
             None => {
-                error!("Failed to acquire lock in compact_memtable");
-                Err(anyhow!("Failed to acquire lock in compact_memtable"))
+                error!("Failed to acquire lock in delete_event");
+                Err(anyhow!("Failed to acquire lock in delete_event"))
             }
         }
     }
 
-    pub(crate) fn delete_event(&self, request: operation::Request) -> anyhow::Result<()> {
-        match self.rdeebee.as_ref().try_write() {
+    /// Apply the writes and deletes of a Batch request atomically.
+    /// Returns the response with the log sequence number to acknowledge it at.
+    /// Waits on the database lock, and fsyncs under `Durability::Sync`, so only call it on the blocking pool.
+    pub(crate) fn write_batch(
+        &self,
+        request: operation::Request,
+    ) -> anyhow::Result<(operation::Response, u64)> {
+        match self.rdeebee.as_ref().try_write_for(LOCK_TIMEOUT) {
             Some(mut guard) => {
-                guard.delete_event(request);
-                Ok(())
+                let mut response = guard.write_batch(WriteBatch::from(request.batch));
+                // Failures name the key at fault.
+                if response.key.is_empty() {
+                    response.key = request.key;
+                }
+                Ok((response, guard.appended_lsn()))
             }
             None => {
-                error!("Failed to acquire lock in compact_memtable");
-                Err(anyhow!("Failed to acquire lock in compact_memtable"))
+                error!("Failed to acquire lock in write_batch");
+                Err(anyhow!("Failed to acquire lock in write_batch"))
             }
         }
     }
 
+    pub(crate) fn durable_lsn(&self) -> Option<u64> {
+        self.rdeebee
+            .as_ref()
+            .try_read()
+            .map(|guard| guard.durable_lsn())
+    }
+
+    /// Get the commit position of the last event durable.
+    pub(crate) fn committed_position(&self) -> Option<u64> {
+        self.rdeebee
+            .as_ref()
+            .try_read()
+            .map(|guard| guard.committed_position())
+    }
+
+    /// Returns false if the lock is taken; the next check picks it up.
+    pub(crate) fn sync_due(&self) -> bool {
+        self.rdeebee
+            .as_ref()
+            .try_read()
+            .is_some_and(|guard| guard.sync_due())
+    }
+
+    /// fsync the Wal on the blocking pool.
+    pub(crate) async fn sync_wal(&self) -> anyhow::Result<()> {
+        let rdeebee = self.rdeebee.clone();
+        spawn_blocking(move || match rdeebee.as_ref().try_write_for(LOCK_TIMEOUT) {
+            Some(mut guard) => Ok(guard.sync_wal()?),
+            None => Err(anyhow!("Failed to acquire lock in sync_wal")),
+        })
+        .await?
+    }
+
     pub(crate) fn get_leaders(&self) -> anyhow::Result<Vec<ServiceNode>> {
         let leaders = self.cluster_node.as_ref().borrow().read().get_leaders()?;
         // .await?;
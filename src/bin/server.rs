@@ -1,18 +1,32 @@
 use std::{
     borrow::{Borrow, BorrowMut},
     collections::VecDeque,
-    env, str,
+    env, mem,
+    ops::Bound,
+    str,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::anyhow;
+use futures_util::{pin_mut, Stream, StreamExt};
 use parking_lot::RwLock;
 use protobuf::{CodedInputStream, EnumOrUnknown, Message};
-use rdeebee::wire_format::operation::{Operation, Request, Response, Status};
+use rdeebee::{
+    wire_format::operation::{Operation, Request, Response, Status},
+    ChangeFilter, CompressionOptions, Durability, FilterOptions, LeveledOptions, LeveledPolicy,
+    StorageEngineError, StorageMode,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    select,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    task::spawn_blocking,
+    time::{interval, sleep},
 };
 use tracing::{error, info, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -23,9 +37,23 @@ mod rdeebee_server;
 
 const PORT: u16 = 2048;
 const DEEBEE_FOLDER: &str = "/tmp/rdeebee";
-// const COMPACTION_SIZE: usize = 2048;
-const COMPACTION_SIZE: usize = 500;
+// const MEMTABLE_SIZE: usize = 2048;
+const MEMTABLE_SIZE: usize = 500;
 const QUEUE_CAPACITY: usize = 500;
+const STREAM_PAGE_SIZE: usize = 100;
+// Keep every event of a key, not just the latest one.
+const STORAGE_MODE: StorageMode = StorageMode::History;
+const GROUP_COMMIT_INTERVAL: Duration = Duration::from_millis(5);
+// fsync the wal every 5ms or 64KiB of writes, whichever comes first.
+const DURABILITY: Durability = Durability::GroupCommit {
+    interval: GROUP_COMMIT_INTERVAL,
+    max_bytes: 64 * 1024,
+};
+
+/// A write waiting to be applied, with the channel to acknowledge it on.
+type PendingWrite = (Request, oneshot::Sender<Response>);
+/// A write applied to the database, waiting for its log sequence number to be durable.
+type UnacknowledgedWrite = (u64, Response, oneshot::Sender<Response>);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -49,7 +77,22 @@ async fn main() -> anyhow::Result<()> {
 
     let addr = format!("127.0.0.1:{}", PORT);
 
-    let rdb_srv = match RDeeBeeServer::new(COMPACTION_SIZE, DEEBEE_FOLDER.to_string()).await {
+    // Leveled compaction keeps reads cheap.
+    // A write heavy deployment can swap in a `SizeTieredPolicy` instead.
+    let policy = Box::new(LeveledPolicy::new(LeveledOptions {
+        memtable_size: MEMTABLE_SIZE,
+        ..Default::default()
+    }));
+    let rdb_srv = match RDeeBeeServer::new(
+        policy,
+        DEEBEE_FOLDER.to_string(),
+        STORAGE_MODE,
+        DURABILITY,
+        FilterOptions::default(),
+        CompressionOptions::default(),
+    )
+    .await
+    {
         Ok(rdb_srv) => rdb_srv,
         Err(e) => return Err(e),
     };
@@ -102,7 +145,10 @@ async fn main() -> anyhow::Result<()> {
 
     let (event_sender, event_receiver) = unbounded_channel::<bool>();
 
-    let db_add_handler = add_events_to_db(rdb_get, event_queue_get, event_receiver);
+    // Subscriptions wait on the commit position of the last event durable.
+    let (commit_sender, commit_receiver) = watch::channel(0);
+
+    let db_add_handler = add_events_to_db(rdb_get, event_queue_get, event_receiver, commit_sender);
 
     let listener = TcpListener::bind(&addr).await?;
     info!("Server started on: {}", &listener.local_addr().unwrap());
@@ -114,6 +160,7 @@ async fn main() -> anyhow::Result<()> {
         event_sender,
         compaction_sender,
         event_queue,
+        commit_receiver,
     );
 
     let results = tokio::join!(compaction_handler, db_add_handler, main_thrd);
@@ -131,7 +178,8 @@ async fn main_task(
     rdb: RDeeBeeServer,
     event_sender: UnboundedSender<bool>,
     compaction_sender: UnboundedSender<bool>,
-    event_queue: Arc<RwLock<VecDeque<Request>>>,
+    event_queue: Arc<RwLock<VecDeque<PendingWrite>>>,
+    commits: watch::Receiver<u64>,
 ) -> anyhow::Result<()> {
     loop {
         let (socket, _) = listener.accept().await?;
@@ -139,6 +187,7 @@ async fn main_task(
         let rdb_clone = rdb.clone();
         let compaction_notifier = compaction_sender.clone();
         let event_queue = event_queue.clone();
+        let commits = commits.clone();
         tokio::spawn(async move {
             handle_client(
                 socket,
@@ -146,6 +195,7 @@ async fn main_task(
                 event_queue,
                 event_notifier,
                 compaction_notifier,
+                commits,
             )
             .await;
         });
@@ -154,49 +204,112 @@ async fn main_task(
 
 /// Add events to DB when notified about arrival of new event.
 /// Uses the Write Lock.
+/// Writes are acknowledged once the wal has made them durable.
+/// Under group commit, a timer drives the fsync for writes that did not reach the byte threshold.
+/// Subscriptions are woken once more events are durable.
 async fn add_events_to_db(
     rdb: RDeeBeeServer,
-    event_queue: Arc<RwLock<VecDeque<Request>>>,
+    event_queue: Arc<RwLock<VecDeque<PendingWrite>>>,
     mut event_notifier_receiver: UnboundedReceiver<bool>,
+    commits: watch::Sender<u64>,
 ) {
-    while let Some(event_added) = event_notifier_receiver.recv().await {
-        println!("Event notification received");
-        if !event_added {
-            continue;
-        }
-        match event_queue.as_ref().borrow_mut().try_write() {
-            Some(mut queue_guard) => {
-                while !queue_guard.is_empty() {
-                    match queue_guard.pop_front() {
-                        Some(event) => {
-                            match event.op.enum_value() {
-                                Ok(op) => match op {
-                                    Operation::Write =>
-                                    // TODO: retry???
-                                    {
-                                        if let Err(e) = rdb.add_event(event) {
-                                            error!("failed to add event: {}", e);
-                                        }
-                                    }
-                                    Operation::Delete =>
-                                    // TODO: retry???
-                                    {
-                                        if let Err(e) = rdb.delete_event(event) {
-                                            error!("failed to delete event: {}", e);
-                                        }
-                                    }
-                                    Operation::Read => {}
-                                },
-                                Err(_) => error!("failed to get operation from event"),
-                            }
-                        }
-                        None => error!("failed to get event from array"),
+    let mut unacknowledged: Vec<UnacknowledgedWrite> = Vec::new();
+    let mut group_commit = interval(GROUP_COMMIT_INTERVAL);
+    loop {
+        select! {
+            event_added = event_notifier_receiver.recv() => {
+                let event_added = match event_added {
+                    Some(event_added) => event_added,
+                    None => break,
+                };
+                println!("Event notification received");
+                // Applying waits on the database lock and may fsync, so keep it off the async workers.
+                if event_added {
+                    let (rdb, event_queue) = (rdb.clone(), event_queue.clone());
+                    match spawn_blocking(move || apply_events(&rdb, &event_queue)).await {
+                        Ok(applied) => unacknowledged.extend(applied),
+                        Err(e) => error!("apply task failed: {}", e),
                     }
                 }
+                println!("Database MemTable size: {:#?}", rdb.get_memtable_size());
+            }
+            _ = group_commit.tick() => {
+                if rdb.sync_due() {
+                    if let Err(e) = rdb.sync_wal().await {
+                        error!("failed to sync wal: {}", e);
+                    }
+                }
+            }
+        }
+        acknowledge_durable(&rdb, &mut unacknowledged);
+        if let Some(position) = rdb.committed_position() {
+            commits.send_if_modified(|committed| mem::replace(committed, position) != position);
+        }
+    }
+}
+
+/// Apply the queued events to the database.
+/// Failed writes are answered right away, the rest are returned to wait for the wal.
+/// Blocks on the database lock, so run it on the blocking pool.
+fn apply_events(
+    rdb: &RDeeBeeServer,
+    event_queue: &Arc<RwLock<VecDeque<PendingWrite>>>,
+) -> Vec<UnacknowledgedWrite> {
+    // Take the queued events out first, so requests can queue more while these wait on the database lock.
+    // Wait for the queue rather than give up: nothing else drains it until the next write arrives.
+    let pending: Vec<PendingWrite> = event_queue.as_ref().write().drain(..).collect();
+    let mut unacknowledged = Vec::new();
+    for (event, responder) in pending {
+        let mut response = Response::new();
+        response.key = event.key.clone();
+        response.op = event.op;
+        let result = match event.op.enum_value() {
+            Ok(Operation::Write) => rdb.add_event(event),
+            Ok(Operation::Delete) => rdb.delete_event(event),
+            Ok(Operation::Batch) => rdb.write_batch(event),
+            Ok(
+                Operation::Read
+                | Operation::Stream
+                | Operation::Scan
+                | Operation::ReadAll
+                | Operation::Subscribe,
+            )
+            | Err(_) => Err(anyhow!("failed to get write operation from event")),
+        };
+        match result {
+            Ok((response, lsn)) if response.status.enum_value() == Ok(Status::Ok) => {
+                unacknowledged.push((lsn, response, responder))
+            }
+            Ok((response, _)) => {
+                let _ = responder.send(response);
+            }
+            // TODO: retry???
+            Err(e) => {
+                error!("failed to apply event: {}", e);
+                response.status = EnumOrUnknown::new(Status::Server_Error);
+                let _ = responder.send(response);
             }
-            None => error!("failed to get event from array",),
         }
-        println!("Database MemTable size: {:#?}", rdb.get_memtable_size());
+    }
+    unacknowledged
+}
+
+/// Acknowledge the writes whose log sequence numbers are durable.
+fn acknowledge_durable(rdb: &RDeeBeeServer, unacknowledged: &mut Vec<UnacknowledgedWrite>) {
+    if unacknowledged.is_empty() {
+        return;
+    }
+    let durable_lsn = match rdb.durable_lsn() {
+        Some(lsn) => lsn,
+        None => return,
+    };
+    let (durable, pending) = mem::take(unacknowledged)
+        .into_iter()
+        .partition(|(lsn, _, _)| *lsn <= durable_lsn);
+    *unacknowledged = pending;
+    for (_, response, responder) in durable {
+        // The client may have hung up; the write is durable either way.
+        let _ = responder.send(response);
     }
 }
 
@@ -211,11 +324,28 @@ async fn compaction_thread(
         }
         // If new events have arrived, check the size of the MemTable.
         // And compact the MemTable if needed.
-        println!("size: {}", rdb.get_memtable_size().unwrap());
-        if rdb.get_memtable_size().is_some() && rdb.get_memtable_size().unwrap() > COMPACTION_SIZE {
+        // A writer holds the lock, so check again on the next event.
+        let size = match rdb.get_memtable_size() {
+            Some(size) => size,
+            None => continue,
+        };
+        println!("size: {}", size);
+        if rdb.memtable_compaction_due().unwrap_or(false) {
             println!("compacting");
-            rdb.compact_memtable()?;
-            rdb.compact_sstables()?;
+            // The flush and the merge write whole tables, so keep them off the async workers.
+            // They only lock the database briefly, so requests keep being served.
+            let rdb = rdb.clone();
+            let result = spawn_blocking(move || {
+                rdb.compact_memtable()?;
+                rdb.compact_sstables()
+            })
+            .await;
+            // A failed flush or merge is tried again on a later event.
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("failed to compact: {}", e),
+                Err(e) => error!("compaction task failed: {}", e),
+            }
         }
     }
     Ok(())
@@ -226,9 +356,10 @@ async fn compaction_thread(
 async fn handle_client(
     mut socket: TcpStream,
     rdb: RDeeBeeServer,
-    event_queue: Arc<RwLock<VecDeque<Request>>>,
+    event_queue: Arc<RwLock<VecDeque<PendingWrite>>>,
     event_notifier: UnboundedSender<bool>,
     compaction_notifier: UnboundedSender<bool>,
+    commits: watch::Receiver<u64>,
 ) {
     let mut buf = vec![0; 1024];
     let mut raw = Vec::new();
@@ -268,15 +399,15 @@ async fn handle_client(
 
     match request.op.enum_value() {
         Ok(op) => match op {
-            Operation::Delete | Operation::Write => {
+            Operation::Delete | Operation::Write | Operation::Batch => {
                 let mut event_added = false;
+                let (responder, acknowledgement) = oneshot::channel();
                 // Do we want a retry logic instead of failing the request?
                 match event_queue.as_ref().borrow_mut().try_write() {
                     Some(mut arr_write_guard) => {
-                        arr_write_guard.push_back(request);
+                        arr_write_guard.push_back((request, responder));
                         info!("added event");
                         event_added = true;
-                        response.status = EnumOrUnknown::new(Status::Ok);
                     }
                     None => {
                         error!("failed to get array lock for key: {}", response.key.clone());
@@ -299,10 +430,21 @@ async fn handle_client(
                     Err(e) => println!("didn't send: {}", e),
                 }
 
+                // Only reply once the write is applied and durable.
+                if event_added {
+                    response = match acknowledgement.await {
+                        Ok(response) => response,
+                        Err(_) => {
+                            response.status = EnumOrUnknown::new(Status::Server_Error);
+                            response
+                        }
+                    };
+                }
+
                 send_response(socket, response).await;
             }
             Operation::Read => {
-                match rdb.get_event(&request.key) {
+                match rdb.get_event(request.key, request.at_seq).await {
                     Some(response) => send_response(socket, response).await,
                     None => {
                         response.status = EnumOrUnknown::new(Status::Server_Error);
@@ -310,6 +452,71 @@ async fn handle_client(
                     }
                 };
             }
+            Operation::Stream => {
+                let start = request.start_seq.map_or(Bound::Unbounded, Bound::Included);
+                let end = request.end_seq.map_or(Bound::Unbounded, Bound::Included);
+                let page_size = match request.page_size {
+                    0 => STREAM_PAGE_SIZE,
+                    n => n as usize,
+                };
+                match rdb
+                    .get_stream(request.key.clone(), (start, end), page_size)
+                    .await
+                {
+                    Ok(Some(pages)) => {
+                        send_stream(socket, request.key, Operation::Stream, pages).await
+                    }
+                    Ok(None) => {
+                        response.status = EnumOrUnknown::new(Status::Invalid_Key);
+                        send_response(socket, response).await;
+                    }
+                    Err(e) => {
+                        error!("failed to open stream: {}", e);
+                        response.status = EnumOrUnknown::new(Status::Server_Error);
+                        send_response(socket, response).await;
+                    }
+                }
+            }
+            Operation::Scan => {
+                let page_size = match request.page_size {
+                    0 => STREAM_PAGE_SIZE,
+                    n => n as usize,
+                };
+                let pages = rdb
+                    .scan(
+                        request.key.clone(),
+                        request.start_key,
+                        request.end_key,
+                        page_size,
+                    )
+                    .await;
+                match pages {
+                    Ok(pages) => send_stream(socket, request.key, Operation::Scan, pages).await,
+                    Err(e) => {
+                        error!("failed to open scan: {}", e);
+                        response.status = EnumOrUnknown::new(Status::Server_Error);
+                        send_response(socket, response).await;
+                    }
+                }
+            }
+            Operation::ReadAll => {
+                let limit = match request.limit {
+                    0 => STREAM_PAGE_SIZE,
+                    n => n as usize,
+                };
+                match rdb.read_all(request.start_seq.unwrap_or(0), limit).await {
+                    Ok(page) => {
+                        let pages = futures_util::stream::iter([Ok(page)]);
+                        send_stream(socket, request.key, Operation::ReadAll, pages).await
+                    }
+                    Err(e) => {
+                        error!("failed to read all: {}", e);
+                        response.status = EnumOrUnknown::new(Status::Server_Error);
+                        send_response(socket, response).await;
+                    }
+                }
+            }
+            Operation::Subscribe => subscribe(socket, &rdb, &request, commits).await,
         },
         Err(e) => {
             error!("error getting operation: {}", e);
@@ -319,6 +526,100 @@ async fn handle_client(
     }
 }
 
+/// Write every response of the stream or scan, one page at a time.
+/// A read error is sent as a final `Server_Error` response.
+/// Closing the socket marks the end of the stream.
+async fn send_stream(
+    mut socket: TcpStream,
+    key: String,
+    op: Operation,
+    pages: impl Stream<Item = Result<Vec<Response>, StorageEngineError>>,
+) {
+    pin_mut!(pages);
+    while let Some(page) = pages.next().await {
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                error!("failed to read stream: {}", e);
+                let mut response = Response::new();
+                response.key = key;
+                response.op = EnumOrUnknown::new(op);
+                response.status = EnumOrUnknown::new(Status::Server_Error);
+                send_response(socket, response).await;
+                return;
+            }
+        };
+        for response in page {
+            let response_bytes = response.write_length_delimited_to_bytes().unwrap();
+            if let Err(e) = socket.write_all(&response_bytes).await {
+                error!("failed to write stream: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Push the committed events the subscription selects, in order, until the client hangs up.
+/// Catches up from the resume token first, then waits for more events to become durable.
+async fn subscribe(
+    mut socket: TcpStream,
+    rdb: &RDeeBeeServer,
+    request: &Request,
+    mut commits: watch::Receiver<u64>,
+) {
+    let mut filter = ChangeFilter::new().key_prefix(&request.key);
+    if !request.event_type.is_empty() {
+        filter = filter.event_type(&request.event_type);
+    }
+    let mut from_position = request
+        .resume_token
+        .map_or(0, |token| token.saturating_add(1));
+    loop {
+        // Take in the commits so far before reading, so none is missed while reading.
+        drop(commits.borrow_and_update());
+        let page = match rdb
+            .read_changes(filter.clone(), from_position, STREAM_PAGE_SIZE)
+            .await
+        {
+            Ok(page) => page,
+            // Such as a table failing to read.
+            Err(e) if e.downcast_ref::<StorageEngineError>().is_some() => {
+                error!("failed to read changes: {}", e);
+                let mut response = Response::new();
+                response.key = request.key.clone();
+                response.op = EnumOrUnknown::new(Operation::Subscribe);
+                // The storage mode doesn't keep the changes to subscribe to.
+                response.status = EnumOrUnknown::new(match e.downcast_ref() {
+                    Some(StorageEngineError::ChangesNeedHistory) => Status::Invalid_Op,
+                    _ => Status::Server_Error,
+                });
+                send_response(socket, response).await;
+                return;
+            }
+            // The lock is taken, try again shortly.
+            Err(e) => {
+                info!("subscription waiting: {}", e);
+                sleep(GROUP_COMMIT_INTERVAL).await;
+                continue;
+            }
+        };
+        if page.is_empty() {
+            if commits.changed().await.is_err() {
+                return;
+            }
+            continue;
+        }
+        for response in page {
+            from_position = response.position + 1;
+            let response_bytes = response.write_length_delimited_to_bytes().unwrap();
+            if let Err(e) = socket.write_all(&response_bytes).await {
+                info!("subscriber hung up: {}", e);
+                return;
+            }
+        }
+    }
+}
+
 async fn send_response(mut socket: TcpStream, response: Response) {
     let response_bytes = response.write_length_delimited_to_bytes().unwrap();
     let result = socket.write(&response_bytes).await.unwrap();
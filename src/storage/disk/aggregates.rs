@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tracing::warn;
+
+use crate::{
+    storage::{sync_dir, write_header, write_record, FileKind, RecordReader},
+    AggregateSnapshot, Codec, CompressionOptions, StorageEngineError,
+};
+
+/// The latest aggregate snapshot of every key, in a log of its own next to the Wals and SSTables.
+/// Snapshots are derived from the events, so they never go through the MemTable, flushes or merges,
+/// and only the latest snapshot of a key is kept.
+/// A snapshot is committed once it is appended and synced.
+/// The log is rewritten with the live snapshots once most of it is superseded.
+pub(crate) struct AggregateStore {
+    path: PathBuf,
+    file: File,
+    codec: Arc<dyn Codec>,
+    snapshots: HashMap<String, AggregateSnapshot>,
+    /// The number of records in the log, superseded ones included.
+    records: usize,
+}
+
+impl AggregateStore {
+    const NAME: &str = "AGGREGATES";
+    /// Small logs are left to grow, however much of them is superseded.
+    const MIN_REWRITE_RECORDS: usize = 1024;
+
+    /// Open the log in the directory, creating it if needed, and load the latest snapshot of every key.
+    /// A torn snapshot at the end was never committed, so it is ignored.
+    pub(crate) fn open(
+        dir: &Path,
+        compression: &CompressionOptions,
+    ) -> Result<Self, StorageEngineError> {
+        let path = dir.join(Self::NAME);
+        let mut snapshots: HashMap<String, AggregateSnapshot> = HashMap::new();
+        let mut records = 0;
+        let mut codec = compression.table_codec.clone();
+        let mut rewrite = true;
+        if path.exists() {
+            let file = BufReader::new(File::open(&path)?);
+            let mut reader = RecordReader::new(file, path.clone(), FileKind::Aggregates);
+            codec = reader.codec(compression)?;
+            let torn = loop {
+                let snapshot: AggregateSnapshot = match reader.next_record() {
+                    Ok(Some(data)) => bincode::deserialize(&codec.decompress(&data)?)?,
+                    Ok(None) => break false,
+                    Err(StorageEngineError::TruncatedRecord(_, offset)) => {
+                        warn!("ignoring torn snapshot at {} in {}", offset, path.display());
+                        break true;
+                    }
+                    Err(e) => return Err(e),
+                };
+                records += 1;
+                match snapshots.get(snapshot.key()) {
+                    Some(latest)
+                        if latest.id() == snapshot.id()
+                            && latest.version() > snapshot.version() => {}
+                    _ => {
+                        snapshots.insert(snapshot.key().to_string(), snapshot);
+                    }
+                }
+            };
+            rewrite = torn;
+        }
+        let mut store = Self {
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            path,
+            codec,
+            snapshots,
+            records,
+        };
+        if rewrite || store.records > store.snapshots.len() {
+            store.codec = compression.table_codec.clone();
+            store.rewrite()?;
+        }
+        Ok(store)
+    }
+
+    /// Get the latest snapshot of the key.
+    pub(crate) fn get(&self, key: &str) -> Option<&AggregateSnapshot> {
+        self.snapshots.get(key)
+    }
+
+    /// Append the snapshot and sync it, superseding the previous snapshot of its key.
+    pub(crate) fn put(&mut self, snapshot: AggregateSnapshot) -> Result<(), StorageEngineError> {
+        let data = self.codec.compress(&bincode::serialize(&snapshot)?);
+        write_record(&mut self.file, &data)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        self.snapshots.insert(snapshot.key().to_string(), snapshot);
+        if self.records > Self::MIN_REWRITE_RECORDS.max(2 * self.snapshots.len()) {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Drop the snapshots failing the predicate, rewriting the log if any was dropped.
+    pub(crate) fn retain<F: FnMut(&AggregateSnapshot) -> bool>(
+        &mut self,
+        mut keep: F,
+    ) -> Result<(), StorageEngineError> {
+        let len = self.snapshots.len();
+        self.snapshots.retain(|_, snapshot| keep(snapshot));
+        if self.snapshots.len() < len {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    /// Write the live snapshots to a new log, and swap it in.
+    fn rewrite(&mut self) -> Result<(), StorageEngineError> {
+        let temp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&temp_path)?;
+        write_header(&mut file, FileKind::Aggregates, self.codec.id())?;
+        for snapshot in self.snapshots.values() {
+            write_record(
+                &mut file,
+                &self.codec.compress(&bincode::serialize(snapshot)?),
+            )?;
+        }
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            sync_dir(dir)?;
+        }
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = self.snapshots.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+
+    use uuid::Uuid;
+
+    use super::AggregateStore;
+    use crate::{AggregateSnapshot, CompressionOptions};
+
+    #[test]
+    fn aggregate_store_test() {
+        // Unique to this process so concurrent test runs don't share it.
+        let dir =
+            &std::env::temp_dir().join(format!("rdeebee-aggregate-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let compression = CompressionOptions::default();
+        let (cart, order) = (Uuid::new_v4(), Uuid::new_v4());
+        {
+            let mut store = AggregateStore::open(dir, &compression).unwrap();
+            store
+                .put(AggregateSnapshot::new("cart", cart, 2, b"two".to_vec()))
+                .unwrap();
+            store
+                .put(AggregateSnapshot::new("cart", cart, 5, b"five".to_vec()))
+                .unwrap();
+            store
+                .put(AggregateSnapshot::new("order", order, 1, b"one".to_vec()))
+                .unwrap();
+        }
+        // Tear the last snapshot, as a crash halfway through the write would.
+        let path = dir.join(AggregateStore::NAME);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 2)
+            .unwrap();
+
+        let mut store = AggregateStore::open(dir, &compression).unwrap();
+        assert_eq!(store.get("cart").unwrap().state(), b"five");
+        assert!(store.get("order").is_none());
+        assert_eq!(store.records, 1);
+
+        store.retain(|snapshot| snapshot.key() != "cart").unwrap();
+        assert!(AggregateStore::open(dir, &compression)
+            .unwrap()
+            .get("cart")
+            .is_none());
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::BufReader,
+    ops::Range,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    storage::{record_follows, sync_dir, write_header, write_record, FileKind, RecordReader},
+    Codec, NoCompression, StorageEngineError,
+};
+
+/// A table file and the level it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TableFile {
+    pub(crate) id: u64,
+    pub(crate) level: usize,
+}
+
+/// A change to the set of live files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ManifestEdit {
+    /// Tables added, in order.
+    /// Tables added to L0 take the place of the first L0 table removed, or go after the newest.
+    pub(crate) added: Vec<TableFile>,
+    /// IDs of the tables removed.
+    pub(crate) removed: Vec<u64>,
+    /// IDs of the Wals started.
+    pub(crate) new_wals: Vec<u64>,
+    /// Every Wal with a lower ID is flushed, and no longer live.
+    pub(crate) log_number: Option<u64>,
+    /// No ID this high or higher was reserved yet. Set by `Manifest::append`.
+    pub(crate) next_file_id: u64,
+    /// No event appended before the edit has a higher commit position.
+    pub(crate) last_position: u64,
+}
+
+/// The live files, as of the last committed edit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Version {
+    /// The table IDs of every level, L0 oldest first.
+    pub(crate) levels: Vec<Vec<u64>>,
+    /// The IDs of the live Wals, oldest first.
+    pub(crate) wals: Vec<u64>,
+    /// Every file of a lower ID was created by the database, see `Manifest::reserve_file_ids`.
+    pub(crate) next_file_id: u64,
+    /// Commit positions up to this one are taken, even by events a merge dropped since.
+    pub(crate) last_position: u64,
+}
+
+impl Version {
+    fn apply(&mut self, edit: &ManifestEdit) {
+        let mut at = self
+            .levels
+            .first()
+            .and_then(|l0| l0.iter().position(|id| edit.removed.contains(id)));
+        for level in self.levels.iter_mut() {
+            level.retain(|id| !edit.removed.contains(id));
+        }
+        for table in &edit.added {
+            if self.levels.len() <= table.level {
+                self.levels.resize(table.level + 1, Vec::new());
+            }
+            match (table.level, at.as_mut()) {
+                (0, Some(at)) => {
+                    self.levels[0].insert(*at, table.id);
+                    *at += 1;
+                }
+                (level, _) => self.levels[level].push(table.id),
+            }
+        }
+        self.wals.extend(&edit.new_wals);
+        if let Some(log_number) = edit.log_number {
+            self.wals.retain(|id| *id >= log_number);
+        }
+        self.next_file_id = self.next_file_id.max(edit.next_file_id);
+        self.last_position = self.last_position.max(edit.last_position);
+    }
+
+    /// A single edit building this version from scratch.
+    fn snapshot(&self) -> ManifestEdit {
+        let added = self
+            .levels
+            .iter()
+            .enumerate()
+            .flat_map(|(level, ids)| ids.iter().map(move |id| TableFile { id: *id, level }))
+            .collect();
+        ManifestEdit {
+            added,
+            new_wals: self.wals.clone(),
+            next_file_id: self.next_file_id,
+            last_position: self.last_position,
+            ..Default::default()
+        }
+    }
+}
+
+/// The manifest is a log of the edits made to the set of live files, LevelDB style.
+/// It is the source of truth for the tables and Wals recovery opens,
+/// any other file in the directory is left over from a crash.
+/// An edit is committed once it is appended and synced,
+/// so a merge is installed atomically no matter how many files it adds or removes.
+/// Files are only created before their edit is committed, and only deleted after.
+/// Their IDs are reserved in an edit of their own, committed before the files are created.
+pub(crate) struct Manifest {
+    file: File,
+    /// The offset the committed edits end at.
+    end: u64,
+    /// Did an append fail, leaving a torn edit after `end`?
+    torn: bool,
+    version: Version,
+    next_file_id: u64,
+}
+
+impl Manifest {
+    const NAME: &str = "MANIFEST";
+
+    /// Open the manifest in the directory, creating it if needed, and replay its edits.
+    /// A torn edit at the end, cut short or garbled, was never committed, so it is cut off.
+    /// A bad edit with valid edits after it is corruption, and an error.
+    /// The edits are rewritten as a single one, so the log doesn't grow across restarts.
+    pub(crate) fn open(dir: &Path) -> Result<Self, StorageEngineError> {
+        let path = dir.join(Self::NAME);
+        let mut version = Version::default();
+        let mut rewrite = true;
+        if path.exists() {
+            let (edits, end) = Self::read_edits(&path)?;
+            if end < fs::metadata(&path)?.len() {
+                warn!("cutting off torn edit at {} in {}", end, path.display());
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(end)?;
+                file.sync_all()?;
+            }
+            for edit in &edits {
+                version.apply(edit);
+            }
+            rewrite = edits.len() != 1;
+        }
+        if rewrite {
+            let temp_path = path.with_extension("tmp");
+            let mut file = File::create(&temp_path)?;
+            write_header(&mut file, FileKind::Manifest, NoCompression.id())?;
+            write_record(&mut file, &bincode::serialize(&version.snapshot())?)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)?;
+            sync_dir(dir)?;
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Self {
+            end: file.metadata()?.len(),
+            torn: false,
+            file,
+            next_file_id: version.next_file_id.max(1),
+            version,
+        })
+    }
+
+    /// Returns the edits along with the offset the committed edits end at.
+    fn read_edits(path: &Path) -> Result<(Vec<ManifestEdit>, u64), StorageEngineError> {
+        let file = BufReader::new(File::open(path)?);
+        let mut reader = RecordReader::new(file, path.to_owned(), FileKind::Manifest);
+        let mut edits = Vec::new();
+        let end = loop {
+            match reader.next_record() {
+                Ok(Some(data)) => edits.push(bincode::deserialize(&data)?),
+                Ok(None) => break reader.offset(),
+                Err(e) => match e.bad_record_offset() {
+                    Some(offset) if !record_follows(path, offset)? => break offset,
+                    _ => return Err(e),
+                },
+            }
+        };
+        Ok((edits, end))
+    }
+
+    /// Get the live files.
+    pub(crate) fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// Reserve the next `count` file IDs, committing them before any file takes one.
+    /// Recovery only removes the files of reserved IDs, as only those can be left over from a crash.
+    pub(crate) fn reserve_file_ids(
+        &mut self,
+        count: u64,
+    ) -> Result<Range<u64>, StorageEngineError> {
+        let start = self.next_file_id;
+        self.next_file_id += count;
+        self.append(ManifestEdit::default())?;
+        Ok(start..self.next_file_id)
+    }
+
+    /// Append the edit and sync it, committing it.
+    /// The edit records the next file ID, so an ID is never reserved twice.
+    /// An edit that failed to append is cut off first, so no edit is written behind a torn one.
+    pub(crate) fn append(&mut self, mut edit: ManifestEdit) -> Result<(), StorageEngineError> {
+        edit.next_file_id = self.next_file_id;
+        let data = bincode::serialize(&edit)?;
+        if self.torn {
+            self.file.set_len(self.end)?;
+            self.torn = false;
+        }
+        let result = write_record(&mut self.file, &data).and_then(|written| {
+            self.file.sync_data()?;
+            Ok(written)
+        });
+        match result {
+            Ok(written) => self.end += written as u64,
+            Err(e) => {
+                self.torn = true;
+                return Err(e.into());
+            }
+        }
+        self.version.apply(&edit);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        path::PathBuf,
+    };
+
+    use super::{Manifest, ManifestEdit, TableFile};
+    use crate::StorageEngineError;
+
+    /// An empty directory for the test, unique to this process so concurrent test runs don't share it.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("rdeebee-manifest-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn table(id: u64, level: usize) -> TableFile {
+        TableFile { id, level }
+    }
+
+    #[test]
+    fn manifest_version_test() {
+        let dir = &test_dir("version");
+        let mut manifest = Manifest::open(dir).unwrap();
+        let mut ids = manifest.reserve_file_ids(9).unwrap();
+        let mut next = || ids.next().unwrap();
+        let wal = next();
+        manifest
+            .append(ManifestEdit {
+                new_wals: vec![wal],
+                ..Default::default()
+            })
+            .unwrap();
+        for id in [next(), next(), next()] {
+            manifest
+                .append(ManifestEdit {
+                    added: vec![table(id, 0)],
+                    log_number: Some(next()),
+                    last_position: id * 10,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        // Merge the two oldest L0 tables in place, then move the newest into L1.
+        let (merged, moved) = (next(), next());
+        manifest
+            .append(ManifestEdit {
+                added: vec![table(merged, 0)],
+                removed: vec![2, 3],
+                ..Default::default()
+            })
+            .unwrap();
+        manifest
+            .append(ManifestEdit {
+                added: vec![table(moved, 1)],
+                removed: vec![4],
+                ..Default::default()
+            })
+            .unwrap();
+        let version = manifest.version().clone();
+        assert_eq!(version.levels, vec![vec![merged], vec![moved]]);
+        assert!(version.wals.is_empty());
+        assert_eq!(version.next_file_id, 10);
+        // Merges leave the last commit position as the flushes set it.
+        assert_eq!(version.last_position, 40);
+
+        // Simulate a crash in the middle of appending an edit.
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(Manifest::NAME))
+            .unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(manifest);
+
+        let mut manifest = Manifest::open(dir).unwrap();
+        assert_eq!(manifest.version(), &version);
+        assert_eq!(manifest.reserve_file_ids(1).unwrap(), 10..11);
+        drop(manifest);
+        assert_eq!(Manifest::open(dir).unwrap().version().next_file_id, 11);
+    }
+
+    #[test]
+    fn manifest_torn_edit_test() {
+        let dir = &test_dir("torn");
+        let path = dir.join(Manifest::NAME);
+        let tear = || {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        };
+
+        // The torn edit is cut off on open, even when the edits aren't rewritten.
+        drop(Manifest::open(dir).unwrap());
+        let end = fs::metadata(&path).unwrap().len();
+        tear();
+        let mut manifest = Manifest::open(dir).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), end);
+
+        // Simulate an append that failed halfway, then append the next edit.
+        tear();
+        manifest.torn = true;
+        let wal = manifest.reserve_file_ids(1).unwrap().start;
+        manifest
+            .append(ManifestEdit {
+                new_wals: vec![wal],
+                ..Default::default()
+            })
+            .unwrap();
+        drop(manifest);
+        assert_eq!(Manifest::open(dir).unwrap().version().wals, vec![wal]);
+    }
+
+    #[test]
+    fn manifest_corrupt_edit_test() {
+        let dir = &test_dir("corrupt");
+        let path = dir.join(Manifest::NAME);
+        let mut manifest = Manifest::open(dir).unwrap();
+        let wal = manifest.reserve_file_ids(1).unwrap().start;
+        manifest
+            .append(ManifestEdit {
+                new_wals: vec![wal],
+                ..Default::default()
+            })
+            .unwrap();
+        drop(manifest);
+
+        // An edit garbled rather than cut short by a crash is cut off too.
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        let manifest = Manifest::open(dir).unwrap();
+        assert!(manifest.version().wals.is_empty());
+        assert_eq!(manifest.version().next_file_id, wal + 1);
+        drop(manifest);
+
+        // Corruption followed by valid edits isn't a torn write.
+        let mut manifest = Manifest::open(dir).unwrap();
+        let end = fs::metadata(&path).unwrap().len();
+        manifest.reserve_file_ids(1).unwrap();
+        manifest.reserve_file_ids(1).unwrap();
+        drop(manifest);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[end as usize + 9] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+        assert!(matches!(
+            Manifest::open(dir),
+            Err(StorageEngineError::ChecksumMismatch(_, offset)) if offset == end
+        ));
+    }
+}
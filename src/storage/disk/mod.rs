@@ -1,5 +1,18 @@
+use std::{fs::File, io, path::Path};
+
+mod aggregates;
+mod manifest;
+mod record;
 mod sstable;
 mod wal;
 
+pub(crate) use aggregates::*;
+pub(crate) use manifest::*;
+pub(crate) use record::*;
 pub(crate) use sstable::*;
 pub(crate) use wal::*;
+
+/// fsync a directory, so the files created in it survive a crash.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
@@ -0,0 +1,350 @@
+use std::{
+    fs,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{Codec, CompressionOptions, StorageEngineError};
+
+/// On-disk framing shared by the Wal, the SSTables and the manifest.
+///
+/// Every file starts with a header:
+///
+/// | magic (4) | format version (u16 LE) | file kind (1) | codec ID (1) |
+///
+/// The codec compressed the data of the records, see `Codec`.
+///
+/// followed by records:
+///
+/// | length (u32 LE) | CRC32 of data (u32 LE) | data (length bytes) |
+///
+/// The length prefix means record data can hold any byte,
+/// and the checksum catches torn or corrupted writes.
+const MAGIC: [u8; 4] = *b"RDEE";
+pub(crate) const HEADER_SIZE: usize = 8;
+const RECORD_HEADER_SIZE: usize = 8;
+/// Anything larger is treated as a corrupted length prefix rather than allocated.
+const MAX_RECORD_SIZE: u32 = 64 * 1024 * 1024;
+
+/// The kind of file, so a Wal is never read as an SSTable or vice versa.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FileKind {
+    Wal = 1,
+    Table = 2,
+    Manifest = 3,
+    Aggregates = 4,
+}
+
+impl FileKind {
+    /// The format version of each kind of file.
+    /// Bump it whenever the layout of that kind changes.
+    fn version(self) -> u16 {
+        match self {
+            FileKind::Wal => 1,
+            FileKind::Table => 1,
+            FileKind::Manifest => 1,
+            FileKind::Aggregates => 1,
+        }
+    }
+}
+
+/// Write the file header. Must be the first thing written to a new file.
+pub(crate) fn write_header<W: Write>(
+    writer: &mut W,
+    kind: FileKind,
+    codec_id: u8,
+) -> io::Result<()> {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..6].copy_from_slice(&kind.version().to_le_bytes());
+    header[6] = kind as u8;
+    header[7] = codec_id;
+    writer.write_all(&header)
+}
+
+/// Frame and write one record.
+/// Returns the number of bytes written.
+pub(crate) fn write_record<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<usize> {
+    let len = u32::try_from(data.len())
+        .ok()
+        .filter(|len| *len <= MAX_RECORD_SIZE)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "record too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(data).to_le_bytes())?;
+    writer.write_all(data)?;
+    Ok(RECORD_HEADER_SIZE + data.len())
+}
+
+/// Does a valid record start anywhere in the file past the bad record at the offset?
+/// Tells a torn write at the end of a file from corruption followed by more records.
+/// The length prefix of the bad record may be garbled too, so every offset after it is tried.
+pub(crate) fn record_follows(path: &Path, offset: u64) -> io::Result<bool> {
+    let bytes = fs::read(path)?;
+    let start = (offset as usize + 1).min(bytes.len());
+    Ok((start..bytes.len()).any(|at| is_record(&bytes[at..])))
+}
+
+/// Does a whole, non-empty record with a valid checksum start at the beginning of the bytes?
+fn is_record(bytes: &[u8]) -> bool {
+    if bytes.len() < RECORD_HEADER_SIZE {
+        return false;
+    }
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(bytes[4..RECORD_HEADER_SIZE].try_into().unwrap());
+    let data = &bytes[RECORD_HEADER_SIZE..];
+    len > 0 && len <= data.len() && crc32fast::hash(&data[..len]) == crc
+}
+
+/// Reads the records of a file, validating the header before the first record.
+/// An empty file holds no records.
+/// After the first error the reader returns no more records.
+pub(crate) struct RecordReader<R> {
+    reader: R,
+    path: PathBuf,
+    kind: FileKind,
+    offset: u64,
+    header_checked: bool,
+    codec_id: u8,
+    done: bool,
+}
+
+impl<R: Read> RecordReader<R> {
+    pub(crate) fn new(reader: R, path: PathBuf, kind: FileKind) -> Self {
+        Self {
+            reader,
+            path,
+            kind,
+            offset: 0,
+            header_checked: false,
+            codec_id: 0,
+            done: false,
+        }
+    }
+
+    /// Get the path of the file being read.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the offset of the next record.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Get the codec recorded in the file header, among the compression options.
+    /// The header is validated first, if it hasn't been already.
+    /// An empty file holds no records, so any codec reads it.
+    pub(crate) fn codec(
+        &mut self,
+        compression: &CompressionOptions,
+    ) -> Result<Arc<dyn Codec>, StorageEngineError> {
+        if !self.header_checked {
+            self.header_checked = self.read_header()?;
+        }
+        compression
+            .codec(self.codec_id)
+            .ok_or_else(|| StorageEngineError::UnknownCodec(self.path.clone(), self.codec_id))
+    }
+
+    /// Get the next record.
+    /// Returns None at the end of the file.
+    pub(crate) fn next_record(&mut self) -> Result<Option<Vec<u8>>, StorageEngineError> {
+        if self.done {
+            return Ok(None);
+        }
+        let record = self.read_record();
+        if !matches!(record, Ok(Some(_))) {
+            self.done = true;
+        }
+        record
+    }
+
+    fn read_record(&mut self) -> Result<Option<Vec<u8>>, StorageEngineError> {
+        if !self.header_checked {
+            if !self.read_header()? {
+                return Ok(None);
+            }
+            self.header_checked = true;
+        }
+
+        let start = self.offset;
+        let mut record_header = [0u8; RECORD_HEADER_SIZE];
+        match self.read_full(&mut record_header)? {
+            0 => return Ok(None),
+            RECORD_HEADER_SIZE => {}
+            _ => {
+                return Err(StorageEngineError::TruncatedRecord(
+                    self.path.clone(),
+                    start,
+                ))
+            }
+        }
+        let len = u32::from_le_bytes(record_header[..4].try_into().unwrap());
+        let crc = u32::from_le_bytes(record_header[4..].try_into().unwrap());
+        if len > MAX_RECORD_SIZE {
+            return Err(StorageEngineError::InvalidRecordLength(
+                self.path.clone(),
+                start,
+            ));
+        }
+
+        let mut data = vec![0u8; len as usize];
+        if self.read_full(&mut data)? != data.len() {
+            return Err(StorageEngineError::TruncatedRecord(
+                self.path.clone(),
+                start,
+            ));
+        }
+        if crc32fast::hash(&data) != crc {
+            return Err(StorageEngineError::ChecksumMismatch(
+                self.path.clone(),
+                start,
+            ));
+        }
+        Ok(Some(data))
+    }
+
+    /// Returns false if the file is empty.
+    fn read_header(&mut self) -> Result<bool, StorageEngineError> {
+        let mut header = [0u8; HEADER_SIZE];
+        match self.read_full(&mut header)? {
+            0 => return Ok(false),
+            HEADER_SIZE => {}
+            _ => return Err(StorageEngineError::TruncatedRecord(self.path.clone(), 0)),
+        }
+        if header[..4] != MAGIC || header[6] != self.kind as u8 {
+            return Err(StorageEngineError::InvalidFileHeader(self.path.clone()));
+        }
+        let version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        if version != self.kind.version() {
+            return Err(StorageEngineError::UnsupportedFormatVersion(
+                self.path.clone(),
+                version,
+            ));
+        }
+        self.codec_id = header[7];
+        Ok(true)
+    }
+
+    /// Fill the buffer unless the file ends first.
+    /// Returns the number of bytes read.
+    fn read_full(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.reader.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> RecordReader<R> {
+    /// Move to the record at the offset.
+    /// The file header is validated first, if it hasn't been already.
+    pub(crate) fn seek(&mut self, offset: u64) -> Result<(), StorageEngineError> {
+        if !self.header_checked {
+            if !self.read_header()? {
+                return Err(StorageEngineError::TruncatedRecord(self.path.clone(), 0));
+            }
+            self.header_checked = true;
+        }
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        self.done = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, path::PathBuf};
+
+    use super::{write_header, write_record, FileKind, RecordReader};
+    use crate::StorageEngineError;
+
+    fn framed(records: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, FileKind::Wal, 0).unwrap();
+        for record in records {
+            write_record(&mut bytes, record).unwrap();
+        }
+        bytes
+    }
+
+    fn reader(bytes: Vec<u8>) -> RecordReader<Cursor<Vec<u8>>> {
+        RecordReader::new(Cursor::new(bytes), PathBuf::from("test"), FileKind::Wal)
+    }
+
+    #[test]
+    fn record_roundtrip_test() {
+        let mut reader = reader(framed(&[b"first|record", b"", b"|||"]));
+        assert_eq!(reader.next_record().unwrap().unwrap(), b"first|record");
+        assert_eq!(reader.next_record().unwrap().unwrap(), b"");
+        assert_eq!(reader.next_record().unwrap().unwrap(), b"|||");
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn record_empty_file_test() {
+        assert!(reader(Vec::new()).next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn record_checksum_test() {
+        let mut bytes = framed(&[b"first", b"second"]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let mut reader = reader(bytes);
+        assert!(reader.next_record().unwrap().is_some());
+        assert!(matches!(
+            reader.next_record(),
+            Err(StorageEngineError::ChecksumMismatch(_, 21))
+        ));
+        assert!(reader.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn record_truncated_test() {
+        let mut bytes = framed(&[b"first", b"second"]);
+        bytes.truncate(bytes.len() - 2);
+        let mut reader = reader(bytes);
+        assert!(reader.next_record().unwrap().is_some());
+        assert!(matches!(
+            reader.next_record(),
+            Err(StorageEngineError::TruncatedRecord(_, 21))
+        ));
+    }
+
+    #[test]
+    fn record_seek_test() {
+        let bytes = framed(&[b"first", b"second", b"third"]);
+        let mut reader = reader(bytes);
+        // header, then "first" framed in 8 + 5 bytes
+        reader.seek(21).unwrap();
+        assert_eq!(reader.next_record().unwrap().unwrap(), b"second");
+        assert_eq!(reader.offset(), 35);
+    }
+
+    #[test]
+    fn record_header_test() {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, FileKind::Table, 0).unwrap();
+        assert!(matches!(
+            reader(bytes).next_record(),
+            Err(StorageEngineError::InvalidFileHeader(_))
+        ));
+
+        let mut bytes = framed(&[b"first"]);
+        bytes[4] = 9;
+        assert!(matches!(
+            reader(bytes).next_record(),
+            Err(StorageEngineError::UnsupportedFormatVersion(_, 9))
+        ));
+    }
+}
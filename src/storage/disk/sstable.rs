@@ -1,49 +1,387 @@
 use std::{
     fs::{self, File, OpenOptions},
-    io::{self, BufRead, BufReader, BufWriter, Write},
-    path::PathBuf,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    iter::Peekable,
+    ops::Range,
+    path::{Path, PathBuf},
     str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    vec,
 };
 
-use tracing::{error, info};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::{storage::MemTable, Action, Event, StorageEngineError};
+use crate::{
+    storage::{
+        sync_dir, write_header, write_record, BloomFilter, FileKind, MemTable, RecordReader,
+        HEADER_SIZE,
+    },
+    Action, Codec, CompressionOptions, Event, FilterOptions, StorageEngineError, StorageMode,
+};
+
+/// Target size of a data block.
+/// A block is closed once it holds at least this many bytes of events.
+const BLOCK_SIZE: usize = 4 * 1024;
+/// | filter offset (u64 LE) | index offset (u64 LE) | CRC32 of both offsets (u32 LE) |
+const FOOTER_SIZE: usize = 20;
+/// Entries in a chunk of an order index, 4 KiB worth, the size of a data block.
+const ORDER_CHUNK_LEN: usize = 128;
+
+/// An order a table indexes its events in, besides by ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Order {
+    /// By commit position, for the changes evicted from the all-stream index.
+    Position,
+    /// By sequence number, for the events of every key evicted from the all-stream index.
+    Seq,
+}
+
+impl Order {
+    const ALL: [Order; 2] = [Order::Position, Order::Seq];
+
+    /// Get the sort key of the entry in the order.
+    pub(crate) fn key(self, entry: &OrderEntry) -> (u64, u64) {
+        match self {
+            Order::Position => (entry.position, entry.seq),
+            Order::Seq => (entry.seq, entry.position),
+        }
+    }
+}
+
+/// An entry of an order index, pointing at an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct OrderEntry {
+    pub(crate) seq: u64,
+    pub(crate) position: u64,
+    pub(crate) id: Uuid,
+}
+
+impl From<&Event> for OrderEntry {
+    fn from(event: &Event) -> Self {
+        Self {
+            seq: event.sequence_num(),
+            position: event.position(),
+            id: event.id(),
+        }
+    }
+}
+
+/// The sparse index of the chunks of an order index:
+/// the key of the first entry of every chunk and where the chunk starts, along with where the chunks end.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct OrderIndex {
+    chunks: Vec<((u64, u64), u64)>,
+    end: u64,
+}
+
+/// The first ID and sequence number of a data block and where the block starts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockHandle {
+    first_id: Uuid,
+    first_seq: u64,
+    offset: u64,
+}
+
+/// The sparse index of an SSTable, one entry per data block, and the last ID in the table,
+/// along with the bloom filter over the IDs in the table and the sparse index of every order index.
+/// `data_end` is where the data blocks end and the filter block begins.
+struct TableIndex {
+    blocks: Vec<BlockHandle>,
+    last_id: Option<Uuid>,
+    /// By `Order`.
+    orders: Vec<OrderIndex>,
+    data_end: u64,
+    filter: BloomFilter,
+}
+
+impl Default for TableIndex {
+    fn default() -> Self {
+        Self {
+            blocks: Vec::new(),
+            last_id: None,
+            orders: Vec::new(),
+            data_end: 0,
+            filter: BloomFilter::new(FilterOptions {
+                capacity: 0,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// Writes a table file: the data blocks, followed by the filter block, the index block and the footer.
+/// The events must be added sorted by ID, then by sequence number.
+/// Only the data blocks are compressed.
+struct TableWriter<W: Write> {
+    writer: W,
+    codec: Arc<dyn Codec>,
+    /// The false positive probability of the filter.
+    fp_rate: f64,
+    blocks: Vec<BlockHandle>,
+    data_end: u64,
+    ids: Vec<Uuid>,
+    /// Every event written, sorted into the order indexes once the data blocks are done.
+    entries: Vec<OrderEntry>,
+    block: Vec<Event>,
+    block_size: usize,
+}
+
+impl<W: Write> TableWriter<W> {
+    fn new(mut writer: W, codec: Arc<dyn Codec>, fp_rate: f64) -> Result<Self, StorageEngineError> {
+        write_header(&mut writer, FileKind::Table, codec.id())?;
+        Ok(Self {
+            writer,
+            codec,
+            fp_rate,
+            blocks: Vec::new(),
+            data_end: HEADER_SIZE as u64,
+            ids: Vec::new(),
+            entries: Vec::new(),
+            block: Vec::new(),
+            block_size: 0,
+        })
+    }
+
+    fn add(&mut self, event: Event) -> Result<(), StorageEngineError> {
+        if self.ids.last() != Some(&event.id()) {
+            self.ids.push(event.id());
+        }
+        self.entries.push(OrderEntry::from(&event));
+        if self.block.is_empty() {
+            self.blocks.push(BlockHandle {
+                first_id: event.id(),
+                first_seq: event.sequence_num(),
+                offset: self.data_end,
+            });
+        }
+        self.block_size += bincode::serialized_size(&event)? as usize;
+        self.block.push(event);
+        if self.block_size >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes written so far, plus the uncompressed size of the open block.
+    fn size(&self) -> u64 {
+        self.data_end + self.block_size as u64
+    }
+
+    fn write_block(&mut self) -> Result<(), StorageEngineError> {
+        let block = self.codec.compress(&bincode::serialize(&self.block)?);
+        self.data_end += write_record(&mut self.writer, &block)? as u64;
+        self.block.clear();
+        self.block_size = 0;
+        Ok(())
+    }
+
+    /// Write the last data block, the filter, the order indexes, the index and the footer.
+    /// The filter is sized from the number of IDs written, at the false positive probability of the writer.
+    /// Returns the index along with the writer, to be synced.
+    fn finish(mut self) -> Result<(TableIndex, W), StorageEngineError> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+
+        let mut filter = BloomFilter::new(FilterOptions {
+            capacity: self.ids.len(),
+            fp_rate: self.fp_rate,
+        });
+        for id in &self.ids {
+            filter.add(*id);
+        }
+        let mut offset =
+            self.data_end + write_record(&mut self.writer, &filter.to_bytes()?)? as u64;
+        let mut orders = Vec::with_capacity(Order::ALL.len());
+        for order in Order::ALL {
+            self.entries.sort_unstable_by_key(|entry| order.key(entry));
+            let mut index = OrderIndex::default();
+            for chunk in self.entries.chunks(ORDER_CHUNK_LEN) {
+                index.chunks.push((order.key(&chunk[0]), offset));
+                offset += write_record(&mut self.writer, &bincode::serialize(chunk)?)? as u64;
+            }
+            index.end = offset;
+            orders.push(index);
+        }
+        let index_offset = offset;
+        let last_id = self.ids.last().copied();
+        write_record(
+            &mut self.writer,
+            &bincode::serialize(&(&self.blocks, last_id, &orders))?,
+        )?;
 
+        let mut footer = [0u8; FOOTER_SIZE];
+        footer[..8].copy_from_slice(&self.data_end.to_le_bytes());
+        footer[8..16].copy_from_slice(&index_offset.to_le_bytes());
+        let crc = crc32fast::hash(&footer[..16]);
+        footer[16..].copy_from_slice(&crc.to_le_bytes());
+        self.writer.write_all(&footer)?;
+        let index = TableIndex {
+            blocks: self.blocks,
+            last_id,
+            orders,
+            data_end: self.data_end,
+            filter,
+        };
+        Ok((index, self.writer))
+    }
+}
+
+impl TableIndex {
+    /// Read the footer, then the filter and index blocks it points to.
+    /// Returns the index along with the codec of the data blocks.
+    fn read(
+        filepath: &Path,
+        compression: &CompressionOptions,
+    ) -> Result<(Self, Arc<dyn Codec>), StorageEngineError> {
+        let mut file = File::open(filepath)?;
+        if file.metadata()?.len() < (HEADER_SIZE + FOOTER_SIZE) as u64 {
+            return Err(StorageEngineError::InvalidTableFooter(filepath.to_owned()));
+        }
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        file.read_exact(&mut footer)?;
+        let crc = u32::from_le_bytes(footer[16..].try_into().unwrap());
+        if crc32fast::hash(&footer[..16]) != crc {
+            return Err(StorageEngineError::InvalidTableFooter(filepath.to_owned()));
+        }
+        let data_end = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let index_offset = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+
+        file.rewind()?;
+        let mut reader = RecordReader::new(file, filepath.to_owned(), FileKind::Table);
+        let filter = BloomFilter::from_bytes(&Self::read_record_at(&mut reader, data_end)?)?;
+        let (blocks, last_id, orders): (Vec<BlockHandle>, Option<Uuid>, Vec<OrderIndex>) =
+            bincode::deserialize(&Self::read_record_at(&mut reader, index_offset)?)?;
+        let codec = reader.codec(compression)?;
+        let index = Self {
+            blocks,
+            last_id,
+            orders,
+            data_end,
+            filter,
+        };
+        Ok((index, codec))
+    }
+
+    fn read_record_at(
+        reader: &mut RecordReader<File>,
+        offset: u64,
+    ) -> Result<Vec<u8>, StorageEngineError> {
+        reader.seek(offset)?;
+        reader
+            .next_record()?
+            .ok_or_else(|| StorageEngineError::TruncatedRecord(reader.path().to_owned(), offset))
+    }
+}
+
+/// Decompress and deserialize a data block.
+fn decode_block(codec: &dyn Codec, data: &[u8]) -> Result<Vec<Event>, StorageEngineError> {
+    Ok(bincode::deserialize(&codec.decompress(data)?)?)
+}
+
+/// Iterates over the events of an SSTable file, one data block at a time.
 pub(crate) struct SSTableIterator {
-    reader: BufReader<File>,
+    reader: RecordReader<BufReader<File>>,
+    codec: Arc<dyn Codec>,
+    data_end: u64,
+    block: vec::IntoIter<Event>,
 }
 
 impl SSTableIterator {
-    fn new(filepath: PathBuf) -> Result<Self, StorageEngineError> {
-        let file = OpenOptions::new().read(true).open(filepath)?;
-        let reader = BufReader::new(file);
-        Ok(Self { reader })
+    /// Iterate from the data block at the offset to the end of the data blocks.
+    fn new(
+        filepath: PathBuf,
+        codec: Arc<dyn Codec>,
+        offset: u64,
+        data_end: u64,
+    ) -> Result<Self, StorageEngineError> {
+        let file = OpenOptions::new().read(true).open(&filepath)?;
+        let mut reader = RecordReader::new(BufReader::new(file), filepath, FileKind::Table);
+        // Past the data blocks there is nothing left to read.
+        let data_end = match offset < data_end {
+            true => {
+                reader.seek(offset)?;
+                data_end
+            }
+            false => 0,
+        };
+        Ok(Self {
+            reader,
+            codec,
+            data_end,
+            block: Vec::new().into_iter(),
+        })
     }
 }
 
 impl Iterator for SSTableIterator {
-    type Item = Event;
+    type Item = Result<Event, StorageEngineError>;
 
+    /// A corrupted block is returned as an error and ends the iteration.
     fn next(&mut self) -> Option<Self::Item> {
-        let mut data_bytes = Vec::new();
-        match self.reader.read_until(b'|', &mut data_bytes) {
-            Ok(sz) => {
-                if sz == 0 {
-                    return None;
-                }
-                match bincode::deserialize::<Event>(&data_bytes) {
-                    Ok(event) => Some(event),
-                    Err(e) => {
-                        error!("Error getting next event: {}", e);
-                        None
-                    }
-                }
+        if let Some(event) = self.block.next() {
+            return Some(Ok(event));
+        }
+        if self.reader.offset() >= self.data_end {
+            return None;
+        }
+        let block = match self.reader.next_record() {
+            Ok(Some(data)) => decode_block(self.codec.as_ref(), &data),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        match block {
+            Ok(block) => {
+                self.block = block.into_iter();
+                self.next()
+            }
+            Err(e) => {
+                // Don't read past a broken block.
+                self.data_end = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Iterates over the entries of an order index, one chunk at a time.
+pub(crate) struct OrderIterator {
+    reader: RecordReader<BufReader<File>>,
+    end: u64,
+    chunk: vec::IntoIter<OrderEntry>,
+}
+
+impl Iterator for OrderIterator {
+    type Item = Result<OrderEntry, StorageEngineError>;
+
+    /// A corrupted chunk is returned as an error and ends the iteration.
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.chunk.next() {
+            return Some(Ok(entry));
+        }
+        if self.reader.offset() >= self.end {
+            return None;
+        }
+        let chunk = match self.reader.next_record() {
+            Ok(Some(data)) => bincode::deserialize::<Vec<OrderEntry>>(&data).map_err(Into::into),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        match chunk {
+            Ok(chunk) => {
+                self.chunk = chunk.into_iter();
+                self.next()
             }
             Err(e) => {
-                error!("Error getting next event: {}", e);
-                None
+                // Don't read past a broken chunk.
+                self.end = 0;
+                Some(Err(e))
             }
         }
     }
@@ -51,249 +389,629 @@ impl Iterator for SSTableIterator {
 
 /// For the sstable structure, I looked at this [code](https://github.com/DevinZ1993/NaiveKV/blob/main/src/sstable.rs)
 /// and the associated [blog](https://devinz1993.medium.com/naivekv-a-log-structured-storage-engine-bc44bde596b)
+///
+/// The file is laid out as:
+///
+/// | header | data blocks | filter block | order indexes | index block | footer |
+///
+/// Each data block is a record holding events sorted by ID, then by sequence number,
+/// compressed with the codec recorded in the header.
+/// The filter block is a record holding the bloom filter over the IDs in the table.
+/// Every order index points at the events of the table sorted in an `Order`, in chunks of records.
+/// The index block is a record mapping the first ID of every data block to its offset,
+/// followed by the last ID in the table and the first key of every chunk of the order indexes.
+/// The footer points to the filter and index blocks, and checksums both offsets.
+/// A point read is a filter check, then a binary search plus one block read.
+///
+/// Flushed tables start in level 0, and compactions move their data down the levels.
+/// The manifest records the level of every table.
+///
+/// Once a merge replaces a table, the file is kept until the last reader holding the table lets go.
 pub(crate) struct SSTable {
-    memtable: Option<MemTable>,
+    memtable: Option<Arc<MemTable>>,
     filepath: PathBuf,
-    writer: Option<BufWriter<File>>,
+    writer: Option<TableWriter<BufWriter<File>>>,
+    index: TableIndex,
+    codec: Arc<dyn Codec>,
+    level: usize,
+    /// The file ID the table is named after.
+    id: u64,
+    /// Remove the file on drop.
+    obsolete: AtomicBool,
 }
 
 impl SSTable {
     const TABLENAME: &str = "rdeebee";
+    pub(crate) const TEMP_EXTENSION: &str = "tmp";
 
-    /// Create a table file in the directory provided
-    /// Consumes the MemTable
+    /// Create a table file with the file ID in the directory provided, compressed with the codec,
+    /// and with a filter at the false positive probability.
+    /// The MemTable may be shared, so it stays readable while the table is written.
     pub(crate) fn from_memtable(
         dirname: &str,
-        memtable: MemTable,
+        id: u64,
+        memtable: impl Into<Arc<MemTable>>,
+        codec: Arc<dyn Codec>,
+        fp_rate: f64,
     ) -> Result<Self, StorageEngineError> {
-        let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros();
-        let dir = PathBuf::from_str(dirname)?;
-        let filepath = dir.join(format!("{}-{}.table", Self::TABLENAME, epoch));
+        let filepath = Self::file_path(&PathBuf::from_str(dirname)?, id);
         let file = OpenOptions::new()
             .append(true)
             .create(true)
             .open(&filepath)?;
-        let writer = BufWriter::new(file);
+        let writer = TableWriter::new(BufWriter::new(file), codec.clone(), fp_rate)?;
         Ok(Self {
-            memtable: Some(memtable),
+            memtable: Some(memtable.into()),
             filepath,
             writer: Some(writer),
+            index: TableIndex::default(),
+            codec,
+            level: 0,
+            id,
+            obsolete: AtomicBool::new(false),
         })
     }
 
+    /// Get the path of the table file with the ID.
+    pub(crate) fn file_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{}-{}.table", Self::TABLENAME, id))
+    }
+
+    /// Get the file ID of the table.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the level of the table.
+    pub(crate) fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Get the size of the data blocks in bytes.
+    pub(crate) fn size(&self) -> u64 {
+        self.index.data_end
+    }
+
+    /// Get the lowest and highest ID in the table, or None if it is empty.
+    pub(crate) fn key_range(&self) -> Option<(Uuid, Uuid)> {
+        let first = self.index.blocks.first()?.first_id;
+        Some((first, self.index.last_id?))
+    }
+
+    /// Can the table hold IDs within the range?
+    pub(crate) fn overlaps(&self, (first, last): (Uuid, Uuid)) -> bool {
+        match self.key_range() {
+            Some((start, end)) => start <= last && first <= end,
+            None => false,
+        }
+    }
+
     /// Does this event exist in the SSTable
-    pub(crate) fn contains(&self, id: Uuid) -> bool {
-        for ref event in self.iter() {
+    pub(crate) fn contains(&self, id: Uuid) -> Result<bool, StorageEngineError> {
+        Ok(self.get(id)?.is_some())
+    }
+
+    /// Find the latest event by ID
+    /// Only the last block starting at or before the ID can hold it.
+    pub(crate) fn get(&self, id: Uuid) -> Result<Option<Event>, StorageEngineError> {
+        if !self.index.filter.find(id) {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .blocks
+            .partition_point(|block| block.first_id <= id);
+        if block == 0 {
+            return Ok(None);
+        }
+        let events = self.read_block(&self.index.blocks[block - 1])?;
+        Ok(events.into_iter().rev().find(|event| event.id() == id))
+    }
+
+    /// Find the latest event of the ID with a sequence number up to `seq`.
+    /// Reading starts at the last block starting at or before the sequence number of the ID,
+    /// and stops at the first event past it.
+    pub(crate) fn get_up_to(
+        &self,
+        id: Uuid,
+        seq: u64,
+    ) -> Result<Option<Event>, StorageEngineError> {
+        if !self.index.filter.find(id) {
+            return Ok(None);
+        }
+        let block = self
+            .index
+            .blocks
+            .partition_point(|block| (block.first_id, block.first_seq) <= (id, seq));
+        let offset = match block.checked_sub(1) {
+            Some(block) => self.index.blocks[block].offset,
+            None => return Ok(None),
+        };
+        let mut latest = None;
+        for event in self.iter_from(offset)? {
+            let event = event?;
+            if (event.id(), event.sequence_num()) > (id, seq) {
+                break;
+            }
             if event.id() == id {
-                return true;
+                latest = Some(event);
             }
         }
-        false
+        Ok(latest)
     }
 
-    /// Find event by ID
-    pub(crate) fn get(&self, id: Uuid) -> Option<Event> {
-        for ref event in self.iter() {
-            if event.id() == id {
-                return Some(event.clone());
+    /// Find the event of the ID with the sequence number and commit position.
+    /// Reading starts at the last block starting before the sequence number of the ID,
+    /// and stops at the first event past it.
+    pub(crate) fn get_at(
+        &self,
+        id: Uuid,
+        seq: u64,
+        position: u64,
+    ) -> Result<Option<Event>, StorageEngineError> {
+        if !self.index.filter.find(id) {
+            return Ok(None);
+        }
+        // Events sharing the sequence number may start at the end of the block before.
+        let block = self
+            .index
+            .blocks
+            .partition_point(|block| (block.first_id, block.first_seq) < (id, seq))
+            .saturating_sub(1);
+        let offset = match self.index.blocks.get(block) {
+            Some(block) => block.offset,
+            None => return Ok(None),
+        };
+        for event in self.iter_from(offset)? {
+            let event = event?;
+            let at = (event.id(), event.sequence_num());
+            if at > (id, seq) {
+                break;
+            }
+            if at == (id, seq) && event.position() == position {
+                return Ok(Some(event));
             }
         }
-        None
+        Ok(None)
+    }
+
+    /// Lazily iterate over the events of the ID from the sequence number `from_seq` on,
+    /// in ascending order of sequence numbers.
+    /// Reading starts at the last block starting before the sequence number of the ID.
+    /// The file is opened here, so the iterator keeps working if the table is merged away.
+    /// Errors are passed through and end the iteration.
+    pub(crate) fn stream(
+        &self,
+        id: Uuid,
+        from_seq: u64,
+    ) -> Result<impl Iterator<Item = Result<Event, StorageEngineError>> + Send, StorageEngineError>
+    {
+        // The events from the sequence number may start at the end of the block before the first one past it.
+        let block = self
+            .index
+            .blocks
+            .partition_point(|block| (block.first_id, block.first_seq) < (id, from_seq))
+            .saturating_sub(1);
+        let offset = match self.index.filter.find(id) {
+            true => self
+                .index
+                .blocks
+                .get(block)
+                .map_or(self.index.data_end, |block| block.offset),
+            // The table can't hold the ID, so start at the end of the data blocks.
+            false => self.index.data_end,
+        };
+        Ok(self
+            .iter_from(offset)?
+            .skip_while(move |event| {
+                matches!(event, Ok(event) if (event.id(), event.sequence_num()) < (id, from_seq))
+            })
+            .take_while(move |event| !matches!(event, Ok(event) if event.id() != id)))
+    }
+
+    /// Lazily iterate over the entries of the order index, from the key on.
+    /// Reading starts at the last chunk starting before the key.
+    pub(crate) fn ordered(
+        &self,
+        order: Order,
+        from: (u64, u64),
+    ) -> Result<
+        impl Iterator<Item = Result<OrderEntry, StorageEngineError>> + Send,
+        StorageEngineError,
+    > {
+        let index = self
+            .index
+            .orders
+            .get(order as usize)
+            .ok_or_else(|| StorageEngineError::InvalidTableFooter(self.filepath.clone()))?;
+        let chunk = index
+            .chunks
+            .partition_point(|(first, _)| *first < from)
+            .saturating_sub(1);
+        let file = File::open(&self.filepath)?;
+        let mut reader =
+            RecordReader::new(BufReader::new(file), self.filepath.clone(), FileKind::Table);
+        // An empty index has nothing to read.
+        let end = match index.chunks.get(chunk) {
+            Some((_, offset)) => {
+                reader.seek(*offset)?;
+                index.end
+            }
+            None => 0,
+        };
+        let iter = OrderIterator {
+            reader,
+            end,
+            chunk: Vec::new().into_iter(),
+        };
+        Ok(iter.skip_while(move |entry| matches!(entry, Ok(entry) if order.key(entry) < from)))
     }
 
     /// Saves the SSTable to disk
+    /// The file is synced, so the Wals protecting the MemTable can be retired afterwards.
+    /// The table lets go of the writer, and of the MemTable once it is written.
     pub(crate) fn save_to_disk(&mut self) -> Result<(), StorageEngineError> {
         let memtable = match self.memtable.as_ref() {
             Some(memtable) => memtable,
             None => return Err(StorageEngineError::InvalidMemTable),
         };
-        match &mut self.writer {
-            Some(writer) => {
-                for event in memtable {
-                    let event_ser = bincode::serialize(&event)?;
-                    writer.write_all(&event_ser)?;
-                    writer.write_all("|".as_bytes())?; // delimeter
-                }
-                writer.flush()?;
-                Ok(())
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => {
+                return Err(StorageEngineError::InvalidSSTableWriter(
+                    self.filepath.clone(),
+                ))
             }
-            None => Err(StorageEngineError::InvalidSSTableWriter(
-                self.filepath.clone(),
-            )),
+        };
+        for event in memtable.as_ref() {
+            writer.add(event)?;
         }
+        let (index, mut writer) = writer.finish()?;
+        self.index = index;
+        Self::sync(&mut writer, &self.filepath)?;
+        self.memtable = None;
+        Ok(())
     }
 
-    /// Consumes the SSTable to write to file
-    /// Used when merging
-    pub(crate) fn write_to_file(mut self, events: Vec<Event>) -> Result<(), StorageEngineError> {
-        match &mut self.writer {
-            Some(writer) => {
-                for event in events {
-                    let event_ser = bincode::serialize(&event)?;
-                    writer.write_all(&event_ser)?;
-                    writer.write_all("|".as_bytes())?; // delimeter
-                }
-                writer.flush()?;
-            }
-            None => {}
+    /// Flush and fsync the table file along with its directory.
+    fn sync(writer: &mut BufWriter<File>, filepath: &Path) -> Result<(), StorageEngineError> {
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        if let Some(dir) = filepath.parent() {
+            sync_dir(dir)?;
         }
         Ok(())
     }
 
-    fn get_epoch_from_filename(filename: &str) -> Result<u128, StorageEngineError> {
-        Ok(filename
-            .split(|c| (c == '-') || (c == '.'))
-            .collect::<Vec<&str>>()[1]
-            .parse::<u128>()?)
-    }
-
-    /// Given an existing file, return an SSTable
-    pub(crate) fn from_file(filepath: PathBuf) -> io::Result<Self> {
+    /// Given an existing file, return an SSTable at the level
+    /// The index is read into memory.
+    /// The codec recorded in the header must be one of the compression options.
+    pub(crate) fn from_file(
+        dirname: &str,
+        id: u64,
+        level: usize,
+        compression: &CompressionOptions,
+    ) -> Result<Self, StorageEngineError> {
+        let filepath = Self::file_path(&PathBuf::from_str(dirname)?, id);
         info!("Opening new segment: {}", &filepath.display());
+        let (index, codec) = TableIndex::read(&filepath, compression)?;
         Ok(Self {
             memtable: None,
+            level,
+            id,
             filepath,
             writer: None,
+            index,
+            codec,
+            obsolete: AtomicBool::new(false),
         })
     }
 
-    fn iter(&self) -> SSTableIterator {
-        SSTableIterator::new(self.filepath.clone()).unwrap()
+    /// Iterate over every event in the table.
+    pub(crate) fn iter(&self) -> Result<SSTableIterator, StorageEngineError> {
+        self.iter_from(HEADER_SIZE as u64)
     }
 
-    /// Consumes the SSTables to create a new file
-    /// Returns the new SSTable for the merged data
-    pub(crate) fn merge(mut self, other: SSTable) -> Result<SSTable, StorageEngineError> {
-        let mut events = Vec::new();
-        let mut deleted_events = Vec::new();
+    fn iter_from(&self, offset: u64) -> Result<SSTableIterator, StorageEngineError> {
+        SSTableIterator::new(
+            self.filepath.clone(),
+            self.codec.clone(),
+            offset,
+            self.index.data_end,
+        )
+    }
 
-        let self_file = match self.filepath.file_name().and_then(|f| f.to_str()) {
-            Some(path) => path,
-            None => {
-                return Err(StorageEngineError::InvalidSSTableFilePath(
-                    self.filepath.clone(),
-                ))
-            }
-        };
+    fn read_block(&self, block: &BlockHandle) -> Result<Vec<Event>, StorageEngineError> {
+        let file = File::open(&self.filepath)?;
+        let mut reader = RecordReader::new(file, self.filepath.clone(), FileKind::Table);
+        reader.seek(block.offset)?;
+        match reader.next_record()? {
+            Some(data) => decode_block(self.codec.as_ref(), &data),
+            None => Err(StorageEngineError::TruncatedRecord(
+                self.filepath.clone(),
+                block.offset,
+            )),
+        }
+    }
 
-        let other_file = match other.filepath.file_name().and_then(|f| f.to_str()) {
-            Some(path) => path,
-            None => {
-                return Err(StorageEngineError::InvalidSSTableFilePath(
-                    self.filepath.clone(),
-                ))
-            }
-        };
+    /// Get the ID of the next event without consuming it.
+    fn peek_id(iter: &mut Peekable<SSTableIterator>) -> Result<Option<Uuid>, StorageEngineError> {
+        if let Some(Err(e)) = iter.next_if(|event| event.is_err()) {
+            return Err(e);
+        }
+        Ok(iter
+            .peek()
+            .and_then(|event| event.as_ref().ok())
+            .map(|event| event.id()))
+    }
+
+    /// Take the consecutive events with the given ID from the front of the iterator.
+    /// An error after the run is left in place for the next `peek_id`.
+    fn take_run(iter: &mut Peekable<SSTableIterator>, id: Uuid) -> Vec<Event> {
+        let mut run = Vec::new();
+        while let Some(Ok(event)) =
+            iter.next_if(|event| matches!(event, Ok(event) if event.id() == id))
+        {
+            run.push(event);
+        }
+        run
+    }
 
-        let epoch1 = Self::get_epoch_from_filename(self_file)?;
-        let epoch2 = Self::get_epoch_from_filename(other_file)?;
+    /// Merge the tables, ordered from oldest to newest, into the new tables of the output.
+    /// In `StorageMode::Latest` only the newest event of each ID survives.
+    /// If that is a delete, it is dropped as well when `drop_deletes` is set,
+    /// which is only safe if no table left out of the merge holds the ID.
+    /// In `StorageMode::History` all events of each ID are kept in ascending order of sequence numbers.
+    /// The output is split into tables of about its table size without splitting an ID,
+    /// so the new tables don't overlap.
+    /// The input files are left in place, call `remove` once the new tables are installed.
+    ///
+    /// Each new table is written to a temp file, synced, then renamed into place,
+    /// so a table file is never seen half written.
+    /// If the merge fails, the tables written so far are removed, along with the one being written.
+    /// A temp file left behind by a crash is removed by recovery.
+    pub(crate) fn merge(
+        tables: &[&SSTable],
+        mut output: MergeOutput,
+        mode: StorageMode,
+        drop_deletes: bool,
+    ) -> Result<Vec<SSTable>, StorageEngineError> {
+        let result = Self::merge_into(&mut output, tables, mode, drop_deletes)
+            .and_then(|_| output.finish_table());
+        if let Err(e) = result {
+            if let Some((_, temp_path, _)) = output.current.take() {
+                MergeOutput::discard(&temp_path);
+            }
+            for table in output.tables {
+                table.remove()?;
+            }
+            return Err(e);
+        }
+        Ok(output.tables)
+    }
 
-        let mut iter1 = self.iter();
-        let mut iter2 = other.iter();
+    fn merge_into(
+        output: &mut MergeOutput,
+        tables: &[&SSTable],
+        mode: StorageMode,
+        drop_deletes: bool,
+    ) -> Result<(), StorageEngineError> {
+        let mut iters = Vec::with_capacity(tables.len());
+        for table in tables {
+            iters.push(table.iter()?.peekable());
+        }
 
         loop {
-            let event: Option<Event>;
-            match (iter1.next(), iter2.next()) {
-                (Some(event1), Some(event2)) => match event1.id().cmp(&event2.id()) {
-                    std::cmp::Ordering::Less => {
-                        if event1.action() == &Action::Delete {
-                            deleted_events.push(event1.id());
-                        }
-                        event = Some(event1);
-                    }
-                    std::cmp::Ordering::Equal => match epoch1 > epoch2 {
-                        true => {
-                            if event1.action() == &Action::Delete {
-                                deleted_events.push(event1.id());
-                            }
-                            event = Some(event1);
-                        }
-                        false => {
-                            if event2.action() == &Action::Delete {
-                                deleted_events.push(event2.id());
-                            }
-                            event = Some(event2);
-                        }
-                    },
-                    std::cmp::Ordering::Greater => {
-                        if event2.action() == &Action::Delete {
-                            deleted_events.push(event2.id());
-                        }
-                        event = Some(event2);
-                    }
-                },
-                (None, Some(event2)) => {
-                    if event2.action() == &Action::Delete {
-                        deleted_events.push(event2.id());
-                    }
-                    event = Some(event2);
-                }
-                (Some(event1), None) => {
-                    if event1.action() == &Action::Delete {
-                        deleted_events.push(event1.id());
-                    }
-                    event = Some(event1);
+            let mut next_id: Option<Uuid> = None;
+            for iter in iters.iter_mut() {
+                if let Some(id) = Self::peek_id(iter)? {
+                    next_id = Some(next_id.map_or(id, |next_id| next_id.min(id)));
                 }
-                (None, None) => break,
             }
-            if let Some(event) = event {
-                if !deleted_events.contains(&event.id()) {
-                    events.push(event);
+            let id = match next_id {
+                Some(id) => id,
+                None => break,
+            };
+            let runs: Vec<Vec<Event>> = iters
+                .iter_mut()
+                .map(|iter| Self::take_run(iter, id))
+                .collect();
+            let events = match mode {
+                StorageMode::Latest => match runs.into_iter().rev().find_map(|mut run| run.pop()) {
+                    Some(event) if drop_deletes && event.action() == &Action::Delete => Vec::new(),
+                    Some(event) => vec![event],
+                    None => Vec::new(),
+                },
+                StorageMode::History => {
+                    // Stable sort, so the older table wins ties on sequence numbers.
+                    let mut events: Vec<Event> = runs.into_iter().flatten().collect();
+                    events.sort_by_key(|event| event.sequence_num());
+                    events
                 }
+            };
+            if !events.is_empty() {
+                output.add(events)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the table file.
+    pub(crate) fn remove(&self) -> Result<(), StorageEngineError> {
+        Ok(fs::remove_file(&self.filepath)?)
+    }
+
+    /// Delete the table file once the table is dropped.
+    pub(crate) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        if *self.obsolete.get_mut() {
+            if let Err(e) = fs::remove_file(&self.filepath) {
+                // Recovery removes the file instead, as the manifest no longer lists it.
+                warn!("failed to remove {}: {}", self.filepath.display(), e);
             }
         }
+    }
+}
 
-        fs::remove_file(self.filepath.clone()).expect("failed to remove old file");
-        fs::remove_file(other.filepath).expect("failed to remove old file");
+/// The tables written by a merge into `level` of `dir`, split at about `table_size` bytes.
+/// Each table takes the next of the reserved file IDs, and is written to a temp file,
+/// synced, then renamed into place.
+/// Once a single ID is left, the last table takes the rest of the events, however large it grows.
+/// The filters of the tables have a false positive probability of `fp_rate`.
+pub(crate) struct MergeOutput<'a> {
+    dir: &'a Path,
+    level: usize,
+    table_size: u64,
+    file_ids: Range<u64>,
+    codec: Arc<dyn Codec>,
+    fp_rate: f64,
+    current: Option<(u64, PathBuf, TableWriter<BufWriter<File>>)>,
+    tables: Vec<SSTable>,
+}
 
-        let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros();
+impl<'a> MergeOutput<'a> {
+    pub(crate) fn new(
+        dir: &'a Path,
+        level: usize,
+        table_size: u64,
+        file_ids: Range<u64>,
+        codec: Arc<dyn Codec>,
+        fp_rate: f64,
+    ) -> Self {
+        Self {
+            dir,
+            level,
+            table_size,
+            file_ids,
+            codec,
+            fp_rate,
+            current: None,
+            tables: Vec::new(),
+        }
+    }
 
-        let dir = match self.filepath.parent() {
-            Some(dir) => dir,
-            None => return Err(StorageEngineError::InvalidDbDir(self.filepath)),
+    /// Add the events of an ID, then finish the table if it is large enough.
+    fn add(&mut self, events: Vec<Event>) -> Result<(), StorageEngineError> {
+        let (_, _, writer) = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let id = self
+                    .file_ids
+                    .next()
+                    .ok_or(StorageEngineError::OutOfFileIds)?;
+                let temp_path = self.dir.join(format!(
+                    "{}-{}.table.{}",
+                    SSTable::TABLENAME,
+                    id,
+                    SSTable::TEMP_EXTENSION
+                ));
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&temp_path)?;
+                let writer =
+                    TableWriter::new(BufWriter::new(file), self.codec.clone(), self.fp_rate)?;
+                self.current.insert((id, temp_path, writer))
+            }
         };
+        for event in events {
+            writer.add(event)?;
+        }
+        if writer.size() >= self.table_size && !self.file_ids.is_empty() {
+            self.finish_table()?;
+        }
+        Ok(())
+    }
 
-        let dir = dir.to_owned();
-        let filepath = dir.join(format!("{}-{}.table", Self::TABLENAME, epoch));
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&filepath)?;
-        let writer = BufWriter::new(file);
-        self.writer = Some(writer);
-        self.write_to_file(events)?;
-        Ok(Self {
+    /// Sync the table being written, then rename it into place.
+    /// The temp file is removed if either fails.
+    fn finish_table(&mut self) -> Result<(), StorageEngineError> {
+        let (id, temp_path, writer) = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let filepath = temp_path.with_extension("");
+        let index = match Self::install(writer, &temp_path, &filepath) {
+            Ok(index) => index,
+            Err(e) => {
+                Self::discard(&temp_path);
+                return Err(e);
+            }
+        };
+        self.tables.push(SSTable {
             memtable: None,
             filepath,
             writer: None,
-        })
+            index,
+            codec: self.codec.clone(),
+            level: self.level,
+            id,
+            obsolete: AtomicBool::new(false),
+        });
+        // The table is listed first, so a failed merge removes it along with the others.
+        Ok(sync_dir(self.dir)?)
+    }
+
+    fn install(
+        writer: TableWriter<BufWriter<File>>,
+        temp_path: &Path,
+        filepath: &Path,
+    ) -> Result<TableIndex, StorageEngineError> {
+        let (index, mut writer) = writer.finish()?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(temp_path, filepath)?;
+        Ok(index)
+    }
+
+    /// Remove the temp file of a table that was never finished.
+    fn discard(temp_path: &Path) {
+        if let Err(e) = fs::remove_file(temp_path) {
+            // Recovery removes the file instead.
+            warn!("failed to remove {}: {}", temp_path.display(), e);
+        }
     }
 }
 
 impl IntoIterator for SSTable {
-    type Item = Event;
+    type Item = Result<Event, StorageEngineError>;
     type IntoIter = SSTableIterator;
 
     fn into_iter(self) -> Self::IntoIter {
-        SSTableIterator::new(self.filepath).unwrap()
+        self.iter().unwrap()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{thread, time::Duration};
+    use std::{fs, path::Path, sync::Arc};
 
-    use crate::storage::{
-        disk::SSTable,
-        event::{Action, Event},
-        mem::MemTable,
+    use crate::{
+        storage::{disk::SSTable, mem::MemTable, MergeOutput, Order, HEADER_SIZE},
+        Action, CompressionOptions, Event, Lz4, NoCompression, StorageEngineError, StorageMode,
     };
     use uuid::Uuid;
 
+    const FP_RATE: f64 = 0.01;
+
+    /// An empty directory of its own for the test, unique to this process so concurrent test runs don't share it.
+    fn test_dir(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("rdeebee-sstable-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
     fn create_events(n: usize) -> Vec<Event> {
         let mut events = Vec::new();
         for _ in 0..n {
-            events.push(Event::new(Action::Read));
+            events.push(Event::new(Action::Read, 0));
         }
         events
     }
@@ -304,96 +1022,374 @@ mod test {
         }
     }
 
+    fn get_stream(table: &SSTable, id: Uuid) -> Vec<Event> {
+        table
+            .stream(id, 0)
+            .unwrap()
+            .map(|event| event.unwrap())
+            .collect()
+    }
+
+    fn payload_event(id: Uuid, seq: u64, payload: &str) -> Event {
+        let mut event = Event::with_id(id, Action::Write, seq);
+        event.set_payload(Some(bincode::serialize(payload).unwrap()));
+        event
+    }
+
     #[test]
     fn sstable_from_memtable_test() {
-        let mut memtable = MemTable::new();
-        for i in 0..5 {
-            memtable.insert(Event::new(Action::Read));
-        }
-        let mut sstable = SSTable::from_memtable("/tmp", memtable);
+        let dir = test_dir("from-memtable");
+        let mut memtable = MemTable::new(StorageMode::History);
+        insert_events(&mut memtable, create_events(5));
+        let mut sstable =
+            SSTable::from_memtable(&dir, 1, memtable, Arc::new(Lz4), FP_RATE).unwrap();
         println!("{}", sstable.filepath.display());
-        sstable.save_to_disk();
+        sstable.save_to_disk().unwrap();
         for event in sstable {
-            println!("Event: {}", event);
+            println!("Event: {}", event.unwrap());
         }
     }
 
     #[test]
     fn sstable_from_file_test() {
-        let mut memtable = MemTable::new();
-        for i in 0..5 {
-            memtable.insert(Event::new(Action::Read));
-        }
-        let mut sstable = SSTable::from_memtable("/tmp", memtable);
-        sstable.save_to_disk();
+        let dir = test_dir("from-file");
+        let mut memtable = MemTable::new(StorageMode::History);
+        insert_events(&mut memtable, create_events(5));
+        let mut sstable =
+            SSTable::from_memtable(&dir, 1, memtable, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable.save_to_disk().unwrap();
 
-        let othertable = SSTable::from_file(sstable.filepath).unwrap();
-        for event in othertable {
-            println!("Event: {}", event);
-        }
+        let othertable =
+            SSTable::from_file(&dir, sstable.id, 0, &CompressionOptions::default()).unwrap();
+        assert_eq!(othertable.into_iter().count(), 5);
     }
 
     #[test]
     fn sstable_merge_test() {
-        let mut memtable1 = MemTable::new();
-        let mut common_id1 = Uuid::from_u128(0);
-        let mut common_id2 = Uuid::from_u128(1);
-        for i in 0..5 {
-            let mut event = Event::new(Action::Read);
-            if i == 0 {
-                common_id1 = event.id();
-                event.set_payload_str("From epoch 1-1");
-            } else if i == 2 {
-                common_id2 = event.id();
-                event.set_payload_str("From epoch 1-2");
-            }
-            memtable1.insert(event);
+        let dir = test_dir("merge");
+        let common_id1 = Uuid::new_v4();
+        let common_id2 = Uuid::new_v4();
+
+        let mut memtable1 = MemTable::new(StorageMode::Latest);
+        insert_events(&mut memtable1, create_events(3));
+        memtable1.insert(payload_event(common_id1, 1, "From epoch 1-1"));
+        memtable1.insert(payload_event(common_id2, 2, "From epoch 1-2"));
+        let mut sstable1 =
+            SSTable::from_memtable(&dir, 1, memtable1, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable1.save_to_disk().unwrap();
+
+        let mut memtable2 = MemTable::new(StorageMode::Latest);
+        insert_events(&mut memtable2, create_events(3));
+        memtable2.insert(payload_event(common_id1, 3, "From epoch 2-1"));
+        memtable2.insert(Event::with_id(common_id2, Action::Delete, 4));
+        let mut sstable2 =
+            SSTable::from_memtable(&dir, 2, memtable2, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable2.save_to_disk().unwrap();
+
+        let tables = [sstable1, sstable2];
+        let mut merged = SSTable::merge(
+            &[&tables[0], &tables[1]],
+            MergeOutput::new(Path::new(&dir), 1, u64::MAX, 3..4, Arc::new(Lz4), FP_RATE),
+            StorageMode::Latest,
+            true,
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 1);
+        let merged = merged.remove(0);
+        assert_eq!(merged.level(), 1);
+        assert_eq!(get_stream(&merged, common_id1).len(), 1);
+        assert_eq!(merged.get(common_id1).unwrap().unwrap().sequence_num(), 3);
+        assert!(!merged.contains(common_id2).unwrap());
+        assert_eq!(merged.into_iter().count(), 7);
+
+        // Keep the delete, and split the output into one table per ID.
+        let merged = SSTable::merge(
+            &[&tables[0], &tables[1]],
+            MergeOutput::new(Path::new(&dir), 1, 1, 4..12, Arc::new(Lz4), FP_RATE),
+            StorageMode::Latest,
+            false,
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 8);
+        for pair in merged.windows(2) {
+            assert!(pair[0].key_range().unwrap().1 < pair[1].key_range().unwrap().0);
         }
-        let mut sstable1 = SSTable::from_memtable("/tmp", memtable1);
-        sstable1.save_to_disk();
+        assert!(merged
+            .iter()
+            .any(|table| table.contains(common_id2).unwrap()));
 
-        let epoch1 = sstable1.epoch;
-        for event in sstable1.iter() {
-            if event.payload().is_some() {
-                println!("Event Set1: {}", event);
-            }
+        // Out of IDs, the last table takes the rest of the events.
+        let short = SSTable::merge(
+            &[&tables[0], &tables[1]],
+            MergeOutput::new(Path::new(&dir), 1, 1, 12..14, Arc::new(Lz4), FP_RATE),
+            StorageMode::Latest,
+            false,
+        )
+        .unwrap();
+        assert_eq!(short.len(), 2);
+        assert_eq!(short[1].iter().unwrap().count(), 7);
+        for table in tables.into_iter().chain(merged).chain(short) {
+            table.remove().unwrap();
         }
+    }
+
+    #[test]
+    fn sstable_merge_history_test() {
+        let dir = test_dir("merge-history");
+        let common_id = Uuid::new_v4();
+
+        let mut memtable1 = MemTable::new(StorageMode::History);
+        insert_events(&mut memtable1, create_events(3));
+        memtable1.insert(payload_event(common_id, 1, "First"));
+        memtable1.insert(payload_event(common_id, 4, "Third"));
+        let mut sstable1 =
+            SSTable::from_memtable(&dir, 1, memtable1, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable1.save_to_disk().unwrap();
 
-        thread::sleep(Duration::from_millis(10));
+        let mut memtable2 = MemTable::new(StorageMode::History);
+        insert_events(&mut memtable2, create_events(3));
+        memtable2.insert(payload_event(common_id, 2, "Second"));
+        memtable2.insert(Event::with_id(common_id, Action::Delete, 6));
+        let mut sstable2 =
+            SSTable::from_memtable(&dir, 2, memtable2, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable2.save_to_disk().unwrap();
+
+        let mut merged = SSTable::merge(
+            &[&sstable1, &sstable2],
+            MergeOutput::new(Path::new(&dir), 1, u64::MAX, 3..4, Arc::new(Lz4), FP_RATE),
+            StorageMode::History,
+            true,
+        )
+        .unwrap();
+        let merged = merged.remove(0);
+        let seqs: Vec<u64> = get_stream(&merged, common_id)
+            .iter()
+            .map(|event| event.sequence_num())
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 4, 6]);
+        assert_eq!(
+            merged.get(common_id).unwrap().unwrap().action(),
+            &Action::Delete
+        );
+        assert_eq!(merged.into_iter().count(), 10);
+    }
 
-        let mut memtable2 = MemTable::new();
-        for i in 0..5 {
-            let mut event = Event::new(Action::Read);
-            if i == 0 {
-                event.set_id(common_id1);
-                event.set_payload_str("From epoch 2-1");
-            } else if i == 2 {
-                event.set_id(common_id2);
-                event.set_payload_str("From epoch 2-2");
+    #[test]
+    fn sstable_merge_failure_test() {
+        let dir = test_dir("merge-failure");
+        let mut memtable = MemTable::new(StorageMode::History);
+        for seq in 0..200 {
+            memtable.insert(payload_event(
+                Uuid::new_v4(),
+                seq,
+                "A payload to fill up the blocks",
+            ));
+        }
+        let mut sstable =
+            SSTable::from_memtable(&dir, 1, memtable, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable.save_to_disk().unwrap();
+
+        // Flip a byte in the last data block, so the merge fails halfway through.
+        let mut bytes = fs::read(&sstable.filepath).unwrap();
+        bytes[sstable.index.blocks.last().unwrap().offset as usize + 8] ^= 0xff;
+        fs::write(&sstable.filepath, bytes).unwrap();
+        let sstable = SSTable::from_file(&dir, 1, 0, &CompressionOptions::default()).unwrap();
+
+        for (table_size, file_ids) in [(u64::MAX, 2..3), (1, 3..1000)] {
+            let result = SSTable::merge(
+                &[&sstable],
+                MergeOutput::new(
+                    Path::new(&dir),
+                    1,
+                    table_size,
+                    file_ids,
+                    Arc::new(Lz4),
+                    FP_RATE,
+                ),
+                StorageMode::History,
+                true,
+            );
+            assert!(matches!(
+                result,
+                Err(StorageEngineError::ChecksumMismatch(_, _))
+            ));
+            let files: Vec<_> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            assert_eq!(files, vec![sstable.filepath.clone()]);
+        }
+    }
+
+    #[test]
+    fn sstable_index_test() {
+        let dir = test_dir("index");
+        let ids: Vec<Uuid> = (0..50).map(|_| Uuid::new_v4()).collect();
+        let mut memtable = MemTable::new(StorageMode::History);
+        for id in &ids {
+            for seq in 0..10 {
+                memtable.insert(payload_event(*id, seq, "A payload to fill up the blocks"));
             }
-            memtable2.insert(event);
-        }
-        let mut sstable2 = SSTable::from_memtable("/tmp", memtable2);
-        sstable2.save_to_disk();
-        let epoch2 = sstable2.epoch;
-        for event in sstable2.iter() {
-            if event.payload().is_some() {
-                println!("Event Set2: {}", event);
+        }
+        let mut sstable =
+            SSTable::from_memtable(&dir, 1, memtable, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable.save_to_disk().unwrap();
+        assert!(sstable.index.blocks.len() > 1);
+
+        let sstable =
+            SSTable::from_file(&dir, sstable.id, 0, &CompressionOptions::default()).unwrap();
+        for id in &ids {
+            let seqs: Vec<u64> = get_stream(&sstable, *id)
+                .iter()
+                .map(|event| event.sequence_num())
+                .collect();
+            assert_eq!(seqs, (0..10).collect::<Vec<u64>>());
+            let seqs: Vec<u64> = sstable
+                .stream(*id, 6)
+                .unwrap()
+                .map(|event| event.unwrap().sequence_num())
+                .collect();
+            assert_eq!(seqs, (6..10).collect::<Vec<u64>>());
+            assert_eq!(sstable.get(*id).unwrap().unwrap().sequence_num(), 9);
+            for seq in 0..10 {
+                let event = sstable.get_at(*id, seq, 0).unwrap().unwrap();
+                assert_eq!((event.id(), event.sequence_num()), (*id, seq));
+                let event = sstable.get_up_to(*id, seq).unwrap().unwrap();
+                assert_eq!((event.id(), event.sequence_num()), (*id, seq));
             }
+            assert_eq!(
+                sstable.get_up_to(*id, 20).unwrap().unwrap().sequence_num(),
+                9
+            );
+            assert!(sstable.get_at(*id, 5, 1).unwrap().is_none());
+            assert!(sstable.get_at(*id, 10, 0).unwrap().is_none());
         }
+        assert!(!sstable.contains(Uuid::new_v4()).unwrap());
+        assert_eq!(sstable.into_iter().count(), 500);
+    }
 
-        let filepath = sstable1.merge(sstable2);
+    #[test]
+    fn sstable_order_test() {
+        let dir = test_dir("order");
+        let mut memtable = MemTable::new(StorageMode::History);
+        // Commit positions run against the sequence numbers.
+        for seq in 1..=300 {
+            let mut event = Event::new(Action::Write, seq);
+            event.set_position(301 - seq);
+            memtable.insert(event);
+        }
+        let mut sstable =
+            SSTable::from_memtable(&dir, 1, memtable, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable.save_to_disk().unwrap();
+        assert!(sstable.index.orders[Order::Position as usize].chunks.len() > 2);
 
-        let merged_sstable = SSTable::from_file(filepath).unwrap();
-        for event in merged_sstable {
-            if event.payload().is_some() {
-                println!("Merged Event Set (Common ID): {}", event);
-            }
+        let sstable =
+            SSTable::from_file(&dir, sstable.id, 0, &CompressionOptions::default()).unwrap();
+        let positions = |from: (u64, u64)| -> Vec<u64> {
+            sstable
+                .ordered(Order::Position, from)
+                .unwrap()
+                .map(|entry| entry.unwrap().position)
+                .collect()
+        };
+        assert_eq!(positions((0, 0)), (1..=300).collect::<Vec<u64>>());
+        assert_eq!(positions((200, 0)), (200..=300).collect::<Vec<u64>>());
+        // Within the position, the key goes on to the sequence number.
+        assert_eq!(positions((200, 102)), (201..=300).collect::<Vec<u64>>());
+        assert!(positions((301, 0)).is_empty());
+        let entry = sstable.ordered(Order::Position, (1, 0)).unwrap().next();
+        assert_eq!(entry.unwrap().unwrap().seq, 300);
+        let seqs: Vec<u64> = sstable
+            .ordered(Order::Seq, (100, 0))
+            .unwrap()
+            .map(|entry| entry.unwrap().seq)
+            .collect();
+        assert_eq!(seqs, (100..=300).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn sstable_filter_rate_test() {
+        let dir = test_dir("filter-rate");
+        let mut memtable = MemTable::new(StorageMode::History);
+        insert_events(&mut memtable, create_events(100));
+        let memtable = Arc::new(memtable);
+        let filter_size = |id: u64, fp_rate: f64| {
+            let mut sstable =
+                SSTable::from_memtable(&dir, id, memtable.clone(), Arc::new(Lz4), fp_rate).unwrap();
+            sstable.save_to_disk().unwrap();
+            let sstable = SSTable::from_file(&dir, id, 0, &CompressionOptions::default()).unwrap();
+            sstable.index.filter.to_bytes().unwrap().len()
+        };
+        assert!(filter_size(1, 0.0001) > 2 * filter_size(2, 0.1));
+    }
+
+    #[test]
+    fn sstable_corruption_test() {
+        let dir = test_dir("corruption");
+        let mut memtable = MemTable::new(StorageMode::History);
+        insert_events(&mut memtable, create_events(3));
+        let mut sstable =
+            SSTable::from_memtable(&dir, 1, memtable, Arc::new(Lz4), FP_RATE).unwrap();
+        sstable.save_to_disk().unwrap();
+
+        // Flip a byte in the data block.
+        let mut bytes = std::fs::read(&sstable.filepath).unwrap();
+        bytes[HEADER_SIZE + 8] ^= 0xff;
+        std::fs::write(&sstable.filepath, &bytes).unwrap();
+
+        let results: Vec<_> =
+            SSTable::from_file(&dir, sstable.id, 0, &CompressionOptions::default())
+                .unwrap()
+                .into_iter()
+                .collect();
+        assert_eq!(results.len(), 1);
+        assert!(matches!(
+            results[0],
+            Err(StorageEngineError::ChecksumMismatch(_, _))
+        ));
+
+        // Flip a byte in the footer.
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&sstable.filepath, bytes).unwrap();
+        assert!(matches!(
+            SSTable::from_file(&dir, sstable.id, 0, &CompressionOptions::default()),
+            Err(StorageEngineError::InvalidTableFooter(_))
+        ));
+    }
+
+    #[test]
+    fn sstable_compression_test() {
+        let dir = test_dir("compression");
+        let payload = r#"{"order": 42, "items": ["book", "pen"], "status": "shipped"}"#;
+        let mut memtable = MemTable::new(StorageMode::History);
+        for seq in 0..100 {
+            memtable.insert(payload_event(Uuid::new_v4(), seq, payload));
         }
-        if epoch1 > epoch2 {
-            println!("Merged set contains epoch1");
-        } else {
-            println!("Merged set contains epoch2");
+        let memtable = Arc::new(memtable);
+        let mut plain =
+            SSTable::from_memtable(&dir, 1, memtable.clone(), Arc::new(NoCompression), FP_RATE)
+                .unwrap();
+        plain.save_to_disk().unwrap();
+        let mut compressed =
+            SSTable::from_memtable(&dir, 2, memtable, Arc::new(Lz4), FP_RATE).unwrap();
+        compressed.save_to_disk().unwrap();
+        assert!(compressed.size() * 3 < plain.size());
+
+        // Tables written with different codecs sit side by side.
+        let options = CompressionOptions::default();
+        for id in [1, 2] {
+            let table = SSTable::from_file(&dir, id, 0, &options).unwrap();
+            assert_eq!(table.into_iter().filter(|event| event.is_ok()).count(), 100);
         }
+
+        // A codec missing from the options can't be read.
+        let mut bytes = fs::read(&plain.filepath).unwrap();
+        bytes[HEADER_SIZE - 1] = 99;
+        fs::write(&plain.filepath, bytes).unwrap();
+        assert!(matches!(
+            SSTable::from_file(&dir, 1, 0, &options),
+            Err(StorageEngineError::UnknownCodec(_, 99))
+        ));
     }
 }
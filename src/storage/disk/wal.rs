@@ -1,89 +1,158 @@
 use std::{
+    fs,
     fs::{File, OpenOptions},
-    io::{self, BufRead, BufReader, BufWriter, Write},
+    io::{self, BufReader, BufWriter, ErrorKind, Write},
+    mem,
     path::{Path, PathBuf},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::Arc,
+    time::Instant,
+    vec,
 };
 
 use tracing::error;
-use uuid::Uuid;
 
-use crate::{Action, Event, StorageEngineError};
+use crate::{
+    storage::{sync_dir, write_header, write_record, FileKind, RecordReader},
+    Codec, CompressionOptions, Durability, Event, StorageEngineError,
+};
 
 /// This is the Write-Ahead Log
 /// This part, again, follows this [blog](https://adambcomer.com/blog/simple-database/Wal/)
+/// Events are stored as framed records (see `record.rs`), compressed with the codec recorded in the header.
+/// Every record holds a batch of events, so a batch is recovered whole or not at all.
 pub(crate) struct WalIterator {
-    reader: BufReader<File>,
+    reader: RecordReader<BufReader<File>>,
+    codec: Arc<dyn Codec>,
+    batch: vec::IntoIter<Event>,
 }
 
 impl WalIterator {
     /// Create a new iterator from the file path.
-    pub(crate) fn new(path: PathBuf) -> io::Result<WalIterator> {
-        let file = OpenOptions::new().read(true).open(path)?;
-        let reader = BufReader::new(file);
-        Ok(Self { reader })
+    /// The codec recorded in the header must be one of the compression options.
+    pub(crate) fn new(
+        path: PathBuf,
+        compression: &CompressionOptions,
+    ) -> Result<WalIterator, StorageEngineError> {
+        let mut reader = Self::reader(path)?;
+        let codec = reader.codec(compression)?;
+        Ok(Self {
+            reader,
+            codec,
+            batch: Vec::new().into_iter(),
+        })
+    }
+
+    fn reader(path: PathBuf) -> io::Result<RecordReader<BufReader<File>>> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        Ok(RecordReader::new(BufReader::new(file), path, FileKind::Wal))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<Event>, StorageEngineError> {
+        Ok(bincode::deserialize(&self.codec.decompress(data)?)?)
     }
 }
 
 impl Iterator for WalIterator {
-    type Item = Event;
+    type Item = Result<Event, StorageEngineError>;
 
     /// Get the next entry in the Wal file.
+    /// A corrupted record is returned as an error and ends the iteration.
     fn next(&mut self) -> Option<Self::Item> {
-        let mut data_bytes = Vec::new();
-        match self.reader.read_until(b'|', &mut data_bytes) {
-            Ok(sz) => {
-                if sz == 0 {
-                    return None;
-                }
-                match bincode::deserialize::<Event>(&data_bytes) {
-                    Ok(event) => Some(event),
-                    Err(e) => {
-                        error!("Error getting next event: {}", e);
-                        None
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Error getting next event: {}", e);
-                None
+        if let Some(event) = self.batch.next() {
+            return Some(Ok(event));
+        }
+        let batch = match self.reader.next_record() {
+            Ok(Some(data)) => self.decode(&data),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        match batch {
+            Ok(batch) => {
+                self.batch = batch.into_iter();
+                self.next()
             }
+            Err(e) => Some(Err(e)),
         }
     }
 }
 
+/// A Wal protects a single MemTable.
+/// Once that MemTable is flushed to an SSTable, the Wal is retired.
 pub(crate) struct Wal {
     path: PathBuf,
+    id: u64,
     file: BufWriter<File>,
+    codec: Arc<dyn Codec>,
+    durability: Durability,
+    /// Bytes appended since the last fsync.
+    unsynced_bytes: usize,
+    last_sync: Instant,
+    /// The length of the file, counting the bytes still buffered.
+    len: u64,
+    /// A failed append couldn't be cut off, so no more events are appended.
+    poisoned: bool,
 }
 
 impl Wal {
     const WAL_NAME: &str = "rdeebee";
 
-    /// Create a new Wal.
-    pub(crate) fn new(dir: &str) -> Result<Self, StorageEngineError> {
-        let dir_path = PathBuf::from(dir);
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros();
-        let temp_file = dir_path.join(format!("{}-{}.wal", Self::WAL_NAME, timestamp));
-        let file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&temp_file)?;
-        let file = BufWriter::new(file);
-        Ok(Self {
-            path: temp_file,
-            file,
-        })
+    /// Create a new Wal with the file ID.
+    pub(crate) fn new(
+        dir: &str,
+        id: u64,
+        durability: Durability,
+        compression: &CompressionOptions,
+    ) -> Result<Self, StorageEngineError> {
+        Self::from_path(&Self::file_path(dir, id), durability, compression)
+    }
+
+    /// Get the path of the Wal file with the ID.
+    pub(crate) fn file_path(dir: &str, id: u64) -> PathBuf {
+        PathBuf::from(dir).join(format!("{}-{}.wal", Self::WAL_NAME, id))
     }
 
     /// Create a Wal from existing file.
-    pub(crate) fn from_path(path: &Path) -> io::Result<Self> {
+    /// The file header is written and synced if the file is new, recording the Wal codec of the options.
+    /// An existing file keeps the codec in its header.
+    pub(crate) fn from_path(
+        path: &Path,
+        durability: Durability,
+        compression: &CompressionOptions,
+    ) -> Result<Self, StorageEngineError> {
+        let id = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.rsplit('-').next())
+            .and_then(|id| id.parse::<u64>().ok())
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid wal file name"))?;
         let file = OpenOptions::new().create(true).append(true).open(path)?;
-        let file = BufWriter::new(file);
+        let is_new = file.metadata()?.len() == 0;
+        let mut file = BufWriter::new(file);
+        let codec = match is_new {
+            true => {
+                let codec = compression.wal_codec.clone();
+                write_header(&mut file, FileKind::Wal, codec.id())?;
+                file.flush()?;
+                file.get_ref().sync_all()?;
+                if let Some(dir) = path.parent() {
+                    sync_dir(dir)?;
+                }
+                codec
+            }
+            false => WalIterator::reader(path.to_owned())?.codec(compression)?,
+        };
+        let len = file.get_ref().metadata()?.len();
 
         Ok(Wal {
             path: path.to_owned(),
+            id,
             file,
+            codec,
+            durability,
+            unsynced_bytes: 0,
+            last_sync: Instant::now(),
+            len,
+            poisoned: false,
         })
     }
 
@@ -92,81 +161,177 @@ impl Wal {
         self.path.clone()
     }
 
-    /// Add an event to the Wal
-    pub(crate) fn add_event(&mut self, event: Event) -> Result<(), StorageEngineError> {
-        let event_ser = bincode::serialize(&event)?;
-        self.file.write_all(&event_ser)?;
-        self.file.write_all("|".as_bytes())?; // delimiter
+    /// Get the file ID of the Wal.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Add the events to the Wal as a single record, then apply the durability policy.
+    /// The record checksum covers the whole batch, so recovery never sees part of it.
+    /// Returns true if every event in the Wal is durable under the policy,
+    /// false if the events wait for a group commit.
+    /// If the write, flush or fsync fails, the record is cut off, so recovery never replays a failed append.
+    pub(crate) fn add_events(&mut self, events: &[Event]) -> Result<bool, StorageEngineError> {
+        if self.poisoned {
+            return Err(StorageEngineError::PoisonedWal(self.path.clone()));
+        }
+        let batch = self.codec.compress(&bincode::serialize(events)?);
+        let (len, unsynced_bytes) = (self.len, self.unsynced_bytes);
+        match self.append(&batch) {
+            Ok(durable) => Ok(durable),
+            Err(e) => {
+                if let Err(cut) = self.cut_off(len) {
+                    error!(
+                        "failed to cut off failed append to {}: {}",
+                        self.path.display(),
+                        cut
+                    );
+                    self.poisoned = true;
+                }
+                self.unsynced_bytes = unsynced_bytes;
+                Err(e.into())
+            }
+        }
+    }
+
+    fn append(&mut self, batch: &[u8]) -> io::Result<bool> {
+        let written = write_record(&mut self.file, batch)?;
+        self.len += written as u64;
+        self.unsynced_bytes += written;
+        match self.durability {
+            Durability::Sync => self.sync()?,
+            Durability::GroupCommit { max_bytes, .. } => {
+                if self.unsynced_bytes < max_bytes && !self.sync_due() {
+                    return Ok(false);
+                }
+                self.sync()?;
+            }
+            Durability::Buffered => self.flush()?,
+        }
+        Ok(true)
+    }
+
+    /// Drop whatever a failed append left buffered or in the file past the length.
+    /// The file is opened again, as the handle that failed may keep failing.
+    fn cut_off(&mut self, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        let failed = mem::replace(&mut self.file, BufWriter::new(file));
+        // Drop the buffered bytes without writing them.
+        drop(failed.into_parts());
+        self.file.get_ref().set_len(len)?;
+        self.file.get_ref().sync_data()?;
+        self.len = len;
         Ok(())
     }
 
-    /// Append a delete operation to the Wal
-    pub(crate) fn delete_event(
-        &mut self,
-        event_id: Uuid,
-        seq: u64,
-    ) -> Result<(), StorageEngineError> {
-        let mut event = Event::new(Action::Delete, seq);
-        event.set_id(event_id);
-        self.add_event(event)?;
+    /// Is a group commit waiting on the interval to pass?
+    pub(crate) fn sync_due(&self) -> bool {
+        match self.durability {
+            Durability::GroupCommit { interval, .. } => {
+                self.unsynced_bytes > 0 && self.last_sync.elapsed() >= interval
+            }
+            Durability::Sync | Durability::Buffered => false,
+        }
+    }
+
+    /// Are events waiting on a group commit?
+    pub(crate) fn commit_pending(&self) -> bool {
+        matches!(self.durability, Durability::GroupCommit { .. }) && self.unsynced_bytes > 0
+    }
+
+    /// Flush the buffered events to the Wal file.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Flush and fsync the Wal file, making every appended event durable.
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
         self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
         Ok(())
     }
+
+    /// Delete the Wal file.
+    /// Only call this once the MemTable it protects is durably in an SSTable.
+    pub(crate) fn retire(self) -> io::Result<()> {
+        let Wal { path, file, .. } = self;
+        drop(file);
+        fs::remove_file(path)
+    }
 }
 
 impl IntoIterator for Wal {
-    type Item = Event;
+    type Item = Result<Event, StorageEngineError>;
     type IntoIter = WalIterator;
 
     fn into_iter(self) -> Self::IntoIter {
-        WalIterator::new(self.path).unwrap()
+        WalIterator {
+            reader: WalIterator::reader(self.path).unwrap(),
+            codec: self.codec,
+            batch: Vec::new().into_iter(),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::{
-        env,
-        fs::{self, File},
-        io::{BufRead, BufReader, BufWriter, Read, Write},
-        str,
+        fs::{self, OpenOptions},
+        io::BufWriter,
+        slice,
+        sync::Arc,
+        time::Duration,
     };
 
-    use crate::storage::{Action, Event};
+    use crate::{Action, CompressionOptions, Durability, Event, Lz4};
 
-    use super::Wal;
+    use super::{Wal, WalIterator};
 
-    const TEST_DIR: &str = "/tmp";
+    /// A new Wal in an empty directory of its own.
+    fn new_wal(name: &str, durability: Durability) -> Wal {
+        new_wal_with(name, durability, &CompressionOptions::default())
+    }
+
+    fn new_wal_with(name: &str, durability: Durability, compression: &CompressionOptions) -> Wal {
+        // Unique to this process so concurrent test runs don't share it.
+        let dir = std::env::temp_dir().join(format!("rdeebee-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Wal::new(dir.to_str().unwrap(), 1, durability, compression).unwrap()
+    }
 
     #[test]
     fn write_Wal_test() {
-        let mut Wal = Wal::new(TEST_DIR).unwrap();
+        let mut Wal = new_wal("write", Durability::Buffered);
         // create two events
-        let event1 = Event::new(Action::Read);
-        let mut event2 = Event::new(Action::Read);
+        let event1 = Event::new(Action::Read, 0);
+        let mut event2 = Event::new(Action::Read, 0);
         // set payload on one event
         let payload2 = Some(bincode::serialize("This is second event read").unwrap());
         event2.set_payload(payload2);
 
-        Wal.add_event(event1).unwrap();
-        Wal.add_event(event2).unwrap();
-        Wal.flush();
+        Wal.add_events(&[event1]).unwrap();
+        Wal.add_events(&[event2]).unwrap();
+        Wal.flush().unwrap();
     }
 
     #[test]
     fn iterate_Wal_test() {
-        let mut Wal = Wal::new(TEST_DIR).unwrap();
+        let mut Wal = new_wal("iterate", Durability::Buffered);
         // create two events
-        let event1 = Event::new(Action::Read);
-        let mut event2 = Event::new(Action::Read);
+        let event1 = Event::new(Action::Read, 0);
+        let mut event2 = Event::new(Action::Read, 0);
         // set payload on one event
         let payload2 = Some(bincode::serialize("This is second event read").unwrap());
         event2.set_payload(payload2);
 
-        Wal.add_event(event1).unwrap();
-        Wal.add_event(event2).unwrap();
+        Wal.add_events(&[event1]).unwrap();
+        Wal.add_events(&[event2]).unwrap();
 
         for event in Wal {
+            let event = event.unwrap();
             println!("Event: {}", event);
             match event.payload() {
                 Some(payload) => {
@@ -180,23 +345,29 @@ mod test {
 
     #[test]
     fn load_Wal_test() {
-        let mut Wal = Wal::new(TEST_DIR).unwrap();
+        let mut Wal = new_wal("load", Durability::Buffered);
         // create two events
-        let event1 = Event::new(Action::Read);
-        let mut event2 = Event::new(Action::Read);
+        let event1 = Event::new(Action::Read, 0);
+        let mut event2 = Event::new(Action::Read, 0);
         // set payload on one event
         let payload2 = Some(bincode::serialize("This is second event read").unwrap());
         event2.set_payload(payload2);
 
-        Wal.add_event(event1).unwrap();
-        Wal.add_event(event2).unwrap();
-        Wal.flush();
+        Wal.add_events(&[event1]).unwrap();
+        Wal.add_events(&[event2]).unwrap();
+        Wal.flush().unwrap();
 
-        let new_Wal = Wal::from_path(&Wal.path).unwrap();
+        let new_Wal = Wal::from_path(
+            &Wal.path,
+            Durability::Buffered,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
         println!("Old Wal: {:#?}", &Wal.path);
         println!("New Wal: {:#?}", &new_Wal.path);
 
         for event in new_Wal {
+            let event = event.unwrap();
             println!("Event: {}", event);
             match event.payload() {
                 Some(payload) => {
@@ -207,4 +378,99 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn delimiter_payload_wal_test() {
+        let mut wal = new_wal("delimiter", Durability::Buffered);
+        let mut event = Event::new(Action::Write, 1);
+        event.set_payload(Some(vec![b'|'; 16]));
+        wal.add_events(slice::from_ref(&event)).unwrap();
+        wal.add_events(&[Event::new(Action::Write, 2)]).unwrap();
+        wal.flush().unwrap();
+
+        let events: Vec<Event> = wal.into_iter().map(|event| event.unwrap()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], event);
+    }
+
+    #[test]
+    fn group_commit_wal_test() {
+        let durability = Durability::GroupCommit {
+            interval: Duration::from_secs(3600),
+            max_bytes: 1024,
+        };
+        let mut wal = new_wal("group-commit", durability);
+        assert!(!wal.add_events(&[Event::new(Action::Write, 1)]).unwrap());
+        assert!(!wal.sync_due());
+
+        let mut event = Event::new(Action::Write, 2);
+        event.set_payload(Some(vec![0; 1024]));
+        assert!(wal.add_events(&[event]).unwrap());
+        assert_eq!(wal.into_iter().count(), 2);
+    }
+
+    #[test]
+    fn failed_append_wal_test() {
+        let mut wal = new_wal("failed-append", Durability::Sync);
+        wal.add_events(&[Event::new(Action::Write, 1)]).unwrap();
+        let len = fs::metadata(wal.path()).unwrap().len();
+
+        // A handle that can't write makes the append fail when it is synced.
+        let read_only = OpenOptions::new().read(true).open(wal.path()).unwrap();
+        wal.file = BufWriter::new(read_only);
+        assert!(wal.add_events(&[Event::new(Action::Write, 2)]).is_err());
+        assert_eq!(fs::metadata(wal.path()).unwrap().len(), len);
+
+        // The Wal goes on with the failed append cut off.
+        wal.add_events(&[Event::new(Action::Write, 3)]).unwrap();
+        let seqs: Vec<u64> = wal
+            .into_iter()
+            .map(|event| event.unwrap().sequence_num())
+            .collect();
+        assert_eq!(seqs, vec![1, 3]);
+    }
+
+    #[test]
+    fn retire_wal_test() {
+        let mut wal = new_wal("retire", Durability::Sync);
+        wal.add_events(&[Event::new(Action::Write, 1)]).unwrap();
+        let path = wal.path();
+        assert_eq!(wal.id(), 1);
+        wal.retire().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sync_wal_test() {
+        let mut wal = new_wal("sync", Durability::Sync);
+        assert!(wal.add_events(&[Event::new(Action::Write, 1)]).unwrap());
+        assert_eq!(wal.into_iter().count(), 1);
+    }
+
+    #[test]
+    fn compressed_wal_test() {
+        let compression = CompressionOptions {
+            wal_codec: Arc::new(Lz4),
+            ..Default::default()
+        };
+        let mut wal = new_wal_with("compressed", Durability::Sync, &compression);
+        let mut event = Event::new(Action::Write, 1);
+        event.set_payload(Some(br#"{"status": "shipped"}"#.repeat(10)));
+        wal.add_events(slice::from_ref(&event)).unwrap();
+
+        // Reopened without compression, the Wal keeps the codec in its header.
+        let mut wal = Wal::from_path(
+            &wal.path(),
+            Durability::Sync,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+        wal.add_events(&[Event::new(Action::Write, 2)]).unwrap();
+        let events: Vec<Event> = WalIterator::new(wal.path(), &CompressionOptions::default())
+            .unwrap()
+            .map(|event| event.unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0], event);
+    }
 }
@@ -1,27 +1,51 @@
-use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-    num::Wrapping,
-};
+use std::{f64::consts::LN_2, num::Wrapping};
 
 use bitvec::{bitvec, prelude::Msb0, vec::BitVec};
 use fxhash::hash64;
 use uuid::Uuid;
 
+use crate::{FilterOptions, StorageEngineError};
+
 /// This is the custom bloomfilter implementation.
 /// This is used to figure out if a key exists in the database before ever searching the SSTables.
 /// The bloomfilter guarantees that failed membership test means the key does not exist.
+///
+/// A plain filter keeps one bit per slot and cannot forget an ID.
+/// A counting filter keeps a small counter per slot instead, so IDs can be deleted
+/// without clearing slots shared with other IDs.
 pub(crate) struct BloomFilter {
     arr: BFInner,
 }
 
 impl BloomFilter {
-    pub(crate) fn new() -> BloomFilter {
+    /// Create a plain filter sized for the options.
+    /// Used for the filters persisted with the SSTables, which never delete.
+    pub(crate) fn new(options: FilterOptions) -> BloomFilter {
+        let (size, num_hashes) = Self::dimensions(options);
         Self {
-            arr: BFInner::new(),
+            arr: BFInner::new(Slots::Bits(bitvec!(u8, Msb0; 0; size)), num_hashes),
         }
     }
 
+    /// Create a counting filter sized for the options, which supports `delete`.
+    pub(crate) fn counting(options: FilterOptions) -> BloomFilter {
+        let (size, num_hashes) = Self::dimensions(options);
+        Self {
+            arr: BFInner::new(Slots::Counters(vec![0; size.div_ceil(2)]), num_hashes),
+        }
+    }
+
+    /// The number of slots and hashes for the capacity and false positive rate.
+    /// check here for the formulas - https://hur.st/bloomfilter/
+    fn dimensions(options: FilterOptions) -> (usize, usize) {
+        let entries = options.capacity.max(1) as f64;
+        let size = (-entries * options.fp_rate.ln() / (LN_2 * LN_2))
+            .ceil()
+            .max(1.0) as usize;
+        let num_hashes = ((size as f64 / entries) * LN_2).round().max(1.0) as usize;
+        (size, num_hashes)
+    }
+
     pub(crate) fn find(&self, id: Uuid) -> bool {
         self.arr.find(id)
     }
@@ -30,49 +54,135 @@ impl BloomFilter {
         self.arr.add(id);
     }
 
+    /// Only delete an ID that was added, and only once per add.
+    /// A plain filter ignores deletes, so the ID stays a possible member.
     pub(crate) fn delete(&mut self, id: Uuid) {
         self.arr.delete(id);
     }
+
+    /// Serialize the filter, to be written to disk.
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, StorageEngineError> {
+        let arr = &self.arr;
+        let (counting, raw) = match &arr.slots {
+            Slots::Bits(bits) => (false, bits.as_raw_slice()),
+            Slots::Counters(counters) => (true, counters.as_slice()),
+        };
+        Ok(bincode::serialize(&(
+            counting,
+            arr.size as u64,
+            arr.num_hashes as u64,
+            raw,
+        ))?)
+    }
+
+    /// Deserialize a filter written by `to_bytes`.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<BloomFilter, StorageEngineError> {
+        let (counting, size, num_hashes, raw) =
+            bincode::deserialize::<(bool, u64, u64, Vec<u8>)>(bytes)?;
+        let size = size as usize;
+        let slots = match counting {
+            true if raw.len() == size.div_ceil(2) => Slots::Counters(raw),
+            false if raw.len() == size.div_ceil(8) => {
+                let mut bits = BitVec::from_vec(raw);
+                bits.truncate(size);
+                Slots::Bits(bits)
+            }
+            _ => return Err(StorageEngineError::InvalidBloomFilter),
+        };
+        if size == 0 || num_hashes == 0 {
+            return Err(StorageEngineError::InvalidBloomFilter);
+        }
+        Ok(Self {
+            arr: BFInner::new(slots, num_hashes as usize),
+        })
+    }
 }
 
-// Accomodate 10 million entries with a false positive probability of 1e-7.
-// But since we only want to check if something does not exist, there is some room for error?
+/// Counters are 4 bits wide, two to a byte.
+/// A counter that reaches the maximum sticks there, since its true count is unknown from then on.
+const COUNTER_MAX: u8 = 0x0f;
 
-// check here for the values (for 10M IDs) - https://hur.st/bloomfilter/?n=10000000&p=1.0E-7&m=&k=10
-const BF_SIZE: usize = 56_166_771;
-const NUM_HASHES: usize = 10;
+enum Slots {
+    Bits(BitVec<u8, Msb0>),
+    Counters(Vec<u8>),
+}
+
+impl Slots {
+    fn len(&self) -> usize {
+        match self {
+            Slots::Bits(bits) => bits.len(),
+            Slots::Counters(counters) => counters.len() * 2,
+        }
+    }
+
+    fn counter(counters: &[u8], index: usize) -> u8 {
+        (counters[index / 2] >> ((index % 2) * 4)) & COUNTER_MAX
+    }
+
+    fn set_counter(counters: &mut [u8], index: usize, value: u8) {
+        let shift = (index % 2) * 4;
+        let byte = &mut counters[index / 2];
+        *byte = (*byte & !(COUNTER_MAX << shift)) | (value << shift);
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        match self {
+            Slots::Bits(bits) => bits.get(index).is_some_and(|bit| *bit),
+            Slots::Counters(counters) => Self::counter(counters, index) > 0,
+        }
+    }
+
+    fn increment(&mut self, index: usize) {
+        match self {
+            Slots::Bits(bits) => bits.set(index, true),
+            Slots::Counters(counters) => {
+                let value = Self::counter(counters, index);
+                if value < COUNTER_MAX {
+                    Self::set_counter(counters, index, value + 1);
+                }
+            }
+        }
+    }
+
+    fn decrement(&mut self, index: usize) {
+        match self {
+            Slots::Bits(_) => {}
+            Slots::Counters(counters) => {
+                let value = Self::counter(counters, index);
+                if value > 0 && value < COUNTER_MAX {
+                    Self::set_counter(counters, index, value - 1);
+                }
+            }
+        }
+    }
+}
 
 struct BFInner {
-    inner: BitVec<u8, Msb0>,
+    slots: Slots,
     size: usize,
+    num_hashes: usize,
 }
 
 impl BFInner {
-    fn new() -> Self {
+    /// The counters are packed in bytes, so there may be one more slot than asked for.
+    fn new(slots: Slots, num_hashes: usize) -> Self {
         Self {
-            inner: bitvec!(u8, Msb0; 0; BF_SIZE),
-            size: BF_SIZE,
+            size: slots.len(),
+            slots,
+            num_hashes,
         }
     }
 
     fn find(&self, id: Uuid) -> bool {
-        for index in self.hash(id) {
-            match self.inner.get(index) {
-                Some(val) => {
-                    if !*val {
-                        return false;
-                    }
-                }
-                None => return false,
-            }
-        }
-        true
+        self.hash(id)
+            .into_iter()
+            .all(|index| self.slots.is_set(index))
     }
 
+    /// The filters are persisted, so both hashes have to be stable across builds.
+    /// std's `DefaultHasher` makes no such promise.
     fn calculate_hash(id: Uuid) -> u64 {
-        let mut s = DefaultHasher::new();
-        id.hash(&mut s);
-        s.finish()
+        hash64(&id.as_u128().rotate_left(64))
     }
 
     fn hash_i(&self, id: Uuid, i: usize) -> usize {
@@ -86,7 +196,7 @@ impl BFInner {
 
     fn hash(&self, id: Uuid) -> Vec<usize> {
         let mut hashes = Vec::new();
-        for i in 0..NUM_HASHES {
+        for i in 0..self.num_hashes {
             hashes.push(self.hash_i(id, i));
         }
         hashes
@@ -94,13 +204,13 @@ impl BFInner {
 
     fn add(&mut self, id: Uuid) {
         for index in self.hash(id) {
-            self.inner.set(index, true);
+            self.slots.increment(index);
         }
     }
 
     fn delete(&mut self, id: Uuid) {
         for index in self.hash(id) {
-            self.inner.set(index, false);
+            self.slots.decrement(index);
         }
     }
 }
@@ -110,32 +220,69 @@ mod test {
     use uuid::Uuid;
 
     use super::BloomFilter;
+    use crate::FilterOptions;
+
+    const OPTIONS: FilterOptions = FilterOptions {
+        capacity: 1000,
+        fp_rate: 0.01,
+    };
 
     #[test]
     fn bf_create_test() {
-        let bf = BloomFilter::new();
+        let _bf = BloomFilter::new(OPTIONS);
     }
 
     #[test]
     fn bf_add_test() {
-        let mut bf = BloomFilter::new();
+        let mut bf = BloomFilter::new(OPTIONS);
         let id = Uuid::new_v4();
         bf.add(id);
     }
 
     #[test]
     fn bf_find_test() {
-        let mut bf = BloomFilter::new();
+        let mut bf = BloomFilter::new(OPTIONS);
         let id = Uuid::new_v4();
         bf.add(id);
         assert!(bf.find(id));
     }
 
+    #[test]
+    fn bf_persist_test() {
+        let ids: Vec<Uuid> = (0..1000).map(|_| Uuid::new_v4()).collect();
+        let mut bf = BloomFilter::new(OPTIONS);
+        for id in &ids {
+            bf.add(*id);
+        }
+        let bf = BloomFilter::from_bytes(&bf.to_bytes().unwrap()).unwrap();
+        assert!(ids.iter().all(|id| bf.find(*id)));
+        let false_positives = (0..1000).filter(|_| bf.find(Uuid::new_v4())).count();
+        assert!(false_positives < 50);
+    }
+
     #[test]
     fn bf_delete_test() {
-        let mut bf = BloomFilter::new();
+        let mut bf = BloomFilter::counting(OPTIONS);
         let id = Uuid::new_v4();
         bf.add(id);
         bf.delete(id);
+        assert!(!bf.find(id));
+    }
+
+    #[test]
+    fn bf_counting_delete_test() {
+        // A tiny filter, so the IDs share most of their slots.
+        let mut bf = BloomFilter::counting(FilterOptions {
+            capacity: 4,
+            fp_rate: 0.5,
+        });
+        let ids: Vec<Uuid> = (0..8).map(|_| Uuid::new_v4()).collect();
+        for id in &ids {
+            bf.add(*id);
+        }
+        for id in &ids[..4] {
+            bf.delete(*id);
+        }
+        assert!(ids[4..].iter().all(|id| bf.find(*id)));
     }
 }
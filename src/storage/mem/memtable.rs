@@ -1,34 +1,44 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use skiplist::{ordered_skiplist::Iter, OrderedSkipList};
+use im::{ordmap::Iter, OrdMap};
 use uuid::Uuid;
 
-use crate::Event;
+use crate::{
+    storage::{Order, OrderEntry},
+    Event, StorageMode,
+};
 
 pub(crate) struct MemtableIterator<'a> {
-    memtable: &'a MemTable,
-    index: Iter<'a, Uuid>,
+    index: Iter<'a, Uuid, Arc<Vec<Event>>>,
+    current: Option<&'a Vec<Event>>,
+    position: usize,
 }
 
 impl<'a> MemtableIterator<'a> {
     fn new(memtable: &'a MemTable) -> Self {
         Self {
-            memtable,
-            index: (&memtable.identifiers).into_iter(),
+            index: memtable.entries.iter(),
+            current: None,
+            position: 0,
         }
     }
 }
 
-/// The MemtableIterator returns events in ascending order of transaction IDs
-// TODO: This can be optimized further by storing the events with the transaction IDs in the skiplist.
-// This skiplist does not seem to support that so that may require custom implementation.
+/// The MemtableIterator returns events in ascending order of transaction IDs.
+/// Events sharing a transaction ID are returned in ascending order of sequence numbers.
 impl<'a> Iterator for MemtableIterator<'a> {
     type Item = Event;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(id) = self.index.next() {
-            self.memtable.get_event(*id)
-        } else {
-            None
+        loop {
+            if let Some(events) = self.current {
+                if let Some(event) = events.get(self.position) {
+                    self.position += 1;
+                    return Some(event.clone());
+                }
+            }
+            let (_, events) = self.index.next()?;
+            self.current = Some(events.as_ref());
+            self.position = 0;
         }
     }
 }
@@ -37,29 +47,32 @@ impl<'a> Iterator for MemtableIterator<'a> {
 /// We will use this [tutorial](https://adambcomer.com/blog/simple-database/memtable/) to build a MemTable
 /// MemTable holds a sorted list of last written records
 /// MemTables are compacted to the disk (SSTable) when it reaches a certain size
-/// MemTable stores the event(-chain) identifiers in an ordered skiplist.
-/// And it stores the actual event in an HashMap.
-/// The Ordered SkipList is useful when merging the logs (since it is sorted).
-/// The HashMap is useful for mapping each identifier to the actual data associated with that identifier.
+/// MemTable maps the event(-chain) identifiers to their events in a persistent ordered map.
+/// Being sorted is useful when merging the logs.
+/// Being persistent makes a clone cheap: a snapshot shares the MemTable with the writes after it,
+/// and a write only copies the path to its identifier, along with the events of the identifier.
+/// In `StorageMode::History` every event of an identifier is kept, sorted by sequence number.
+/// In `StorageMode::Latest` only the newest event of an identifier is kept.
 /// This is an alternate to using Red Black Trees for memory.
+#[derive(Clone)]
 pub(crate) struct MemTable {
-    identifiers: OrderedSkipList<Uuid>,
-    entries: HashMap<Uuid, Event>,
+    mode: StorageMode,
+    entries: OrdMap<Uuid, Arc<Vec<Event>>>,
     size: usize,
 }
 
 impl MemTable {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(mode: StorageMode) -> Self {
         Self {
-            identifiers: OrderedSkipList::new(),
-            entries: HashMap::new(),
+            mode,
+            entries: OrdMap::new(),
             size: 0,
         }
     }
 
     /// Does this event exist in the MemTable
     pub(crate) fn contains(&self, id: Uuid) -> bool {
-        self.identifiers.contains(&id)
+        self.entries.contains_key(&id)
     }
 
     /// Get memtable size in bytes
@@ -69,16 +82,70 @@ impl MemTable {
 
     /// Insert an event into the database.
     pub(crate) fn insert(&mut self, event: Event) {
-        let id = event.id();
-        self.identifiers.insert(id);
         let sz = event.size();
-        self.entries.insert(id, event);
+        let events = Arc::make_mut(self.entries.entry(event.id()).or_default());
+        match self.mode {
+            StorageMode::Latest => {
+                for old in events.drain(..) {
+                    self.size -= old.size();
+                }
+                events.push(event);
+            }
+            StorageMode::History => {
+                // Events with the same sequence number keep their arrival order.
+                let seq = event.sequence_num();
+                let index = events.partition_point(|e| e.sequence_num() <= seq);
+                events.insert(index, event);
+            }
+        }
         self.size += sz;
     }
 
-    /// Get an event from the database.
+    /// Get the latest event for the identifier from the database.
     pub(crate) fn get_event(&self, transaction: Uuid) -> Option<Event> {
-        self.entries.get(&transaction).map(|event| event.to_owned())
+        self.entries
+            .get(&transaction)
+            .and_then(|events| events.last())
+            .map(|event| event.to_owned())
+    }
+
+    /// Get the latest event for the identifier with a sequence number up to `seq`.
+    pub(crate) fn get_event_up_to(&self, transaction: Uuid, seq: u64) -> Option<Event> {
+        let events = self.entries.get(&transaction)?;
+        let end = events.partition_point(|event| event.sequence_num() <= seq);
+        events[..end].last().cloned()
+    }
+
+    /// Get the event for the identifier with the sequence number and commit position.
+    pub(crate) fn get_event_at(&self, transaction: Uuid, seq: u64, position: u64) -> Option<Event> {
+        let events = self.entries.get(&transaction)?;
+        let start = events.partition_point(|event| event.sequence_num() < seq);
+        events[start..]
+            .iter()
+            .take_while(|event| event.sequence_num() == seq)
+            .find(|event| event.position() == position)
+            .cloned()
+    }
+
+    /// Get every event for the identifier, in ascending order of sequence numbers.
+    pub(crate) fn get_stream(&self, transaction: Uuid) -> Vec<Event> {
+        self.entries
+            .get(&transaction)
+            .map(|events| events.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Get the entries of the events from the key on, sorted in the order.
+    pub(crate) fn ordered(&self, order: Order, from: (u64, u64)) -> Vec<OrderEntry> {
+        let mut entries: Vec<OrderEntry> = self
+            .entries
+            .values()
+            .flat_map(|events| events.iter())
+            .map(OrderEntry::from)
+            .filter(|entry| order.key(entry) >= from)
+            .collect();
+        entries.sort_unstable_by_key(|entry| order.key(entry));
+        entries
     }
 }
 
@@ -93,16 +160,13 @@ impl<'a> IntoIterator for &'a MemTable {
 
 #[cfg(test)]
 mod test {
-    use crate::storage::{
-        event::{Action, Event},
-        mem::memtable::MemTable,
-    };
+    use crate::{storage::mem::memtable::MemTable, Action, Event, StorageMode};
     use rand::{distributions::Alphanumeric, Rng};
 
     fn create_events(n: usize) -> Vec<Event> {
         let mut events = Vec::new();
         for _ in 0..n {
-            events.push(Event::new(Action::Read));
+            events.push(Event::new(Action::Read, 0));
         }
         events
     }
@@ -115,7 +179,7 @@ mod test {
 
     #[test]
     fn memtable_len_test() {
-        let mut memtable = MemTable::new();
+        let mut memtable = MemTable::new(StorageMode::History);
         let mut events = Vec::new();
         for _ in 0..100 {
             let s: String = rand::thread_rng()
@@ -124,7 +188,7 @@ mod test {
                 .map(char::from)
                 .collect();
             let payload = bincode::serialize(&s).unwrap();
-            let mut event = Event::new(Action::Read);
+            let mut event = Event::new(Action::Read, 0);
             event.set_payload(Some(payload));
             events.push(event);
         }
@@ -132,15 +196,15 @@ mod test {
         for event in events {
             memtable.insert(event);
         }
-        assert_eq!(memtable.len(), 100);
+        assert_eq!((&memtable).into_iter().count(), 100);
     }
 
     #[test]
     fn memtable_find_test() {
-        let mut memtable = MemTable::new();
+        let mut memtable = MemTable::new(StorageMode::History);
         let mut events = Vec::new();
-        for i in 0..5 {
-            events.push(Event::new(Action::Read));
+        for _ in 0..5 {
+            events.push(Event::new(Action::Read, 0));
         }
 
         let event = events.get(0).unwrap().to_owned();
@@ -155,7 +219,7 @@ mod test {
 
     #[test]
     fn memtable_size_test() {
-        let mut memtable = MemTable::new();
+        let mut memtable = MemTable::new(StorageMode::History);
         println!("Size at the beginning: {} bytes", memtable.size());
         insert_events(&mut memtable, create_events(10));
         println!("Size at the end: {} bytes", memtable.size());
@@ -163,13 +227,63 @@ mod test {
 
     #[test]
     fn memtable_iterator_test() {
-        let mut memtable = MemTable::new();
-        for i in 0..5 {
-            memtable.insert(Event::new(Action::Read));
+        let mut memtable = MemTable::new(StorageMode::History);
+        for _ in 0..5 {
+            memtable.insert(Event::new(Action::Read, 0));
         }
 
         for event in &memtable {
             println!("{:#?}", event);
         }
     }
+
+    #[test]
+    fn memtable_history_test() {
+        let mut memtable = MemTable::new(StorageMode::History);
+        let first = Event::new(Action::Write, 3);
+        let id = first.id();
+        memtable.insert(first);
+        memtable.insert(Event::with_id(id, Action::Delete, 7));
+        memtable.insert(Event::with_id(id, Action::Write, 5));
+        insert_events(&mut memtable, create_events(3));
+
+        let seqs: Vec<u64> = memtable
+            .get_stream(id)
+            .iter()
+            .map(|event| event.sequence_num())
+            .collect();
+        assert_eq!(seqs, vec![3, 5, 7]);
+        assert_eq!(memtable.get_event(id).unwrap().sequence_num(), 7);
+        assert_eq!((&memtable).into_iter().count(), 6);
+    }
+
+    #[test]
+    fn memtable_latest_test() {
+        let mut memtable = MemTable::new(StorageMode::Latest);
+        let first = Event::new(Action::Write, 1);
+        let id = first.id();
+        let size = first.size();
+        memtable.insert(first);
+        memtable.insert(Event::with_id(id, Action::Write, 2));
+
+        assert_eq!(memtable.get_stream(id).len(), 1);
+        assert_eq!(memtable.get_event(id).unwrap().sequence_num(), 2);
+        assert_eq!(memtable.size(), size);
+    }
+
+    #[test]
+    fn memtable_clone_test() {
+        let mut memtable = MemTable::new(StorageMode::History);
+        let first = Event::new(Action::Write, 1);
+        let id = first.id();
+        memtable.insert(first);
+        let pinned = memtable.clone();
+        memtable.insert(Event::with_id(id, Action::Write, 2));
+        insert_events(&mut memtable, create_events(3));
+
+        assert_eq!(pinned.get_stream(id).len(), 1);
+        assert_eq!((&pinned).into_iter().count(), 1);
+        assert_eq!(memtable.get_stream(id).len(), 2);
+        assert_eq!((&memtable).into_iter().count(), 5);
+    }
 }
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The state of an aggregate folded from the events of a key, as of a version of its stream.
+/// Loading the aggregate starts from the state and replays only the events after that version,
+/// see `RDeeBee::get_aggregate_snapshot` and `RDeeBee::stream_by_key_after_version`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregateSnapshot {
+    key: String,
+    /// The ID of the stream, so a snapshot is dropped along with its stream
+    /// when the key is deleted and written again.
+    id: Uuid,
+    version: u64,
+    state: Vec<u8>,
+}
+
+impl AggregateSnapshot {
+    pub(crate) fn new(key: &str, id: Uuid, version: u64, state: Vec<u8>) -> Self {
+        Self {
+            key: key.to_string(),
+            id,
+            version,
+            state,
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub(crate) fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Get the version of the stream the state covers.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get the serialized state.
+    pub fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    pub fn into_state(self) -> Vec<u8> {
+        self.state
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+};
+
+use uuid::Uuid;
+
+use crate::{Event, StorageMode};
+
+/// The most entries the all-stream index holds, about 100 bytes each plus the key.
+pub(crate) const ALL_STREAM_CAPACITY: usize = 1 << 20;
+
+/// An entry of the all-stream index, pointing at an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IndexEntry<'a> {
+    pub(crate) seq: u64,
+    pub(crate) position: u64,
+    pub(crate) id: Uuid,
+    pub(crate) key: &'a str,
+}
+
+/// An index of the events of every key, ordered by sequence number for `RDeeBee::read_all`,
+/// and by commit position for `RDeeBee::read_changes`.
+/// It is kept in memory and bounded to `capacity` entries, evicting the lowest sequence numbers first,
+/// so only the tail of the database can be read through it, the rest is read through the order indexes of the tables.
+/// Recovery rebuilds it in the same scan of the SSTables and the MemTable that rebuilds the key directory.
+/// Entries point at events by ID, so an event a merge dropped since is skipped when read.
+/// In `StorageMode::Latest` only the latest event of every ID is indexed, too few for change data capture.
+pub(crate) struct AllStreamIndex {
+    mode: StorageMode,
+    capacity: usize,
+    /// The ID and key of every event, by sequence number and commit position.
+    entries: BTreeMap<(u64, u64), (Uuid, String)>,
+    /// The sequence number of every event, by commit position.
+    positions: BTreeMap<u64, u64>,
+    /// The sequence number and commit position indexed for every ID in `StorageMode::Latest`,
+    /// where a new event of an ID replaces the last one.
+    latest: HashMap<Uuid, (u64, u64)>,
+    /// Every sequence number below this one may have been evicted.
+    first_seq: u64,
+    /// Every commit position below this one may have been evicted.
+    first_position: u64,
+}
+
+impl AllStreamIndex {
+    pub(crate) fn new(mode: StorageMode, capacity: usize) -> Self {
+        Self {
+            mode,
+            capacity,
+            entries: BTreeMap::new(),
+            positions: BTreeMap::new(),
+            latest: HashMap::new(),
+            first_seq: 0,
+            first_position: 0,
+        }
+    }
+
+    /// Index the event, newer than every event of its ID indexed so far.
+    pub(crate) fn insert(&mut self, event: &Event) {
+        let id = event.id();
+        let at = (event.sequence_num(), event.position());
+        if self.mode == StorageMode::Latest {
+            if let Some(old) = self.latest.insert(id, at) {
+                self.entries.remove(&old);
+                self.positions.remove(&old.1);
+            }
+        }
+        self.entries.insert(at, (id, event.key().to_string()));
+        self.positions.insert(at.1, at.0);
+        while self.entries.len() > self.capacity {
+            let ((seq, position), (id, _)) = match self.entries.pop_first() {
+                Some(entry) => entry,
+                None => break,
+            };
+            self.positions.remove(&position);
+            if self.latest.get(&id) == Some(&(seq, position)) {
+                self.latest.remove(&id);
+            }
+            self.first_seq = self.first_seq.max(seq + 1);
+            self.first_position = self.first_position.max(position + 1);
+        }
+    }
+
+    /// Get the lowest sequence number a read can start from without missing evicted entries.
+    pub(crate) fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    /// Get the lowest commit position a read can start from without missing evicted entries.
+    pub(crate) fn first_position(&self) -> u64 {
+        self.first_position
+    }
+
+    /// Get the entries with sequence numbers from `from_seq` on, in order of sequence numbers,
+    /// then of commit positions.
+    pub(crate) fn range(&self, from_seq: u64) -> impl Iterator<Item = IndexEntry<'_>> {
+        self.entries
+            .range((Bound::Included((from_seq, 0)), Bound::Unbounded))
+            .map(|((seq, position), (id, key))| IndexEntry {
+                seq: *seq,
+                position: *position,
+                id: *id,
+                key,
+            })
+    }
+
+    /// Get the entries with commit positions from `from_position` on, in order of commit positions.
+    pub(crate) fn changes(&self, from_position: u64) -> impl Iterator<Item = IndexEntry<'_>> {
+        self.positions
+            .range(from_position..)
+            .map(|(position, seq)| {
+                let (id, key) = &self.entries[&(*seq, *position)];
+                IndexEntry {
+                    seq: *seq,
+                    position: *position,
+                    id: *id,
+                    key,
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Action, Event, StorageMode};
+
+    use super::AllStreamIndex;
+
+    fn keyed_event(key: &str, seq: u64, position: u64) -> Event {
+        let mut event = Event::new(Action::Write, seq);
+        event.set_key(key.to_string());
+        event.set_position(position);
+        event
+    }
+
+    #[test]
+    fn all_stream_capacity_test() {
+        let mut index = AllStreamIndex::new(StorageMode::History, 3);
+        for (position, seq) in [4, 1, 5, 2, 6].into_iter().enumerate() {
+            index.insert(&keyed_event("key", seq, position as u64 + 1));
+        }
+        let seqs: Vec<u64> = index.range(0).map(|entry| entry.seq).collect();
+        assert_eq!(seqs, vec![4, 5, 6]);
+        let positions: Vec<u64> = index.changes(0).map(|entry| entry.position).collect();
+        assert_eq!(positions, vec![1, 3, 5]);
+        assert_eq!(index.first_seq(), 3);
+        assert_eq!(index.first_position(), 5);
+    }
+}
@@ -0,0 +1,56 @@
+use protobuf::EnumOrUnknown;
+
+use crate::wire_format::operation::{Operation, Request};
+
+/// Writes and deletes of one or more keys, applied by `RDeeBee::write_batch` as a single Wal record.
+/// After a crash either every event of the batch is recovered or none is.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) requests: Vec<Request>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the payload to the key.
+    pub fn put(&mut self, key: &str, seq: u64, payload: Vec<u8>) -> &mut Self {
+        let mut request = Self::request(key, Operation::Write, seq);
+        request.payload = payload;
+        self.requests.push(request);
+        self
+    }
+
+    /// Delete the key.
+    pub fn delete(&mut self, key: &str, seq: u64) -> &mut Self {
+        self.requests
+            .push(Self::request(key, Operation::Delete, seq));
+        self
+    }
+
+    /// Get the number of writes and deletes in the batch.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    fn request(key: &str, op: Operation, seq: u64) -> Request {
+        let mut request = Request::new();
+        request.key = key.to_string();
+        request.op = EnumOrUnknown::new(op);
+        request.seq = seq;
+        request
+    }
+}
+
+/// A batch from the requests of a `Batch` operation.
+/// Anything but a write or a delete fails the whole batch once it is written.
+impl From<Vec<Request>> for WriteBatch {
+    fn from(requests: Vec<Request>) -> Self {
+        Self { requests }
+    }
+}
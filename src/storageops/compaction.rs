@@ -0,0 +1,595 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    storage::{Manifest, ManifestEdit, MemTable, MergeOutput, SSTable, TableFile, Wal},
+    Codec, Compaction, CompressionOptions, Durability, RDeeBee, StorageEngineError, StorageMode,
+    TableStats,
+};
+
+const MAX_LEVELS: usize = 7;
+
+/// Creates the files of a MemTable flush, handed out by `RDeeBee::prepare_memtable_compact`.
+/// Call `run` without holding the database, then hand the files to `RDeeBee::begin_memtable_compact`.
+pub struct FlushPreparation {
+    dir: String,
+    durability: Durability,
+    compression: CompressionOptions,
+    manifest: Arc<Mutex<Manifest>>,
+    /// Does the flush hand the MemTable over to a new Wal, or flush the MemTable of a failed flush again?
+    new_wal: bool,
+}
+
+impl FlushPreparation {
+    pub(crate) fn new(
+        dir: String,
+        durability: Durability,
+        compression: CompressionOptions,
+        manifest: Arc<Mutex<Manifest>>,
+        new_wal: bool,
+    ) -> Self {
+        Self {
+            dir,
+            durability,
+            compression,
+            manifest,
+            new_wal,
+        }
+    }
+
+    /// Reserve the file IDs of the flush, and start the new Wal.
+    pub fn run(self) -> Result<FlushFiles, StorageEngineError> {
+        // One ID for the table, and one for the new Wal.
+        let count = 1 + u64::from(self.new_wal);
+        let ids = self.manifest.lock().reserve_file_ids(count)?;
+        let wal = match self.new_wal {
+            true => Some(RDeeBee::new_wal(
+                &self.dir,
+                ids.start + 1,
+                self.durability,
+                &self.compression,
+                &self.manifest,
+            )?),
+            false => None,
+        };
+        Ok(FlushFiles {
+            table_id: ids.start,
+            wal,
+        })
+    }
+}
+
+/// The files of a MemTable flush, committed to the manifest by `FlushPreparation::run`.
+pub struct FlushFiles {
+    pub(crate) table_id: u64,
+    pub(crate) wal: Option<Wal>,
+}
+
+/// A MemTable flush, handed out by `RDeeBee::begin_memtable_compact`.
+/// Call `run` without holding the database, then hand it back to `RDeeBee::finish_memtable_compact`.
+pub struct MemTableFlush {
+    dir: String,
+    id: u64,
+    memtable: Arc<MemTable>,
+    codec: Arc<dyn Codec>,
+    fp_rate: f64,
+    table: Option<SSTable>,
+    done: bool,
+}
+
+impl MemTableFlush {
+    pub(crate) fn new(
+        dir: String,
+        id: u64,
+        memtable: Arc<MemTable>,
+        codec: Arc<dyn Codec>,
+        fp_rate: f64,
+    ) -> Self {
+        Self {
+            dir,
+            id,
+            memtable,
+            codec,
+            fp_rate,
+            table: None,
+            done: false,
+        }
+    }
+
+    /// Write and sync the table file.
+    pub fn run(&mut self) -> Result<(), StorageEngineError> {
+        let table = SSTable::from_memtable(
+            &self.dir,
+            self.id,
+            self.memtable.clone(),
+            self.codec.clone(),
+            self.fp_rate,
+        )?;
+        self.table.insert(table).save_to_disk()?;
+        self.done = true;
+        Ok(())
+    }
+
+    /// Get the written table, or None if the flush did not complete.
+    /// An incomplete table file is removed.
+    pub(crate) fn into_table(self) -> Result<Option<SSTable>, StorageEngineError> {
+        match (self.done, self.table) {
+            (true, table) => Ok(table),
+            (false, Some(table)) => {
+                table.remove()?;
+                Ok(None)
+            }
+            (false, None) => Ok(None),
+        }
+    }
+}
+
+/// A merge of SSTables, handed out by `RDeeBee::begin_sstables_compact`.
+/// It holds on to the tables it merges, so it runs without holding the database.
+/// Call `run`, then hand it back to `RDeeBee::finish_sstables_compact`.
+pub struct SSTableMerge {
+    level: usize,
+    output_level: usize,
+    table_size: u64,
+    inputs: Vec<Arc<SSTable>>,
+    /// The tables of the output level that overlap the inputs.
+    overlapping: Vec<Arc<SSTable>>,
+    drop_deletes: bool,
+    dir: PathBuf,
+    mode: StorageMode,
+    /// Reserved for the merged tables.
+    file_ids: Range<u64>,
+    codec: Arc<dyn Codec>,
+    fp_rate: f64,
+    merged: Option<Vec<SSTable>>,
+}
+
+impl SSTableMerge {
+    /// Merge the tables into new table files.
+    pub fn run(&mut self) -> Result<(), StorageEngineError> {
+        // The output level holds the older data, and the inputs are already oldest first in L0.
+        let tables: Vec<&SSTable> = self
+            .overlapping
+            .iter()
+            .chain(self.inputs.iter())
+            .map(|table| table.as_ref())
+            .collect();
+        let output = MergeOutput::new(
+            &self.dir,
+            self.output_level,
+            self.table_size,
+            self.file_ids.clone(),
+            self.codec.clone(),
+            self.fp_rate,
+        );
+        self.merged = Some(SSTable::merge(
+            &tables,
+            output,
+            self.mode,
+            self.drop_deletes,
+        )?);
+        Ok(())
+    }
+}
+
+/// The SSTables of the database, organised in levels.
+/// L0 holds the flushed tables, oldest first, and their key ranges may overlap.
+/// Every level below holds tables with non-overlapping key ranges, sorted by ID,
+/// so at most one table per level can hold an ID.
+/// Data only moves down, so a deeper level holds older data.
+/// Which tables are compacted is up to the `CompactionPolicy`.
+///
+/// Tables are shared with the merges running in the background.
+/// A merge installs its output in place of its inputs once it is done,
+/// even if more tables were flushed into L0 in the meantime.
+#[derive(Clone)]
+pub(crate) struct Levels {
+    levels: Vec<Vec<Arc<SSTable>>>,
+}
+
+impl Levels {
+    pub(crate) fn new() -> Self {
+        Self {
+            levels: (0..MAX_LEVELS).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Arrange recovered tables into their levels.
+    /// The tables must be ordered oldest first.
+    pub(crate) fn from_tables(tables: Vec<SSTable>) -> Self {
+        let mut levels = Self::new();
+        for table in tables {
+            let level = table.level().min(MAX_LEVELS - 1);
+            levels.levels[level].push(Arc::new(table));
+        }
+        for level in levels.levels.iter_mut().skip(1) {
+            level.sort_by_key(|table| table.key_range());
+        }
+        levels
+    }
+
+    /// Add a table flushed from a MemTable.
+    pub(crate) fn add_flushed(&mut self, table: SSTable) {
+        self.levels[0].push(Arc::new(table));
+    }
+
+    /// Iterate over every table, oldest data first.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &SSTable> {
+        self.levels
+            .iter()
+            .rev()
+            .flatten()
+            .map(|table| table.as_ref())
+    }
+
+    /// Get the tables that may hold the ID, oldest data first.
+    pub(crate) fn candidates(&self, id: Uuid) -> Vec<&SSTable> {
+        let mut tables = Vec::new();
+        for level in self.levels.iter().skip(1).rev() {
+            let index =
+                level.partition_point(|table| table.key_range().is_none_or(|(_, last)| last < id));
+            if let Some(table) = level.get(index) {
+                if table.overlaps((id, id)) {
+                    tables.push(table.as_ref());
+                }
+            }
+        }
+        tables.extend(self.levels[0].iter().map(|table| table.as_ref()));
+        tables
+    }
+
+    /// Describe the tables of every level for a `CompactionPolicy`.
+    pub(crate) fn stats(&self) -> Vec<Vec<TableStats>> {
+        self.levels
+            .iter()
+            .map(|level| {
+                level
+                    .iter()
+                    .map(|table| TableStats {
+                        size: table.size(),
+                        key_range: table.key_range(),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The range of IDs covered by the tables.
+    fn key_range(&self, level: usize, tables: &[usize]) -> Option<(Uuid, Uuid)> {
+        tables
+            .iter()
+            .filter_map(|index| self.levels[level][*index].key_range())
+            .reduce(|(first, last), (start, end)| (first.min(start), last.max(end)))
+    }
+
+    /// Check the compaction keeps the invariants of the levels.
+    fn validate(&self, compaction: &Compaction) -> Result<(), StorageEngineError> {
+        let Compaction {
+            level,
+            inputs,
+            output_level,
+            ..
+        } = compaction;
+        let valid = *level < MAX_LEVELS
+            && (*output_level == *level || *output_level == level + 1)
+            && *output_level < MAX_LEVELS
+            && inputs.last().is_some_and(|last| *last < self.levels[*level].len())
+            && inputs.windows(2).all(|pair| pair[0] < pair[1])
+            // Merging within a level, or out of L0, must not reorder the tables.
+            && match (*level, *output_level == *level) {
+                (0, false) => inputs[0] == 0 && inputs.windows(2).all(|pair| pair[0] + 1 == pair[1]),
+                (_, true) => inputs.windows(2).all(|pair| pair[0] + 1 == pair[1]),
+                _ => true,
+            };
+        match valid {
+            true => Ok(()),
+            false => Err(StorageEngineError::InvalidCompaction(*level)),
+        }
+    }
+
+    /// Gather the tables of the compaction into a merge, along with the overlapping tables of the level below.
+    /// The IDs of the merged tables are reserved in the manifest, enough for the merged tables
+    /// to be as large as the tables they replace.
+    /// They are compressed with the codec, and have filters with a false positive probability of `fp_rate`.
+    pub(crate) fn prepare(
+        &self,
+        compaction: Compaction,
+        dir: &Path,
+        mode: StorageMode,
+        manifest: &mut Manifest,
+        codec: Arc<dyn Codec>,
+        fp_rate: f64,
+    ) -> Result<SSTableMerge, StorageEngineError> {
+        self.validate(&compaction)?;
+        let Compaction {
+            level,
+            inputs,
+            output_level,
+            table_size,
+        } = compaction;
+        let range = self.key_range(level, &inputs);
+        let overlapping = match range {
+            Some(range) if output_level != level => self.levels[output_level]
+                .iter()
+                .filter(|table| table.overlaps(range))
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+        // A delete can only be dropped once no older table left out of the merge holds the ID.
+        let drop_deletes = match range {
+            Some(range) => {
+                let older_l0 = match level {
+                    0 => &self.levels[0][..inputs[0]],
+                    _ => &[],
+                };
+                older_l0
+                    .iter()
+                    .chain(self.levels[output_level + 1..].iter().flatten())
+                    .all(|table| !table.overlaps(range))
+            }
+            None => true,
+        };
+        let inputs: Vec<Arc<SSTable>> = inputs
+            .iter()
+            .map(|index| self.levels[level][*index].clone())
+            .collect();
+        let size: u64 = inputs
+            .iter()
+            .chain(overlapping.iter())
+            .map(|table| table.size())
+            .sum();
+        let file_ids = manifest.reserve_file_ids(size / table_size.max(1) + 1)?;
+        Ok(SSTableMerge {
+            level,
+            output_level,
+            table_size,
+            inputs,
+            overlapping,
+            drop_deletes,
+            dir: dir.to_owned(),
+            mode,
+            file_ids,
+            codec,
+            fp_rate,
+            merged: None,
+        })
+    }
+
+    /// Install the output of a merge in place of its tables, then mark their files to be removed.
+    /// The swap is committed to the manifest first, so a crash can't leave it half done.
+    /// A merge that did not run to completion changes nothing.
+    pub(crate) fn install(
+        &mut self,
+        merge: SSTableMerge,
+        manifest: &mut Manifest,
+    ) -> Result<(), StorageEngineError> {
+        let SSTableMerge {
+            level,
+            output_level,
+            inputs,
+            overlapping,
+            merged,
+            ..
+        } = merge;
+        let merged = match merged {
+            Some(merged) => merged,
+            None => return Ok(()),
+        };
+        // New tables may have been flushed into L0 since, so the inputs are found by identity.
+        let at = self.levels[level]
+            .iter()
+            .position(|table| Arc::ptr_eq(table, &inputs[0]))
+            .unwrap_or(0);
+        let removed: Vec<Arc<SSTable>> = inputs.into_iter().chain(overlapping).collect();
+        manifest.append(ManifestEdit {
+            added: merged
+                .iter()
+                .map(|table| TableFile {
+                    id: table.id(),
+                    level: output_level,
+                })
+                .collect(),
+            removed: removed.iter().map(|table| table.id()).collect(),
+            ..Default::default()
+        })?;
+        for tables in [level, output_level] {
+            self.levels[tables].retain(|table| !removed.iter().any(|old| Arc::ptr_eq(table, old)));
+        }
+        let merged = merged.into_iter().map(Arc::new);
+        match output_level {
+            // The merged tables take the place of the inputs, between older and newer tables.
+            0 => {
+                self.levels[0].splice(at..at, merged);
+            }
+            _ => {
+                self.levels[output_level].extend(merged);
+                self.levels[output_level].sort_by_key(|table| table.key_range());
+            }
+        }
+        // Readers such as snapshots may still hold the tables, so their files go once they let go.
+        for table in removed {
+            table.mark_obsolete();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path};
+
+    use uuid::Uuid;
+
+    use super::{Levels, SSTableMerge};
+    use crate::{
+        storage::{Manifest, ManifestEdit, MemTable, SSTable, TableFile},
+        Action, Compaction, CompactionPolicy, CompressionOptions, Event, FilterOptions,
+        LeveledOptions, LeveledPolicy, Recovery, StorageEngineError, StorageMode,
+    };
+
+    /// An empty directory for the test, unique to this process so concurrent test runs don't share it.
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!(
+            "rdeebee-compaction-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn flush(manifest: &mut Manifest, dir: &str, ids: &[Uuid], seq: u64) -> SSTable {
+        let mut memtable = MemTable::new(StorageMode::Latest);
+        for id in ids {
+            memtable.insert(Event::with_id(*id, Action::Write, seq));
+        }
+        let codec = CompressionOptions::default().table_codec;
+        let fp_rate = FilterOptions::default().fp_rate;
+        let mut table = SSTable::from_memtable(
+            dir,
+            manifest.reserve_file_ids(1).unwrap().start,
+            memtable,
+            codec,
+            fp_rate,
+        )
+        .unwrap();
+        table.save_to_disk().unwrap();
+        manifest
+            .append(ManifestEdit {
+                added: vec![TableFile {
+                    id: table.id(),
+                    level: 0,
+                }],
+                ..Default::default()
+            })
+            .unwrap();
+        table
+    }
+
+    fn prepare(
+        levels: &Levels,
+        manifest: &mut Manifest,
+        compaction: Compaction,
+        dir: &str,
+    ) -> Result<SSTableMerge, StorageEngineError> {
+        levels.prepare(
+            compaction,
+            Path::new(dir),
+            StorageMode::Latest,
+            manifest,
+            CompressionOptions::default().table_codec,
+            FilterOptions::default().fp_rate,
+        )
+    }
+
+    fn compact(
+        levels: &mut Levels,
+        manifest: &mut Manifest,
+        compaction: Compaction,
+        dir: &str,
+    ) -> Result<(), StorageEngineError> {
+        let mut merge = prepare(levels, manifest, compaction, dir)?;
+        merge.run()?;
+        levels.install(merge, manifest)
+    }
+
+    fn newest_seq(levels: &Levels, id: Uuid) -> u64 {
+        levels
+            .candidates(id)
+            .into_iter()
+            .rev()
+            .find_map(|table| table.get(id).unwrap())
+            .unwrap()
+            .sequence_num()
+    }
+
+    #[test]
+    fn level0_compaction_test() {
+        let dir = &test_dir("level0");
+        let ids: Vec<Uuid> = (0..10).map(|_| Uuid::new_v4()).collect();
+        let options = LeveledOptions::default();
+        let mut policy = LeveledPolicy::new(options);
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+
+        let mut levels = Levels::new();
+        for seq in 0..options.l0_trigger as u64 - 1 {
+            levels.add_flushed(flush(&mut manifest, dir, &ids, seq));
+            assert!(policy.pick(&levels.stats()).is_none());
+        }
+        levels.add_flushed(flush(&mut manifest, dir, &ids[..5], 10));
+
+        let compaction = policy.pick(&levels.stats()).unwrap();
+        assert_eq!(compaction.level, 0);
+        compact(&mut levels, &mut manifest, compaction, dir).unwrap();
+        assert!(levels.levels[0].is_empty());
+        assert_eq!(levels.levels[1].len(), 1);
+
+        // The newest event of every ID survives.
+        for (index, id) in ids.iter().enumerate() {
+            assert_eq!(levels.candidates(*id).len(), 1);
+            assert_eq!(newest_seq(&levels, *id), if index < 5 { 10 } else { 2 });
+        }
+        // The merged table, and the manifest.
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn level0_merge_in_place_test() {
+        let dir = &test_dir("in-place");
+        let ids: Vec<Uuid> = (0..10).map(|_| Uuid::new_v4()).collect();
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+
+        let mut levels = Levels::new();
+        for seq in 0..3 {
+            levels.add_flushed(flush(&mut manifest, dir, &ids, seq));
+        }
+        // Out of L0, the oldest tables have to go first.
+        let compaction = Compaction {
+            level: 0,
+            inputs: vec![1, 2],
+            output_level: 1,
+            table_size: u64::MAX,
+        };
+        assert!(matches!(
+            compact(&mut levels, &mut manifest, compaction, dir),
+            Err(StorageEngineError::InvalidCompaction(0))
+        ));
+
+        // Merge the two oldest tables, behind the newer ones,
+        // while another table is flushed.
+        let compaction = Compaction {
+            level: 0,
+            inputs: vec![0, 1],
+            output_level: 0,
+            table_size: u64::MAX,
+        };
+        let mut merge = prepare(&levels, &mut manifest, compaction, dir).unwrap();
+        levels.add_flushed(flush(&mut manifest, dir, &ids[..5], 3));
+        merge.run().unwrap();
+        levels.install(merge, &mut manifest).unwrap();
+        assert_eq!(levels.levels[0].len(), 3);
+        for (index, id) in ids.iter().enumerate() {
+            assert_eq!(newest_seq(&levels, *id), if index < 5 { 3 } else { 2 });
+        }
+
+        // Recovery keeps the merged table behind the newer ones.
+        let version = Manifest::open(Path::new(dir)).unwrap().version().clone();
+        let levels = Levels::from_tables(
+            Recovery {}
+                .recover_sstable(dir, &version, &CompressionOptions::default())
+                .unwrap(),
+        );
+        assert_eq!(levels.levels[0].len(), 3);
+        for (index, id) in ids.iter().enumerate() {
+            assert_eq!(newest_seq(&levels, *id), if index < 5 { 3 } else { 2 });
+        }
+    }
+}
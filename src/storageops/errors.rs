@@ -16,6 +16,36 @@ pub enum StorageEngineError {
     FailedSSTableCreation(u128),
     #[error("Failed to get MemTable")]
     InvalidMemTable,
+    #[error("Invalid file header: {0}")]
+    InvalidFileHeader(PathBuf),
+    #[error("Unsupported format version {1} in: {0}")]
+    UnsupportedFormatVersion(PathBuf, u16),
+    #[error("Checksum mismatch for record at offset {1} in: {0}")]
+    ChecksumMismatch(PathBuf, u64),
+    #[error("Truncated record at offset {1} in: {0}")]
+    TruncatedRecord(PathBuf, u64),
+    #[error("Invalid record length at offset {1} in: {0}")]
+    InvalidRecordLength(PathBuf, u64),
+    #[error("Unknown codec {1} in: {0}")]
+    UnknownCodec(PathBuf, u8),
+    #[error("Invalid table footer: {0}")]
+    InvalidTableFooter(PathBuf),
+    #[error("Invalid bloom filter")]
+    InvalidBloomFilter,
+    #[error("Invalid snapshot of {0} at version {1}, its stream is at version {2}")]
+    InvalidAggregateVersion(String, u64, u64),
+    #[error("Failed to upcast {0} from version {1}: {2}")]
+    UpcastFailed(String, u32, String),
+    #[error("Invalid compaction out of level {0}")]
+    InvalidCompaction(usize),
+    #[error("Change data capture needs StorageMode::History, StorageMode::Latest drops the events it replaces")]
+    ChangesNeedHistory,
+    #[error("Ran out of reserved file IDs")]
+    OutOfFileIds,
+    #[error("Wal refuses appends after failing to cut off a failed one: {0}")]
+    PoisonedWal(PathBuf),
+    #[error("File not created by the database: {0}")]
+    UnknownFile(PathBuf),
     #[error(transparent)]
     TimeError(#[from] SystemTimeError),
     #[error(transparent)]
@@ -27,3 +57,15 @@ pub enum StorageEngineError {
     #[error(transparent)]
     SerializationError(#[from] bincode::Error),
 }
+
+impl StorageEngineError {
+    /// Get the offset of the record a framing or checksum error was found at.
+    pub(crate) fn bad_record_offset(&self) -> Option<u64> {
+        match self {
+            StorageEngineError::TruncatedRecord(_, offset)
+            | StorageEngineError::ChecksumMismatch(_, offset)
+            | StorageEngineError::InvalidRecordLength(_, offset) => Some(*offset),
+            _ => None,
+        }
+    }
+}
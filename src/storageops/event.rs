@@ -1,12 +1,15 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Debug, Display},
     mem,
-    time::SystemTime,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::wire_format::operation;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) enum Action {
     Read,
@@ -16,12 +19,85 @@ pub(crate) enum Action {
 
 type Payload = Option<Vec<u8>>;
 
+/// What an event is and where it came from, to follow event flows across keys.
+/// Empty strings stand for unset IDs, as in the wire format.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct EventMetadata {
+    pub(crate) event_type: String,
+    /// The version of the schema of the event type the payload follows, 0 if the type has none.
+    pub(crate) schema_version: u32,
+    /// Wall-clock time the event was appended at, in milliseconds since the Unix epoch,
+    /// unless the writer set one.
+    pub(crate) timestamp: u64,
+    /// The ID shared by every event of a flow.
+    pub(crate) correlation_id: String,
+    /// The ID of the event or command that caused this one.
+    pub(crate) causation_id: String,
+    pub(crate) headers: BTreeMap<String, String>,
+}
+
+impl EventMetadata {
+    /// Take the metadata of the request, stamping it with the current time if it has none.
+    pub(crate) fn from_request(request: &operation::Request) -> Self {
+        let metadata = request.metadata.get_or_default();
+        let timestamp = match metadata.timestamp {
+            0 => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            timestamp => timestamp,
+        };
+        Self {
+            event_type: metadata.event_type.clone(),
+            schema_version: metadata.schema_version,
+            timestamp,
+            correlation_id: metadata.correlation_id.clone(),
+            causation_id: metadata.causation_id.clone(),
+            headers: metadata.headers.clone().into_iter().collect(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        self.event_type.len()
+            + mem::size_of::<u32>()
+            + mem::size_of::<u64>()
+            + self.correlation_id.len()
+            + self.causation_id.len()
+            + headers
+    }
+}
+
+impl From<&EventMetadata> for operation::Metadata {
+    fn from(metadata: &EventMetadata) -> Self {
+        let mut message = operation::Metadata::new();
+        message.event_type = metadata.event_type.clone();
+        message.schema_version = metadata.schema_version;
+        message.timestamp = metadata.timestamp;
+        message.correlation_id = metadata.correlation_id.clone();
+        message.causation_id = metadata.causation_id.clone();
+        message.headers = metadata.headers.clone().into_iter().collect();
+        message
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct Event {
     sequence_num: u64,
     transaction_id: Uuid,
+    /// The key of the stream, so the key directory can be rebuilt from disk.
+    key: String,
+    /// The position of the event in the stream of its key, counting from 1.
+    version: u64,
+    /// The position of the event in the order the database appended its events in, counting from 1.
+    /// Unlike the sequence number, it always grows with the writes.
+    position: u64,
     action: Action,
     payload: Payload,
+    metadata: EventMetadata,
 }
 
 impl Event {
@@ -29,8 +105,12 @@ impl Event {
         Self {
             sequence_num: seq,
             transaction_id: Uuid::new_v4(),
+            key: String::new(),
+            version: 0,
+            position: 0,
             action,
             payload: None,
+            metadata: EventMetadata::default(),
         }
     }
 
@@ -38,8 +118,12 @@ impl Event {
         Self {
             sequence_num: seq,
             transaction_id: id,
+            key: String::new(),
+            version: 0,
+            position: 0,
             action,
             payload: None,
+            metadata: EventMetadata::default(),
         }
     }
 
@@ -47,8 +131,32 @@ impl Event {
         self.transaction_id
     }
 
-    pub(crate) fn set_id(&mut self, id: Uuid) {
-        self.transaction_id = id;
+    pub(crate) fn sequence_num(&self) -> u64 {
+        self.sequence_num
+    }
+
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub(crate) fn set_key(&mut self, key: String) {
+        self.key = key;
+    }
+
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub(crate) fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub(crate) fn set_position(&mut self, position: u64) {
+        self.position = position;
     }
 
     pub(crate) fn set_payload(&mut self, payload: Payload) {
@@ -63,12 +171,21 @@ impl Event {
         self.payload.clone()
     }
 
+    pub(crate) fn metadata(&self) -> &EventMetadata {
+        &self.metadata
+    }
+
+    pub(crate) fn set_metadata(&mut self, metadata: EventMetadata) {
+        self.metadata = metadata;
+    }
+
     pub(crate) fn size(&self) -> usize {
-        let systime_alignment = mem::align_of::<SystemTime>();
+        let num_alignment = mem::align_of::<u64>();
         let id_alignment = mem::align_of::<Uuid>();
         let action_alignment = mem::align_of::<Action>();
         let payload_alignment = mem::align_of::<Vec<u8>>();
-        let mut systime_sz = mem::size_of::<SystemTime>();
+        // The sequence number, the version and the position.
+        let mut num_sz = 3 * mem::size_of::<u64>();
         let mut id_sz = mem::size_of::<Uuid>();
         let mut action_sz = mem::size_of::<Action>();
         let mut payload_sz = match &self.payload {
@@ -76,9 +193,9 @@ impl Event {
             None => payload_alignment,
         };
 
-        systime_sz = match systime_sz % systime_alignment {
-            0 => systime_sz,
-            n => systime_sz + (systime_alignment - n),
+        num_sz = match num_sz % num_alignment {
+            0 => num_sz,
+            n => num_sz + (num_alignment - n),
         };
 
         id_sz = match id_sz % id_alignment {
@@ -96,7 +213,7 @@ impl Event {
             n => payload_sz + (payload_alignment - n),
         };
 
-        systime_sz + id_sz + action_sz + payload_sz
+        num_sz + id_sz + self.key.len() + action_sz + payload_sz + self.metadata.size()
     }
 }
 
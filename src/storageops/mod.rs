@@ -1,54 +1,176 @@
+mod aggregate;
+mod all_stream;
+mod batch;
+mod codec;
+mod compaction;
 mod errors;
 mod recovery;
 mod event;
+mod options;
+mod policy;
+mod schema;
+mod snapshot;
+mod stream;
+mod subscription;
 
-use std::{collections::HashMap, fs, path::PathBuf, mem, str::FromStr};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, mem, ops::RangeBounds, slice, str::FromStr, sync::Arc};
 
+pub use aggregate::*;
+pub(crate) use all_stream::*;
+pub use batch::*;
+pub use codec::*;
+pub use compaction::*;
 pub(crate) use event::*;
-use protobuf::EnumOrUnknown;
+use im::OrdMap;
+use parking_lot::Mutex;
+use protobuf::{EnumOrUnknown, MessageField};
 pub(crate) use recovery::*;
 pub use errors::*;
+pub use options::*;
+pub use policy::*;
+pub use schema::*;
+pub use snapshot::*;
+pub use stream::*;
+pub use subscription::*;
 use tracing::error;
 use uuid::Uuid;
 
-use crate::{storage::{Wal, MemTable, SSTable, BloomFilter}, wire_format::operation::{Request, Response, Operation, Status}};
+use crate::{storage::{AggregateStore, Wal, Manifest, ManifestEdit, MemTable, TableFile, BloomFilter, Order, OrderEntry}, wire_format::operation::{Request, Response, Operation, Status}};
 
 pub struct RDeeBee {
-    compaction_size: usize,
+    policy: Box<dyn CompactionPolicy>,
+    mode: StorageMode,
     deebee_dir: String,
+    durability: Durability,
     wal: Wal,
-    memtable: MemTable,
-    sstables: Vec<SSTable>,
+    /// Log sequence numbers, counting the records appended to the Wal since the database was opened.
+    /// A batch takes a single record.
+    /// Every event up to `durable_lsn` is durable under the durability policy.
+    appended_lsn: u64,
+    durable_lsn: u64,
+    /// The commit position of the last event appended to the Wal, and of the last one durable.
+    /// Change data capture only reads up to `committed_position`.
+    appended_position: u64,
+    committed_position: u64,
+    /// Wals replayed into the MemTable by recovery.
+    /// They are retired along with `wal` once the MemTable is flushed.
+    recovered_wals: Vec<Wal>,
+    /// Shared with snapshots, a write copies it first if a snapshot still holds it.
+    memtable: Arc<MemTable>,
+    /// The MemTable being flushed in the background, and the Wals protecting it.
+    /// It stays readable until its SSTable is installed.
+    immutable: Option<Arc<MemTable>>,
+    immutable_wals: Vec<Wal>,
+    flushing: bool,
+    levels: Levels,
+    compacting: bool,
+    /// Locked on its own, so a flush commits its file IDs and new Wal without holding the database.
+    manifest: Arc<Mutex<Manifest>>,
+    filter: FilterOptions,
+    compression: CompressionOptions,
+    /// A counting filter over the IDs of the live keys, the IDs in the key directory.
+    /// Reads by key resolve the ID through the key directory, so only the table filters are checked for them.
+    /// It stays for `contains_event`, which is given an ID and rules it out without reading any MemTable or SSTable.
     bloomfilter: BloomFilter,
-    key_to_id_map: HashMap<String, Uuid>,
+    /// Ordered by key for scans, and persistent like the MemTable, so snapshots share it.
+    key_to_id_map: OrdMap<String, Uuid>,
+    /// The version of the stream of every live ID, the version of its latest event.
+    stream_versions: HashMap<Uuid, u64>,
+    /// The highest sequence number written.
+    last_seq: u64,
+    /// Kept apart from the events, so flushes and merges never touch them.
+    aggregates: AggregateStore,
+    /// Shared with snapshots and streams, like the MemTable.
+    schemas: Arc<SchemaRegistry>,
+    all_stream: AllStreamIndex,
     recovery: Recovery,
 }
 
 impl RDeeBee {
-    pub fn new(compaction_size: usize, dir: String) -> Result<Self, StorageEngineError> {
+    pub fn new(
+        policy: Box<dyn CompactionPolicy>,
+        dir: String,
+        mode: StorageMode,
+        durability: Durability,
+        filter: FilterOptions,
+        compression: CompressionOptions,
+    ) -> Result<Self, StorageEngineError> {
         fs::create_dir_all(dir.clone())?; // create any of the paths if they don't exist
-        let wal = match Wal::new(&dir) {
+        let manifest = Manifest::open(Path::new(&dir))?;
+        let recovery = Recovery {};
+        // Clear out what a crash left behind before handing out file IDs again.
+        recovery.remove_obsolete_files(&dir, manifest.version())?;
+        let manifest = Arc::new(Mutex::new(manifest));
+        let wal_id = manifest.lock().reserve_file_ids(1)?.start;
+        let wal = Self::new_wal(&dir, wal_id, durability, &compression, &manifest)?;
+        let aggregates = AggregateStore::open(Path::new(&dir), &compression)?;
+        Ok(Self {
+            policy,
+            mode,
+            deebee_dir: dir,
+            durability,
+            wal,
+            appended_lsn: 0,
+            durable_lsn: 0,
+            appended_position: 0,
+            committed_position: 0,
+            recovered_wals: Vec::new(),
+            memtable: Arc::new(MemTable::new(mode)),
+            immutable: None,
+            immutable_wals: Vec::new(),
+            flushing: false,
+            levels: Levels::new(),
+            compacting: false,
+            manifest,
+            filter,
+            compression,
+            bloomfilter: BloomFilter::counting(filter),
+            key_to_id_map: OrdMap::new(),
+            stream_versions: HashMap::new(),
+            last_seq: 0,
+            aggregates,
+            schemas: Arc::new(SchemaRegistry::new()),
+            all_stream: AllStreamIndex::new(mode, ALL_STREAM_CAPACITY),
+            recovery,
+        })
+    }
+
+    /// Start a new Wal with the reserved ID and record it in the manifest.
+    fn new_wal(
+        dir: &str,
+        id: u64,
+        durability: Durability,
+        compression: &CompressionOptions,
+        manifest: &Mutex<Manifest>,
+    ) -> Result<Wal, StorageEngineError> {
+        let wal = match Wal::new(dir, id, durability, compression) {
             Ok(wal) => wal,
             Err(e) => {
-                error!("failed to create the wal: {}", e);
+                error!("failed to create new wal: {}", e);
                 return Err(e);
             }
         };
-        Ok(Self {
-            compaction_size,
-            deebee_dir: dir,
-            wal,
-            memtable: MemTable::new(),
-            sstables: Vec::new(),
-            bloomfilter: BloomFilter::new(),
-            key_to_id_map: HashMap::new(),
-            recovery: Recovery {},
-        })
+        manifest.lock().append(ManifestEdit {
+            new_wals: vec![wal.id()],
+            ..Default::default()
+        })?;
+        Ok(wal)
     }
 
-    /// Get the compaction size.
-    pub fn get_compaction_size(&self) -> usize {
-        self.compaction_size
+    /// Has the MemTable grown large enough to be flushed, according to the compaction policy?
+    pub fn memtable_compaction_due(&self) -> bool {
+        self.policy.flush_due(self.memtable.size())
+    }
+
+    /// Get the schema registry, to register the schemas and upcasters of the event types.
+    /// Snapshots and streams already open keep the registry as it was.
+    pub fn schema_registry_mut(&mut self) -> &mut SchemaRegistry {
+        Arc::make_mut(&mut self.schemas)
+    }
+
+    /// Get the storage mode.
+    pub fn get_storage_mode(&self) -> StorageMode {
+        self.mode
     }
 
     /// Get MemTable size
@@ -61,41 +183,253 @@ impl RDeeBee {
         self.wal.path()
     }
 
-    /// Create a new MemTable.
-    /// Save the old MemTable into an SSTable.
-    pub fn try_memtable_compact(&mut self) -> Result<(), StorageEngineError> {
-        let memtable = mem::replace(&mut self.memtable, MemTable::new());
-        let mut sstable = SSTable::from_memtable(&self.deebee_dir, memtable)?;
-        match sstable.save_to_disk() {
-            Ok(_) => {}
-            Err(e) => return Err(e),
-        }
-        self.sstables.push(sstable);
-        // Once this is successful, we create a new wal as well.
-        let wal = match Wal::new(&self.deebee_dir) {
-            Ok(wal) => wal,
-            Err(e) => {
-                error!("failed to create new wal: {}", e);
-                return Err(e);
+    /// Get the log sequence number of the last record appended to the Wal.
+    /// Read it right after a write to learn which number acknowledges that write.
+    pub fn appended_lsn(&self) -> u64 {
+        self.appended_lsn
+    }
+
+    /// Get the log sequence number up to which every event is durable.
+    /// A write may be acknowledged once this reaches its log sequence number.
+    pub fn durable_lsn(&self) -> u64 {
+        self.durable_lsn
+    }
+
+    /// Is a group commit waiting on its interval to pass?
+    /// Call `sync_wal` when it is.
+    pub fn sync_due(&self) -> bool {
+        self.wal.sync_due() || self.immutable_wals.iter().any(Wal::sync_due)
+    }
+
+    /// fsync the Wal, making every appended event durable.
+    /// The Wals handed to a flush with events still waiting on a group commit are synced along with it.
+    pub fn sync_wal(&mut self) -> Result<(), StorageEngineError> {
+        for wal in self.immutable_wals.iter_mut() {
+            if wal.commit_pending() {
+                wal.sync()?;
             }
+        }
+        self.wal.sync()?;
+        self.commit();
+        Ok(())
+    }
+
+    /// Get the commit position of the last event durable, and so visible to change data capture.
+    /// Subscribers wait for it to pass the last commit position they read.
+    pub fn committed_position(&self) -> u64 {
+        self.committed_position
+    }
+
+    /// Mark every appended event durable, unless a Wal has events waiting on a group commit.
+    fn commit(&mut self) {
+        if self.wal.commit_pending() || self.immutable_wals.iter().any(Wal::commit_pending) {
+            return;
+        }
+        self.durable_lsn = self.appended_lsn;
+        self.committed_position = self.appended_position;
+    }
+
+    /// Give the events the next commit positions, then append them to the Wal as a single record
+    /// and track when it becomes durable.
+    /// Events that fail to append are left out of the Wal, and their positions go to the next events.
+    fn append_to_wal(&mut self, events: &mut [Event]) -> Result<(), StorageEngineError> {
+        for (position, event) in (self.appended_position + 1..).zip(events.iter_mut()) {
+            event.set_position(position);
+        }
+        let durable = self.wal.add_events(events)?;
+        self.appended_position += events.len() as u64;
+        self.appended_lsn += 1;
+        if durable {
+            self.commit();
+        }
+        Ok(())
+    }
+
+    /// Flush the MemTable into an SSTable in the foreground.
+    pub fn try_memtable_compact(&mut self) -> Result<(), StorageEngineError> {
+        let files = match self.prepare_memtable_compact() {
+            Some(preparation) => preparation.run()?,
+            None => return Ok(()),
         };
-        let _ = mem::replace(&mut self.wal, wal);
+        if let Some(mut flush) = self.begin_memtable_compact(files)? {
+            let result = flush.run();
+            self.finish_memtable_compact(flush)?;
+            result?;
+        }
         Ok(())
     }
 
-    /// Remove the two oldest SSTables.
-    /// Merge them.
-    /// Insert into the front of the vector.
+    /// Get a flush ready to create its files, to run without holding the database.
+    /// Returns None if a flush is already running.
+    pub fn prepare_memtable_compact(&self) -> Option<FlushPreparation> {
+        if self.flushing {
+            return None;
+        }
+        Some(FlushPreparation::new(
+            self.deebee_dir.clone(),
+            self.durability,
+            self.compression.clone(),
+            self.manifest.clone(),
+            self.immutable.is_none(),
+        ))
+    }
+
+    /// Hand the MemTable over to a flush, to run without holding the database.
+    /// The MemTable becomes immutable, and new writes go to a new MemTable and the Wal of the files.
+    /// The files are already created, so only the MemTables and Wals are swapped.
+    /// The old Wal keeps the events waiting on a group commit, and the next `sync_wal` syncs it.
+    /// If an earlier flush failed, its MemTable is flushed again instead.
+    /// Returns None if a flush is already running, or if a flush finished since the files were prepared.
+    pub fn begin_memtable_compact(
+        &mut self,
+        files: FlushFiles,
+    ) -> Result<Option<MemTableFlush>, StorageEngineError> {
+        if self.flushing {
+            return Ok(None);
+        }
+        // An unused Wal stays empty, and a later flush retires it along with the rest.
+        if self.immutable.is_none() {
+            let wal = match files.wal {
+                Some(wal) => wal,
+                None => return Ok(None),
+            };
+            let old_wal = mem::replace(&mut self.wal, wal);
+            self.immutable_wals.append(&mut self.recovered_wals);
+            self.immutable_wals.push(old_wal);
+            let memtable = mem::replace(&mut self.memtable, Arc::new(MemTable::new(self.mode)));
+            self.immutable = Some(memtable);
+        }
+        let memtable = match &self.immutable {
+            Some(memtable) => memtable.clone(),
+            None => return Err(StorageEngineError::InvalidMemTable),
+        };
+        self.flushing = true;
+        Ok(Some(MemTableFlush::new(
+            self.deebee_dir.clone(),
+            files.table_id,
+            memtable,
+            self.compression.table_codec.clone(),
+            self.filter.fp_rate,
+        )))
+    }
+
+    /// Install the SSTable of a flush and retire the Wals protecting its MemTable.
+    /// The manifest records the table along with the Wals it makes obsolete, every Wal older than the current one.
+    /// A flush that failed leaves the MemTable in place, to be flushed again.
+    pub fn finish_memtable_compact(
+        &mut self,
+        flush: MemTableFlush,
+    ) -> Result<(), StorageEngineError> {
+        self.flushing = false;
+        let sstable = match flush.into_table()? {
+            Some(sstable) => sstable,
+            None => return Ok(()),
+        };
+        self.manifest.lock().append(ManifestEdit {
+            added: vec![TableFile {
+                id: sstable.id(),
+                level: 0,
+            }],
+            log_number: Some(self.wal.id()),
+            last_position: self.appended_position,
+            ..Default::default()
+        })?;
+        self.levels.add_flushed(sstable);
+        self.immutable = None;
+        // If we crash before these are gone, recovery removes them, as the manifest no longer lists them.
+        for wal in self.immutable_wals.drain(..) {
+            wal.retire()?;
+        }
+        // Their events are durable in the table, so they no longer wait on a group commit.
+        self.commit();
+        Ok(())
+    }
+
+    /// Run the compaction picked by the compaction policy in the foreground, if it picks one.
     pub fn try_sstables_compact(&mut self) -> Result<(), StorageEngineError> {
-        if self.sstables.len() < 2 {
-            return Ok(());
+        if let Some(mut merge) = self.begin_sstables_compact()? {
+            let result = merge.run();
+            self.finish_sstables_compact(merge)?;
+            result?;
         }
-        let s1 = self.sstables.remove(0);
-        let s2 = self.sstables.remove(1);
-        self.sstables.insert(0, s1.merge(s2)?);
         Ok(())
     }
 
+    /// Ask the compaction policy for a compaction, to run without holding the database.
+    /// Returns None if there is nothing to compact, or a compaction is already running.
+    pub fn begin_sstables_compact(&mut self) -> Result<Option<SSTableMerge>, StorageEngineError> {
+        if self.compacting {
+            return Ok(None);
+        }
+        let compaction = match self.policy.pick(&self.levels.stats()) {
+            Some(compaction) => compaction,
+            None => return Ok(None),
+        };
+        let merge = self.levels.prepare(
+            compaction,
+            Path::new(&self.deebee_dir),
+            self.mode,
+            &mut self.manifest.lock(),
+            self.compression.table_codec.clone(),
+            self.filter.fp_rate,
+        )?;
+        self.compacting = true;
+        Ok(Some(merge))
+    }
+
+    /// Atomically swap the merged tables in for the tables they replace.
+    pub fn finish_sstables_compact(
+        &mut self,
+        merge: SSTableMerge,
+    ) -> Result<(), StorageEngineError> {
+        self.compacting = false;
+        self.levels.install(merge, &mut self.manifest.lock())
+    }
+
+    /// Insert the event into the MemTable, once it is in the Wal.
+    fn insert(&mut self, event: Event) {
+        self.last_seq = self.last_seq.max(event.sequence_num());
+        if self.mode == StorageMode::Latest && event.action() == &Action::Delete {
+            self.stream_versions.remove(&event.id());
+        } else {
+            self.stream_versions.insert(event.id(), event.version());
+        }
+        self.all_stream.insert(&event);
+        Arc::make_mut(&mut self.memtable).insert(event);
+    }
+
+    /// The MemTables and SSTables to read from.
+    fn view(&self) -> ReadView<'_> {
+        let mut memtables: Vec<&MemTable> = self
+            .immutable
+            .iter()
+            .map(|memtable| memtable.as_ref())
+            .collect();
+        memtables.push(&self.memtable);
+        ReadView {
+            memtables,
+            levels: &self.levels,
+            schemas: &self.schemas,
+        }
+    }
+
+    /// Take a consistent snapshot of the database, pinned at the last sequence number written.
+    /// Reads through it are unaffected by later writes, flushes and compactions.
+    /// Taking one is cheap, as the MemTable and key directory are persistent maps.
+    /// While it is held, a write copies the path to its entry in each, O(log n),
+    /// along with the MemTable events of its ID, and the SSTables it pins stay on disk until it is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut memtables: Vec<Arc<MemTable>> = self.immutable.iter().cloned().collect();
+        memtables.push(self.memtable.clone());
+        Snapshot::new(
+            self.last_seq,
+            self.key_to_id_map.clone(),
+            memtables,
+            self.levels.clone(),
+            self.schemas.clone(),
+        )
+    }
+
     fn extract_id(&self, id: &str) -> Result<Uuid, bool> {
         let uuid = match Uuid::from_str(id) {
             Ok(id) => id,
@@ -111,6 +445,49 @@ impl RDeeBee {
         self.key_to_id_map.get(key).map(|id| id.to_owned())
     }
 
+    /// Get the version of the stream of the key, the number of events appended to it.
+    /// A key without events is at version 0, as is a deleted key in `StorageMode::Latest`.
+    pub fn stream_version(&self, key: &str) -> u64 {
+        self.get_key_id(key)
+            .and_then(|id| self.stream_versions.get(&id).copied())
+            .unwrap_or(0)
+    }
+
+    /// Check the payload of a write against the schema registered for its event type, if any.
+    /// On a violation, returns the response rejecting the write.
+    fn check_payload(
+        &self,
+        request: &Request,
+        metadata: &mut EventMetadata,
+    ) -> Result<(), Response> {
+        self.schemas
+            .validate(metadata, &request.payload)
+            .map_err(|e| {
+                error!("invalid payload for {}: {}", request.key, e);
+                let mut response = Response::new();
+                response.key = request.key.clone();
+                response.op = request.op;
+                response.status = EnumOrUnknown::new(Status::Invalid_Payload);
+                response
+            })
+    }
+
+    /// Check the version the request expects the stream of its key to be at, if any.
+    /// On a mismatch, returns the response carrying the current version.
+    fn check_version(request: &Request, version: u64) -> Result<(), Response> {
+        match request.expected_version {
+            Some(expected) if expected != version => {
+                let mut response = Response::new();
+                response.key = request.key.clone();
+                response.op = request.op;
+                response.status = EnumOrUnknown::new(Status::Version_Mismatch);
+                response.version = version;
+                Err(response)
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn add_event(&mut self, req: Request) -> Response {
         let mut response = Response::new();
         let action = match req.op.enum_value() {
@@ -118,6 +495,31 @@ impl RDeeBee {
                 Operation::Read => Action::Read,
                 Operation::Write => Action::Write,
                 Operation::Delete => Action::Delete,
+                Operation::Stream => {
+                    error!("Invalid Op: streams are read with stream_by_key");
+                    response.status = EnumOrUnknown::new(Status::Invalid_Op);
+                    return response;
+                }
+                Operation::Scan => {
+                    error!("Invalid Op: scans are read with scan");
+                    response.status = EnumOrUnknown::new(Status::Invalid_Op);
+                    return response;
+                }
+                Operation::Batch => {
+                    error!("Invalid Op: batches are written with write_batch");
+                    response.status = EnumOrUnknown::new(Status::Invalid_Op);
+                    return response;
+                }
+                Operation::ReadAll => {
+                    error!("Invalid Op: the events of every key are read with read_all");
+                    response.status = EnumOrUnknown::new(Status::Invalid_Op);
+                    return response;
+                }
+                Operation::Subscribe => {
+                    error!("Invalid Op: changes are read with read_changes");
+                    response.status = EnumOrUnknown::new(Status::Invalid_Op);
+                    return response;
+                }
             },
             Err(e) => {
                 error!("Invalid Op: {}", e);
@@ -125,23 +527,32 @@ impl RDeeBee {
                 return response;
             }
         };
+        let version = self.stream_version(&req.key);
+        if let Err(response) = Self::check_version(&req, version) {
+            return response;
+        }
+        let mut metadata = EventMetadata::from_request(&req);
+        if action == Action::Write {
+            if let Err(response) = self.check_payload(&req, &mut metadata) {
+                return response;
+            }
+        }
         let seq = req.seq;
         let mut event = match self.get_key_id(&req.key) {
-            Some(id) => {
-                self.key_to_id_map.insert(req.key.clone(), id);
-                Event::with_id(id, action, seq)
-            }
+            Some(id) => Event::with_id(id, action, seq),
             None => {
                 let event = Event::new(action, seq);
-                self.key_to_id_map.insert(req.key.clone(), event.id());
-                self.bloomfilter.add(event.id());
+                self.add_key(req.key.clone(), event.id());
                 event
             }
         };
+        event.set_key(req.key.clone());
+        event.set_version(version + 1);
+        event.set_metadata(metadata);
         if !req.payload.is_empty() {
             event.set_payload(Some(req.payload));
         }
-        match self.wal.add_event(event.clone()) {
+        match self.append_to_wal(slice::from_mut(&mut event)) {
             Ok(_) => response.status = EnumOrUnknown::new(Status::Ok),
             Err(e) => {
                 error!("failed to add event: {}", e);
@@ -149,27 +560,36 @@ impl RDeeBee {
                 return response;
             }
         }
-        self.memtable.insert(event);
+        self.insert(event);
         response.key = req.key;
         response.op = req.op;
+        response.version = version + 1;
         response
     }
 
-    /// Check if an event is in the database.
+    /// Check if an event of the ID of a live key is in the database.
+    /// The IDs of deleted keys are ruled out by the bloom filter.
     pub fn contains_event(&self, id: &str) -> bool {
         let uuid = match self.extract_id(id) {
             Ok(value) => value,
             Err(value) => return value,
         };
-        if self.bloomfilter.find(uuid) {
+        if !self.bloomfilter.find(uuid) {
             return false;
         }
-        if self.memtable.contains(uuid) {
+        if self.memtable.contains(uuid)
+            || self
+                .immutable
+                .as_ref()
+                .is_some_and(|memtable| memtable.contains(uuid))
+        {
             return true;
         }
-        for table in &self.sstables {
-            if table.contains(uuid) {
-                return true;
+        for table in self.levels.candidates(uuid) {
+            match table.contains(uuid) {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => error!("failed to read table: {}", e),
             }
         }
         false
@@ -187,33 +607,22 @@ impl RDeeBee {
             }
         };
 
-        // check if Bloom Filter says event exists
-        if !self.bloomfilter.find(uuid) {
-            response.status = EnumOrUnknown::new(Status::Invalid_Key);
-            return response;
-        }
-
-        let mut ret_event = None;
-
-        // check if event is in memtable
-        for event in &self.memtable {
-            ret_event = Some(event);
-        }
-
-        // check if event is not in memtable then if it is in one of the SSTables.
-        if ret_event.is_none() {
-            for table in self.sstables.iter().rev() {
-                let event = table.get(uuid);
-                if let Some(event) = event {
-                    ret_event = Some(event);
-                    break;
-                }
+        // check if event is in the memtables, then if it is in one of the SSTables.
+        let ret_event = match self.view().get(uuid) {
+            Ok(event) => event,
+            Err(e) => {
+                error!("failed to read table: {}", e);
+                response.status = EnumOrUnknown::new(Status::Server_Error);
+                return response;
             }
-        }
+        };
 
         response.key = key.to_string();
         if let Some(event) = ret_event {
             response.status = EnumOrUnknown::new(Status::Ok);
+            response.seq = event.sequence_num();
+            response.version = event.version();
+            response.metadata = MessageField::some(event.metadata().into());
             response.op = match event.action() {
                 Action::Read => EnumOrUnknown::new(Operation::Read),
                 Action::Write => EnumOrUnknown::new(Operation::Write),
@@ -228,35 +637,273 @@ impl RDeeBee {
         response
     }
 
-    /// Get the entire stream of events if they exist
-    /// TODO: Figure out how to do this live from a threadpool or async context, without storing and returning a vector.
+    /// Get the latest event of the key with a sequence number up to `seq`,
+    /// so the key reads as it was at that point in the global sequence.
+    /// Only `StorageMode::History` keeps every event.
+    /// In `StorageMode::Latest` an event overwritten since is gone, and the key reads as missing.
+    /// It takes a lookup in every MemTable and table that may hold the key, not a read of its stream.
+    pub fn get_event_by_key_at(&self, key: &str, seq: u64) -> Response {
+        let mut response = Response::new();
+        response.key = key.to_string();
+        let uuid = match self.get_key_id(key) {
+            Some(uuid) => uuid,
+            None => {
+                response.status = EnumOrUnknown::new(Status::Invalid_Key);
+                return response;
+            }
+        };
+        match self.view().get_up_to(uuid, seq) {
+            Ok(Some(event)) => event_response(key, &event),
+            Ok(None) => {
+                response.status = EnumOrUnknown::new(Status::Invalid_Key);
+                response
+            }
+            Err(e) => {
+                error!("failed to read table: {}", e);
+                response.status = EnumOrUnknown::new(Status::Server_Error);
+                response
+            }
+        }
+    }
+
+    /// Get the entire stream of events if they exist, in ascending order of sequence numbers.
+    /// In `StorageMode::Latest` this is at most one event per SSTable and one from the MemTable.
+    /// Use `stream_by_key` to read large streams without holding them in memory.
     pub fn get_stream_by_key(&self, key: &str) -> Option<Vec<Response>> {
-        let mut responses = Vec::new();
+        let stream = match self.stream_by_key(key, ..) {
+            Ok(stream) => stream?,
+            Err(e) => {
+                error!("failed to open stream for {}: {}", key, e);
+                return None;
+            }
+        };
+        match stream.collect() {
+            Ok(responses) => Some(responses),
+            Err(e) => {
+                error!("failed to read stream for {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    /// Lazily stream the events of a key with sequence numbers within the range.
+    /// The SSTables are opened up front, so the stream does not borrow the database
+    /// and survives later flushes and merges.
+    /// Returns None if the key doesn't exist.
+    pub fn stream_by_key<R: RangeBounds<u64>>(
+        &self,
+        key: &str,
+        range: R,
+    ) -> Result<Option<KeyStream>, StorageEngineError> {
         let uuid = match self.get_key_id(key) {
             Some(uuid) => uuid,
-            None => return None,
+            None => return Ok(None),
         };
-        if self.bloomfilter.find(uuid) {
-            return None;
+        Ok(Some(self.view().stream(key, uuid, range)?))
+    }
+
+    /// Lazily stream the events of a key after a version of its stream,
+    /// such as the events its aggregate snapshot doesn't cover yet.
+    /// Only `StorageMode::History` keeps every event to replay.
+    /// Returns None if the key doesn't exist.
+    pub fn stream_by_key_after_version(
+        &self,
+        key: &str,
+        version: u64,
+    ) -> Result<Option<KeyStream>, StorageEngineError> {
+        Ok(self
+            .stream_by_key(key, ..)?
+            .map(|stream| stream.after_version(version)))
+    }
+
+    /// Read the events of every key with sequence numbers from `from_seq` on, in order of sequence numbers,
+    /// up to `limit` of them.
+    /// Tail the database by reading again from past the last sequence number returned.
+    /// In `StorageMode::Latest` only the latest event of every ID is left to read.
+    /// The index holds the latest `ALL_STREAM_CAPACITY` events, every event read through it is a point read
+    /// by its ID and sequence number.
+    /// The events evicted from it are read through the sequence number index of every table,
+    /// which only reads from the first one asked for.
+    pub fn read_all(
+        &self,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Response>, StorageEngineError> {
+        let first_seq = self.all_stream.first_seq();
+        let mut responses = match from_seq < first_seq {
+            true => self.read_ordered(
+                Order::Seq,
+                (from_seq, 0),
+                |entry| entry.seq < first_seq,
+                limit,
+                |_| true,
+            )?,
+            false => Vec::new(),
+        };
+        let entries = self.all_stream.range(from_seq.max(first_seq));
+        responses.extend(self.read_entries(entries, limit - responses.len(), |_| true)?);
+        Ok(responses)
+    }
+
+    /// Read the durable events the filter selects, with commit positions from `from_position` on,
+    /// in commit order, up to `limit` of them.
+    /// The commit position of the last event read is the resume token of a subscription:
+    /// read again from past it to continue exactly where the subscriber stopped,
+    /// whatever order the sequence numbers were handed out in.
+    /// Like `read_all`, the changes evicted from the index are read through the tables,
+    /// by the commit position index of every table.
+    /// Only `StorageMode::History` keeps every change. In `StorageMode::Latest` a new event of an ID drops the last one,
+    /// so a subscriber that fell behind would skip writes and deletes without knowing; reading changes fails instead.
+    pub fn read_changes(
+        &self,
+        filter: &ChangeFilter,
+        from_position: u64,
+        limit: usize,
+    ) -> Result<Vec<Response>, StorageEngineError> {
+        if self.mode == StorageMode::Latest {
+            return Err(StorageEngineError::ChangesNeedHistory);
         }
-        for table in &self.sstables {
-            let event = table.get(uuid);
+        let first_position = self.all_stream.first_position();
+        let mut responses = match from_position < first_position {
+            true => self.read_ordered(
+                Order::Position,
+                (from_position, 0),
+                |entry| {
+                    entry.position < first_position && entry.position <= self.committed_position
+                },
+                limit,
+                |response| filter.matches(response),
+            )?,
+            false => Vec::new(),
+        };
+        let entries = self
+            .all_stream
+            .changes(from_position.max(first_position))
+            .take_while(|entry| entry.position <= self.committed_position)
+            .filter(|entry| filter.matches_key(entry.key));
+        let limit = limit - responses.len();
+        responses.extend(self.read_entries(entries, limit, |response| filter.matches(response))?);
+        Ok(responses)
+    }
+
+    /// Read the events the order indexes of the MemTables and SSTables point at, from the key on,
+    /// for as long as the entries are `within` the range to read, keeping up to `limit` of them.
+    /// Every event is a point read by its ID, sequence number and commit position.
+    /// In `StorageMode::Latest` a table not merged yet can still hold an event replaced since, it is skipped.
+    fn read_ordered<W, F>(
+        &self,
+        order: Order,
+        from: (u64, u64),
+        within: W,
+        limit: usize,
+        keep: F,
+    ) -> Result<Vec<Response>, StorageEngineError>
+    where
+        W: Fn(&OrderEntry) -> bool,
+        F: Fn(&Response) -> bool,
+    {
+        let view = self.view();
+        let mut responses = Vec::new();
+        for entry in view.ordered(order, from)? {
+            let entry = entry?;
+            if responses.len() >= limit || !within(&entry) {
+                break;
+            }
+            let event = match self.mode {
+                StorageMode::Latest => view.get(entry.id)?.filter(|latest| {
+                    (latest.sequence_num(), latest.position()) == (entry.seq, entry.position)
+                }),
+                StorageMode::History => view.get_at(entry.id, entry.seq, entry.position)?,
+            };
             if let Some(event) = event {
-                let mut res = Response::new();
-                res.key = key.to_string();
-                res.status = EnumOrUnknown::new(Status::Ok);
-                res.op = match event.action() {
-                    Action::Read => EnumOrUnknown::new(Operation::Read),
-                    Action::Write => EnumOrUnknown::new(Operation::Write),
-                    Action::Delete => EnumOrUnknown::new(Operation::Delete),
-                };
-                if let Some(payload) = event.payload() {
-                    res.payload = payload;
+                let response = event_response(event.key(), &event);
+                if keep(&response) {
+                    responses.push(response);
                 }
-                responses.push(res);
             }
         }
-        Some(responses)
+        Ok(responses)
+    }
+
+    /// Read the events of the all-stream index entries, keeping up to `limit` of them.
+    fn read_entries<'a, I, F>(
+        &'a self,
+        mut entries: I,
+        limit: usize,
+        keep: F,
+    ) -> Result<Vec<Response>, StorageEngineError>
+    where
+        I: Iterator<Item = IndexEntry<'a>>,
+        F: Fn(&Response) -> bool,
+    {
+        let view = self.view();
+        let mut responses = Vec::new();
+        // Events dropped since they were indexed, or not kept, leave a page short, so keep reading.
+        while responses.len() < limit {
+            let page: Vec<IndexEntry> = entries.by_ref().take(limit - responses.len()).collect();
+            if page.is_empty() {
+                break;
+            }
+            responses.extend(
+                view.read_indexed(&page)?
+                    .into_iter()
+                    .filter(|response| keep(response)),
+            );
+        }
+        responses.truncate(limit);
+        Ok(responses)
+    }
+
+    /// Store the serialized state of the aggregate of a key, as of a version of its stream.
+    /// The version must be one the stream has reached.
+    /// The snapshot supersedes the previous one of the key, unless that one covers a later version.
+    pub fn save_aggregate_snapshot(
+        &mut self,
+        key: &str,
+        version: u64,
+        state: Vec<u8>,
+    ) -> Result<(), StorageEngineError> {
+        let current = self.stream_version(key);
+        let id = match self.get_key_id(key) {
+            Some(id) if version > 0 && version <= current => id,
+            _ => {
+                return Err(StorageEngineError::InvalidAggregateVersion(
+                    key.to_string(),
+                    version,
+                    current,
+                ))
+            }
+        };
+        if self
+            .get_aggregate_snapshot(key)
+            .is_some_and(|latest| latest.version() > version)
+        {
+            return Ok(());
+        }
+        self.aggregates
+            .put(AggregateSnapshot::new(key, id, version, state))
+    }
+
+    /// Get the latest snapshot of the aggregate of a key.
+    /// Returns None if there is none, or if the key was deleted and written again since.
+    pub fn get_aggregate_snapshot(&self, key: &str) -> Option<AggregateSnapshot> {
+        let id = self.get_key_id(key)?;
+        self.aggregates
+            .get(key)
+            .filter(|snapshot| snapshot.id() == id)
+            .cloned()
+    }
+
+    /// Scan the keys within the range in ascending order, yielding the latest event of every live key.
+    /// The scan reads from a snapshot, so it does not borrow the database and misses any later writes.
+    /// Every key is looked up on its own, so a scan costs a point read per key in the range.
+    pub fn scan<'a, R: RangeBounds<&'a str>>(&self, range: R) -> KeyScan {
+        self.snapshot().scan(range)
+    }
+
+    /// Scan the keys starting with the prefix in ascending order, like `scan`.
+    pub fn scan_prefix(&self, prefix: &str) -> KeyScan {
+        self.snapshot().scan_prefix(prefix)
     }
 
     pub fn delete_event(&mut self, request: Request) -> Response {
@@ -269,23 +916,1011 @@ impl RDeeBee {
                 return response;
             }
         };
-        self.bloomfilter.delete(id);
-        match self.wal.delete_event(id, request.seq) {
-            Ok(_) => {
-                response.status = EnumOrUnknown::new(Status::Ok);
-                response
+        let version = self.stream_version(&request.key);
+        if let Err(response) = Self::check_version(&request, version) {
+            return response;
+        }
+        let mut event = Event::with_id(id, Action::Delete, request.seq);
+        event.set_key(request.key.clone());
+        event.set_version(version + 1);
+        event.set_metadata(EventMetadata::from_request(&request));
+        if let Err(e) = self.append_to_wal(slice::from_mut(&mut event)) {
+            error!("failed to add delete event to write ahead log: {}", e);
+            response.status = EnumOrUnknown::new(Status::Server_Error);
+            return response;
+        }
+        // The stream outlives the delete when the history is kept.
+        // Otherwise the key is gone, and writing it again starts a new stream.
+        // Each ID is then added to and deleted from the bloom filter at most once.
+        if self.mode == StorageMode::Latest {
+            self.remove_key(&request.key, id);
+        }
+        self.insert(event);
+        response.status = EnumOrUnknown::new(Status::Ok);
+        response.version = version + 1;
+        response
+    }
+
+    /// Apply the writes and deletes of the batch atomically, in order.
+    /// The events go to the Wal as a single record, so a crash never leaves part of the batch behind.
+    /// Every key is checked up front, so the batch fails as a whole on an invalid op, a delete of a missing key,
+    /// a mismatched expected version or an invalid payload, taking earlier events of the batch into account.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Response {
+        let mut response = Response::new();
+        response.op = EnumOrUnknown::new(Operation::Batch);
+        // The IDs and stream versions of the keys written by the batch so far, no ID once deleted.
+        let mut streams: HashMap<&str, (Option<Uuid>, u64)> = HashMap::new();
+        let mut events = Vec::with_capacity(batch.len());
+        for request in &batch.requests {
+            let (id, version) = match streams.get(request.key.as_str()) {
+                Some(stream) => *stream,
+                None => (
+                    self.get_key_id(&request.key),
+                    self.stream_version(&request.key),
+                ),
+            };
+            if let Err(response) = Self::check_version(request, version) {
+                return response;
             }
-            Err(e) => {
-                error!("failed to add delete event to write ahead log: {}", e);
-                response.status = EnumOrUnknown::new(Status::Invalid_Op);
-                response
+            let mut event = match (request.op.enum_value(), id) {
+                (Ok(Operation::Write), Some(id)) => Event::with_id(id, Action::Write, request.seq),
+                (Ok(Operation::Write), None) => Event::new(Action::Write, request.seq),
+                (Ok(Operation::Delete), Some(id)) => {
+                    Event::with_id(id, Action::Delete, request.seq)
+                }
+                (Ok(Operation::Delete), None) => {
+                    response.key = request.key.clone();
+                    response.status = EnumOrUnknown::new(Status::Invalid_Key);
+                    return response;
+                }
+                (op, _) => {
+                    error!("Invalid Op in batch: {:?}", op);
+                    response.key = request.key.clone();
+                    response.status = EnumOrUnknown::new(Status::Invalid_Op);
+                    return response;
+                }
+            };
+            let deleted = event.action() == &Action::Delete && self.mode == StorageMode::Latest;
+            let stream = if deleted {
+                (None, 0)
+            } else {
+                (Some(event.id()), version + 1)
+            };
+            streams.insert(&request.key, stream);
+            let mut metadata = EventMetadata::from_request(request);
+            if event.action() == &Action::Write {
+                if let Err(response) = self.check_payload(request, &mut metadata) {
+                    return response;
+                }
+            }
+            event.set_key(request.key.clone());
+            event.set_version(version + 1);
+            event.set_metadata(metadata);
+            if !request.payload.is_empty() {
+                event.set_payload(Some(request.payload.clone()));
+            }
+            events.push(event);
+        }
+        if events.is_empty() {
+            response.status = EnumOrUnknown::new(Status::Ok);
+            return response;
+        }
+
+        if let Err(e) = self.append_to_wal(&mut events) {
+            error!("failed to add batch to write ahead log: {}", e);
+            response.status = EnumOrUnknown::new(Status::Server_Error);
+            return response;
+        }
+        for event in events {
+            match event.action() {
+                Action::Delete if self.mode == StorageMode::Latest => {
+                    self.remove_key(event.key(), event.id())
+                }
+                Action::Delete => {}
+                _ if !self.key_to_id_map.contains_key(event.key()) => {
+                    self.add_key(event.key().to_string(), event.id())
+                }
+                _ => {}
             }
+            self.insert(event);
         }
+        response.status = EnumOrUnknown::new(Status::Ok);
+        response
+    }
+
+    /// Map the new key to the ID.
+    fn add_key(&mut self, key: String, id: Uuid) {
+        self.key_to_id_map.insert(key, id);
+        self.bloomfilter.add(id);
+    }
+
+    /// Forget the deleted key.
+    fn remove_key(&mut self, key: &str, id: Uuid) {
+        self.bloomfilter.delete(id);
+        self.key_to_id_map.remove(key);
     }
 
+    /// Recover the MemTable and the SSTables from the live files listed in the manifest.
     pub fn recover(&mut self) -> Result<(), StorageEngineError> {
-        self.memtable = self.recovery.recover_memtable(&self.deebee_dir)?;
-        self.sstables = self.recovery.recover_sstable(&self.deebee_dir)?;
+        let version = self.manifest.lock().version().clone();
+        let (memtable, live_wals) = self.recovery.recover_memtable(
+            &self.deebee_dir,
+            self.mode,
+            &version,
+            &self.compression,
+        )?;
+        self.memtable = Arc::new(memtable);
+        let wal_path = self.wal.path();
+        self.recovered_wals = Vec::new();
+        for path in live_wals.into_iter().filter(|path| *path != wal_path) {
+            self.recovered_wals
+                .push(Wal::from_path(&path, self.durability, &self.compression)?);
+        }
+        self.levels = Levels::from_tables(self.recovery.recover_sstable(
+            &self.deebee_dir,
+            &version,
+            &self.compression,
+        )?);
+        self.rebuild_key_directory()?;
+        // Drop the snapshots of the streams deleted since.
+        let key_to_id_map = &self.key_to_id_map;
+        self.aggregates
+            .retain(|snapshot| key_to_id_map.get(snapshot.key()) == Some(&snapshot.id()))
+    }
+
+    /// Rebuild the key directory, the stream versions, the all-stream index and the bloom filter
+    /// from the SSTables and the MemTable.
+    /// Events are visited oldest first, so in `StorageMode::Latest`
+    /// the newest event of a key decides whether the key is still live.
+    /// A delete only drops the key if it still maps to the deleted ID,
+    /// as a MemTable visits its events in ID order and the key may have been written again under a new ID.
+    fn rebuild_key_directory(&mut self) -> Result<(), StorageEngineError> {
+        let mut key_to_id_map = OrdMap::new();
+        let mut versions: HashMap<Uuid, u64> = HashMap::new();
+        let mut all_stream = AllStreamIndex::new(self.mode, ALL_STREAM_CAPACITY);
+        let mut last_seq = 0;
+        let mut last_position = 0;
+        let mode = self.mode;
+        let mut visit = |event: Event| {
+            last_seq = last_seq.max(event.sequence_num());
+            last_position = last_position.max(event.position());
+            let version = versions.entry(event.id()).or_default();
+            *version = (*version).max(event.version());
+            if event.key().is_empty() {
+                return;
+            }
+            all_stream.insert(&event);
+            if mode == StorageMode::Latest && event.action() == &Action::Delete {
+                if key_to_id_map.get(event.key()) == Some(&event.id()) {
+                    key_to_id_map.remove(event.key());
+                }
+            } else {
+                key_to_id_map.insert(event.key().to_string(), event.id());
+            }
+        };
+        for table in self.levels.iter() {
+            for event in table.iter()? {
+                visit(event?);
+            }
+        }
+        for event in self.memtable.as_ref() {
+            visit(event);
+        }
+
+        let mut bloomfilter = BloomFilter::counting(self.filter);
+        for id in key_to_id_map.values() {
+            bloomfilter.add(*id);
+        }
+        self.stream_versions = key_to_id_map
+            .values()
+            .map(|id| (*id, versions.get(id).copied().unwrap_or(0)))
+            .collect();
+        self.key_to_id_map = key_to_id_map;
+        self.all_stream = all_stream;
+        self.last_seq = last_seq;
+        // Every event recovered is durable.
+        // A merge may have dropped the events of the last positions, the manifest still has them.
+        self.appended_position = last_position.max(self.manifest.lock().version().last_position);
+        self.committed_position = self.appended_position;
+        self.bloomfilter = bloomfilter;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, time::Duration};
+
+    use protobuf::EnumOrUnknown;
+    use serde_json::{json, Value};
+
+    use super::{AllStreamIndex, ChangeFilter, JsonSchema, KeyScan, RDeeBee, WriteBatch};
+    use crate::{
+        wire_format::operation::{Metadata, Operation, Request, Status},
+        CompressionOptions, Durability, FilterOptions, LeveledOptions, LeveledPolicy,
+        StorageEngineError, StorageMode,
+    };
+
+    fn request(key: &str, op: Operation, seq: u64) -> Request {
+        let mut request = Request::new();
+        request.key = key.to_string();
+        request.op = EnumOrUnknown::new(op);
+        request.seq = seq;
+        request
+    }
+
+    fn open(dir: &str, mode: StorageMode) -> RDeeBee {
+        RDeeBee::new(
+            Box::new(LeveledPolicy::new(LeveledOptions::default())),
+            dir.to_string(),
+            mode,
+            Durability::Sync,
+            FilterOptions::default(),
+            CompressionOptions::default(),
+        )
+        .unwrap()
+    }
+
+    /// Get a directory for the test, unique to this process so concurrent test runs don't share it.
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rdeebee-{}-{}", name, std::process::id()));
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn recover_key_directory_test() {
+        let dir = &test_dir("key-directory-test");
+        let _ = fs::remove_dir_all(dir);
+        let ids = {
+            let mut rdb = open(dir, StorageMode::Latest);
+            rdb.add_event(request("flushed", Operation::Write, 1));
+            rdb.add_event(request("deleted", Operation::Write, 2));
+            let ids = [rdb.get_key_id("flushed"), rdb.get_key_id("deleted")].map(Option::unwrap);
+            rdb.try_memtable_compact().unwrap();
+            rdb.add_event(request("logged", Operation::Write, 3));
+            rdb.delete_event(request("deleted", Operation::Delete, 4));
+            ids
+        };
+
+        let mut rdb = open(dir, StorageMode::Latest);
+        rdb.recover().unwrap();
+        assert!(rdb.contains_event(&ids[0].to_string()));
+        assert!(!rdb.contains_event(&ids[1].to_string()));
+        for (key, seq) in [("flushed", 1), ("logged", 3)] {
+            let response = rdb.get_event_by_key(key);
+            assert_eq!(response.status.enum_value(), Ok(Status::Ok));
+            assert_eq!(response.seq, seq);
+        }
+        let response = rdb.get_event_by_key("deleted");
+        assert_eq!(response.status.enum_value(), Ok(Status::Invalid_Key));
+    }
+
+    #[test]
+    fn background_flush_test() {
+        let dir = &test_dir("background-flush-test");
+        let _ = fs::remove_dir_all(dir);
+        {
+            let mut rdb = open(dir, StorageMode::History);
+            rdb.add_event(request("key", Operation::Write, 1));
+            let files = rdb.prepare_memtable_compact().unwrap().run().unwrap();
+            let mut flush = rdb.begin_memtable_compact(files).unwrap().unwrap();
+            assert!(rdb.prepare_memtable_compact().is_none());
+
+            // The MemTable being flushed stays readable, and writes go on.
+            rdb.add_event(request("key", Operation::Write, 2));
+            assert_eq!(rdb.get_event_by_key("key").seq, 2);
+            assert_eq!(rdb.get_stream_by_key("key").unwrap().len(), 2);
+
+            flush.run().unwrap();
+            rdb.finish_memtable_compact(flush).unwrap();
+            assert_eq!(rdb.get_stream_by_key("key").unwrap().len(), 2);
+        }
+
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        let seqs: Vec<u64> = rdb
+            .get_stream_by_key("key")
+            .unwrap()
+            .iter()
+            .map(|response| response.seq)
+            .collect();
+        assert_eq!(seqs, vec![1, 2]);
+    }
+
+    #[test]
+    fn flush_group_commit_test() {
+        let dir = &test_dir("flush-group-commit-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = RDeeBee::new(
+            Box::new(LeveledPolicy::new(LeveledOptions::default())),
+            dir.to_string(),
+            StorageMode::History,
+            Durability::GroupCommit {
+                interval: Duration::from_secs(3600),
+                max_bytes: 1024,
+            },
+            FilterOptions::default(),
+            CompressionOptions::default(),
+        )
+        .unwrap();
+        rdb.add_event(request("key", Operation::Write, 1));
+        let files = rdb.prepare_memtable_compact().unwrap().run().unwrap();
+        let mut flush = rdb.begin_memtable_compact(files).unwrap().unwrap();
+
+        // The new Wal syncs on its own, but the old one still waits on a group commit,
+        // so nothing is durable yet.
+        let mut large = request("key", Operation::Write, 2);
+        large.payload = vec![0; 2048];
+        rdb.add_event(large);
+        assert_eq!(rdb.durable_lsn(), 0);
+        rdb.sync_wal().unwrap();
+        assert_eq!(rdb.durable_lsn(), 2);
+
+        flush.run().unwrap();
+        rdb.finish_memtable_compact(flush).unwrap();
+        assert_eq!(rdb.durable_lsn(), 2);
+    }
+
+    #[test]
+    fn point_in_time_read_test() {
+        let dir = &test_dir("point-in-time-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.add_event(request("key", Operation::Write, 2));
+        rdb.add_event(request("key", Operation::Write, 4));
+        rdb.try_memtable_compact().unwrap();
+        rdb.add_event(request("key", Operation::Write, 6));
+        rdb.delete_event(request("key", Operation::Delete, 8));
+
+        let response = rdb.get_event_by_key_at("key", 1);
+        assert_eq!(response.status.enum_value(), Ok(Status::Invalid_Key));
+        for (at, seq) in [(2, 2), (5, 4), (7, 6)] {
+            let response = rdb.get_event_by_key_at("key", at);
+            assert_eq!(response.status.enum_value(), Ok(Status::Ok));
+            assert_eq!(response.op.enum_value(), Ok(Operation::Write));
+            assert_eq!(response.seq, seq);
+        }
+        let response = rdb.get_event_by_key_at("key", 8);
+        assert_eq!(response.op.enum_value(), Ok(Operation::Delete));
+        // A sequence number handed out before one already flushed, but written after it.
+        rdb.add_event(request("late", Operation::Write, 10));
+        rdb.try_memtable_compact().unwrap();
+        rdb.add_event(request("late", Operation::Write, 9));
+        assert_eq!(rdb.get_event_by_key_at("late", 9).seq, 9);
+        assert_eq!(rdb.get_event_by_key_at("late", 11).seq, 10);
+
+        let seqs: Vec<u64> = rdb
+            .stream_by_key("key", ..=5)
+            .unwrap()
+            .unwrap()
+            .map(|response| response.unwrap().seq)
+            .collect();
+        assert_eq!(seqs, vec![2, 4]);
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let dir = &test_dir("snapshot-test");
+        let _ = fs::remove_dir_all(dir);
+        let policy = LeveledPolicy::new(LeveledOptions {
+            l0_trigger: 2,
+            ..Default::default()
+        });
+        let mut rdb = RDeeBee::new(
+            Box::new(policy),
+            dir.to_string(),
+            StorageMode::Latest,
+            Durability::Sync,
+            FilterOptions::default(),
+            CompressionOptions::default(),
+        )
+        .unwrap();
+        rdb.add_event(request("a", Operation::Write, 1));
+        rdb.add_event(request("b", Operation::Write, 2));
+        rdb.try_memtable_compact().unwrap();
+        rdb.add_event(request("a", Operation::Write, 3));
+        let snapshot = rdb.snapshot();
+        assert_eq!(snapshot.seq(), 3);
+
+        // Overwrite, delete and add keys, then flush and compact the tables the snapshot holds.
+        rdb.add_event(request("a", Operation::Write, 4));
+        rdb.delete_event(request("b", Operation::Delete, 5));
+        rdb.add_event(request("c", Operation::Write, 6));
+        rdb.try_memtable_compact().unwrap();
+        rdb.try_sstables_compact().unwrap();
+        assert_eq!(rdb.levels.iter().count(), 1);
+        assert_eq!(rdb.get_event_by_key("a").seq, 4);
+
+        for (key, seq) in [("a", 3), ("b", 2)] {
+            let response = snapshot.get_event_by_key(key);
+            assert_eq!(response.status.enum_value(), Ok(Status::Ok));
+            assert_eq!(response.seq, seq);
+        }
+        let response = snapshot.get_event_by_key("c");
+        assert_eq!(response.status.enum_value(), Ok(Status::Invalid_Key));
+        let stream = snapshot.stream_by_key("b", ..).unwrap().unwrap();
+        assert_eq!(stream.count(), 1);
+
+        // The merged away tables only go once the snapshot does.
+        let tables = |dir| {
+            fs::read_dir(dir)
+                .unwrap()
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .unwrap()
+                        .path()
+                        .extension()
+                        .unwrap_or_default()
+                        == "table"
+                })
+                .count()
+        };
+        assert_eq!(tables(dir), 2);
+        drop(snapshot);
+        assert_eq!(tables(dir), 1);
+    }
+
+    #[test]
+    fn scan_test() {
+        let dir = &test_dir("scan-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = open(dir, StorageMode::History);
+        for (seq, key) in ["orders/2", "users/1", "orders/1", "orders/3"]
+            .iter()
+            .enumerate()
+        {
+            rdb.add_event(request(key, Operation::Write, seq as u64 + 1));
+        }
+        rdb.try_memtable_compact().unwrap();
+        rdb.add_event(request("orders/1", Operation::Write, 5));
+        rdb.delete_event(request("orders/3", Operation::Delete, 6));
+        rdb.add_event(request("ordersx", Operation::Write, 7));
+
+        let scan = |scan: KeyScan| -> Vec<(String, u64)> {
+            scan.map(|response| {
+                let response = response.unwrap();
+                (response.key, response.seq)
+            })
+            .collect()
+        };
+        let expected = vec![("orders/1".to_string(), 5), ("orders/2".to_string(), 1)];
+        assert_eq!(scan(rdb.scan_prefix("orders/")), expected);
+        assert_eq!(scan(rdb.scan("orders/".."orders0")), expected);
+        assert_eq!(scan(rdb.scan("orders/2"..)).len(), 3);
+        assert_eq!(scan(rdb.scan("orders/2"..="orders/2")), expected[1..]);
+        assert!(scan(rdb.scan("users/".."orders/")).is_empty());
+
+        // A scan pages through a snapshot, so later writes don't show up.
+        let pages = rdb.scan(..).pages(2);
+        rdb.add_event(request("orders/0", Operation::Write, 8));
+        let pages: Vec<usize> = pages.map(|page| page.unwrap().len()).collect();
+        assert_eq!(pages, vec![2, 2]);
+    }
+
+    #[test]
+    fn scan_tables_test() {
+        let dir = &test_dir("scan-tables-test");
+        let _ = fs::remove_dir_all(dir);
+        let scan = |scan: KeyScan| -> Vec<(String, u64)> {
+            scan.map(|response| {
+                let response = response.unwrap();
+                (response.key, response.seq)
+            })
+            .collect()
+        };
+        let expected = vec![
+            ("orders/1".to_string(), 4),
+            ("orders/3".to_string(), 3),
+            ("orders/4".to_string(), 6),
+        ];
+        {
+            let mut rdb = open(dir, StorageMode::History);
+            // Every key's events are spread over tables flushed one after another.
+            rdb.add_event(request("orders/1", Operation::Write, 1));
+            rdb.add_event(request("orders/2", Operation::Write, 2));
+            rdb.try_memtable_compact().unwrap();
+            rdb.add_event(request("orders/3", Operation::Write, 3));
+            rdb.add_event(request("orders/1", Operation::Write, 4));
+            rdb.try_memtable_compact().unwrap();
+            rdb.delete_event(request("orders/2", Operation::Delete, 5));
+            rdb.add_event(request("orders/4", Operation::Write, 6));
+            rdb.try_memtable_compact().unwrap();
+            assert_eq!(scan(rdb.scan_prefix("orders/")), expected);
+        }
+
+        // Recovery rebuilds the key directory the scan walks from the tables.
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        assert_eq!(scan(rdb.scan_prefix("orders/")), expected);
+        assert_eq!(scan(rdb.scan("orders/2"..)), expected[1..]);
+    }
+
+    #[test]
+    fn write_batch_test() {
+        let dir = &test_dir("write-batch-test");
+        let _ = fs::remove_dir_all(dir);
+        {
+            let mut rdb = open(dir, StorageMode::Latest);
+            rdb.add_event(request("account/1", Operation::Write, 1));
+            let mut batch = WriteBatch::new();
+            batch
+                .put("account/2", 2, b"opened".to_vec())
+                .delete("account/1", 3)
+                .put("account/1", 4, vec![]);
+            assert_eq!(rdb.write_batch(batch).status.enum_value(), Ok(Status::Ok));
+            assert_eq!(rdb.appended_lsn(), 2);
+            assert_eq!(rdb.get_event_by_key("account/1").seq, 4);
+            assert_eq!(rdb.get_event_by_key("account/2").payload, b"opened");
+
+            // A delete of a missing key fails the whole batch.
+            let mut batch = WriteBatch::new();
+            batch.put("account/3", 5, vec![]).delete("account/4", 6);
+            let response = rdb.write_batch(batch);
+            assert_eq!(response.status.enum_value(), Ok(Status::Invalid_Key));
+            assert_eq!(response.key, "account/4");
+            let response = rdb.get_event_by_key("account/3");
+            assert_eq!(response.status.enum_value(), Ok(Status::Invalid_Key));
+
+            let mut batch = WriteBatch::new();
+            batch
+                .put("account/3", 7, vec![])
+                .put("account/4", 8, vec![]);
+            rdb.write_batch(batch);
+        }
+
+        // Tear the last batch, as a crash halfway through the write would.
+        let wal = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|ext| ext == "wal"))
+            .unwrap();
+        let len = fs::metadata(&wal).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&wal)
+            .unwrap()
+            .set_len(len - 4)
+            .unwrap();
+
+        let mut rdb = open(dir, StorageMode::Latest);
+        rdb.recover().unwrap();
+        assert_eq!(rdb.get_event_by_key("account/1").seq, 4);
+        assert_eq!(rdb.get_event_by_key("account/2").seq, 2);
+        for key in ["account/3", "account/4"] {
+            assert_eq!(
+                rdb.get_event_by_key(key).status.enum_value(),
+                Ok(Status::Invalid_Key)
+            );
+        }
+    }
+
+    #[test]
+    fn expected_version_test() {
+        let dir = &test_dir("expected-version-test");
+        let _ = fs::remove_dir_all(dir);
+        let expect = |mut request: Request, version: u64| {
+            request.expected_version = Some(version);
+            request
+        };
+        {
+            let mut rdb = open(dir, StorageMode::History);
+            let response = rdb.add_event(expect(request("cart", Operation::Write, 1), 0));
+            assert_eq!(response.status.enum_value(), Ok(Status::Ok));
+            assert_eq!(response.version, 1);
+            rdb.try_memtable_compact().unwrap();
+
+            // A second writer still expecting a new stream loses the race.
+            let response = rdb.add_event(expect(request("cart", Operation::Write, 2), 0));
+            assert_eq!(response.status.enum_value(), Ok(Status::Version_Mismatch));
+            assert_eq!(response.version, 1);
+            assert_eq!(rdb.get_stream_by_key("cart").unwrap().len(), 1);
+
+            rdb.add_event(expect(request("cart", Operation::Write, 3), 1));
+            let mut batch = WriteBatch::from(vec![
+                expect(request("cart", Operation::Write, 4), 2),
+                expect(request("cart", Operation::Delete, 5), 2),
+            ]);
+            let response = rdb.write_batch(batch.clone());
+            assert_eq!(response.status.enum_value(), Ok(Status::Version_Mismatch));
+            batch.requests[1].expected_version = Some(3);
+            assert_eq!(rdb.write_batch(batch).status.enum_value(), Ok(Status::Ok));
+            assert_eq!(rdb.stream_version("cart"), 4);
+        }
+
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        assert_eq!(rdb.stream_version("cart"), 4);
+        assert_eq!(rdb.get_event_by_key("cart").version, 4);
+        let response = rdb.add_event(expect(request("cart", Operation::Write, 6), 4));
+        assert_eq!(response.version, 5);
+
+        // Without the history a delete ends the stream, and writing the key again starts a new one.
+        let dir = &test_dir("expected-version-latest-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = open(dir, StorageMode::Latest);
+        rdb.add_event(request("cart", Operation::Write, 1));
+        let response = rdb.delete_event(expect(request("cart", Operation::Delete, 2), 1));
+        assert_eq!(response.version, 2);
+        assert_eq!(rdb.stream_version("cart"), 0);
+        let response = rdb.add_event(expect(request("cart", Operation::Write, 3), 0));
+        assert_eq!(response.status.enum_value(), Ok(Status::Ok));
+        assert_eq!(response.version, 1);
+    }
+
+    #[test]
+    fn aggregate_snapshot_test() {
+        let dir = &test_dir("aggregate-snapshot-test");
+        let _ = fs::remove_dir_all(dir);
+        let versions = |rdb: &RDeeBee, after: u64| -> Vec<u64> {
+            let stream = rdb
+                .stream_by_key_after_version("cart", after)
+                .unwrap()
+                .unwrap();
+            stream.map(|response| response.unwrap().version).collect()
+        };
+        {
+            let mut rdb = open(dir, StorageMode::History);
+            for seq in 1..=3 {
+                rdb.add_event(request("cart", Operation::Write, seq));
+            }
+            rdb.try_memtable_compact().unwrap();
+            rdb.add_event(request("cart", Operation::Write, 4));
+            assert!(rdb.save_aggregate_snapshot("cart", 5, vec![]).is_err());
+            assert!(rdb.save_aggregate_snapshot("missing", 1, vec![]).is_err());
+            rdb.save_aggregate_snapshot("cart", 3, b"three".to_vec())
+                .unwrap();
+            // An older snapshot doesn't replace a newer one.
+            rdb.save_aggregate_snapshot("cart", 2, b"two".to_vec())
+                .unwrap();
+            assert_eq!(versions(&rdb, 3), vec![4]);
+        }
+
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        let snapshot = rdb.get_aggregate_snapshot("cart").unwrap();
+        assert_eq!((snapshot.version(), snapshot.state()), (3, &b"three"[..]));
+        rdb.add_event(request("cart", Operation::Write, 5));
+        assert_eq!(versions(&rdb, snapshot.version()), vec![4, 5]);
+
+        // Without the history the snapshot ends with its stream.
+        let dir = &test_dir("aggregate-snapshot-latest-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = open(dir, StorageMode::Latest);
+        rdb.add_event(request("cart", Operation::Write, 1));
+        rdb.save_aggregate_snapshot("cart", 1, vec![]).unwrap();
+        rdb.delete_event(request("cart", Operation::Delete, 2));
+        rdb.add_event(request("cart", Operation::Write, 3));
+        assert!(rdb.get_aggregate_snapshot("cart").is_none());
+    }
+
+    #[test]
+    fn event_metadata_test() {
+        let dir = &test_dir("event-metadata-test");
+        let _ = fs::remove_dir_all(dir);
+        {
+            let mut rdb = open(dir, StorageMode::History);
+            let mut write = request("order", Operation::Write, 1);
+            let metadata = write.metadata.mut_or_insert_default();
+            metadata.event_type = "OrderPlaced".to_string();
+            metadata.correlation_id = "checkout-7".to_string();
+            metadata
+                .headers
+                .insert("source".to_string(), "web".to_string());
+            rdb.add_event(write);
+            rdb.try_memtable_compact().unwrap();
+            let mut write = request("order", Operation::Write, 2);
+            write.metadata.mut_or_insert_default().timestamp = 42;
+            rdb.add_event(write);
+        }
+
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        let stream = rdb.stream_by_key("order", ..).unwrap().unwrap();
+        let metadata: Vec<Metadata> = stream
+            .map(|response| response.unwrap().metadata.unwrap())
+            .collect();
+        assert_eq!(metadata[0].event_type, "OrderPlaced");
+        assert_eq!(metadata[0].correlation_id, "checkout-7");
+        assert_eq!(metadata[0].headers["source"], "web");
+        // Events are stamped when appended, unless the writer set a timestamp.
+        assert!(metadata[0].timestamp > 0);
+        assert_eq!(metadata[1].timestamp, 42);
+        assert_eq!(rdb.get_event_by_key("order").metadata.timestamp, 42);
+    }
+
+    #[test]
+    fn schema_registry_test() {
+        let dir = &test_dir("schema-registry-test");
+        let _ = fs::remove_dir_all(dir);
+        let typed = |seq: u64, schema_version: u32, payload: &str| {
+            let mut write = request("order", Operation::Write, seq);
+            write.payload = payload.as_bytes().to_vec();
+            let metadata = write.metadata.mut_or_insert_default();
+            metadata.event_type = "OrderPlaced".to_string();
+            metadata.schema_version = schema_version;
+            write
+        };
+        let mut rdb = open(dir, StorageMode::History);
+        let schema = JsonSchema::new(json!({"required": ["total"]}));
+        rdb.schema_registry_mut().register("OrderPlaced", 1, schema);
+        let response = rdb.add_event(typed(1, 0, r#"{"total": 5}"#));
+        assert_eq!(response.status.enum_value(), Ok(Status::Ok));
+        let response = rdb.add_event(typed(2, 0, r#"{"sum": 5}"#));
+        assert_eq!(response.status.enum_value(), Ok(Status::Invalid_Payload));
+        rdb.try_memtable_compact().unwrap();
+
+        // Version 2 renames the total, and events of version 1 are read in the new shape.
+        rdb.schema_registry_mut()
+            .register(
+                "OrderPlaced",
+                2,
+                JsonSchema::new(json!({"required": ["amount"]})),
+            )
+            .register_upcaster("OrderPlaced", 1, |payload| {
+                let v1: Value = serde_json::from_slice(&payload).map_err(|e| e.to_string())?;
+                Ok(json!({"amount": v1["total"]}).to_string().into_bytes())
+            });
+        let mut batch = WriteBatch::from(vec![
+            typed(3, 1, r#"{"total": 7}"#),
+            typed(4, 0, r#"{"total": 7}"#),
+        ]);
+        let response = rdb.write_batch(batch.clone());
+        assert_eq!(response.status.enum_value(), Ok(Status::Invalid_Payload));
+        batch.requests.pop();
+        assert_eq!(rdb.write_batch(batch).status.enum_value(), Ok(Status::Ok));
+
+        let payloads: Vec<(u32, String)> = rdb
+            .stream_by_key("order", ..)
+            .unwrap()
+            .unwrap()
+            .map(|response| {
+                let response = response.unwrap();
+                let payload = String::from_utf8(response.payload).unwrap();
+                (response.metadata.schema_version, payload)
+            })
+            .collect();
+        let expected =
+            [r#"{"amount":5}"#, r#"{"amount":7}"#].map(|payload| (2, payload.to_string()));
+        assert_eq!(payloads, expected);
+        assert_eq!(rdb.get_event_by_key("order").payload, br#"{"amount":7}"#);
+    }
+
+    #[test]
+    fn read_all_test() {
+        let dir = &test_dir("read-all-test");
+        let _ = fs::remove_dir_all(dir);
+        let read_all = |rdb: &RDeeBee, from_seq: u64, limit: usize| -> Vec<(String, u64)> {
+            let responses = rdb.read_all(from_seq, limit).unwrap();
+            responses
+                .into_iter()
+                .map(|response| (response.key, response.seq))
+                .collect()
+        };
+        let events = |events: &[(&str, u64)]| -> Vec<(String, u64)> {
+            events
+                .iter()
+                .map(|(key, seq)| (key.to_string(), *seq))
+                .collect()
+        };
+        {
+            let mut rdb = open(dir, StorageMode::History);
+            rdb.add_event(request("cart", Operation::Write, 1));
+            rdb.add_event(request("order", Operation::Write, 2));
+            rdb.try_memtable_compact().unwrap();
+            rdb.add_event(request("cart", Operation::Write, 3));
+            rdb.delete_event(request("order", Operation::Delete, 4));
+            rdb.add_event(request("order", Operation::Write, 5));
+            let all = events(&[
+                ("cart", 1),
+                ("order", 2),
+                ("cart", 3),
+                ("order", 4),
+                ("order", 5),
+            ]);
+            assert_eq!(read_all(&rdb, 0, 10), all);
+            assert_eq!(read_all(&rdb, 2, 2), all[1..3]);
+            assert!(read_all(&rdb, 6, 10).is_empty());
+        }
+
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        assert_eq!(
+            read_all(&rdb, 3, 10),
+            events(&[("cart", 3), ("order", 4), ("order", 5)])
+        );
+
+        // Without the history only the latest event of every ID is left.
+        let dir = &test_dir("read-all-latest-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = open(dir, StorageMode::Latest);
+        rdb.add_event(request("cart", Operation::Write, 1));
+        rdb.add_event(request("order", Operation::Write, 2));
+        rdb.try_memtable_compact().unwrap();
+        rdb.add_event(request("cart", Operation::Write, 3));
+        assert_eq!(read_all(&rdb, 0, 10), events(&[("order", 2), ("cart", 3)]));
+        assert_eq!(read_all(&rdb, 0, 1), events(&[("order", 2)]));
+    }
+
+    #[test]
+    fn read_all_evicted_test() {
+        let dir = &test_dir("read-all-evicted-test");
+        let _ = fs::remove_dir_all(dir);
+        let read_all = |rdb: &RDeeBee, from_seq: u64, limit: usize| -> Vec<u64> {
+            let responses = rdb.read_all(from_seq, limit).unwrap();
+            responses.into_iter().map(|response| response.seq).collect()
+        };
+        let mut rdb = open(dir, StorageMode::History);
+        // Index only the last two events, so the rest are read from the tables.
+        rdb.all_stream = AllStreamIndex::new(StorageMode::History, 2);
+        rdb.add_event(request("cart", Operation::Write, 1));
+        rdb.add_event(request("order", Operation::Write, 3));
+        rdb.try_memtable_compact().unwrap();
+        rdb.add_event(request("cart", Operation::Write, 2));
+        rdb.add_event(request("order", Operation::Write, 4));
+        rdb.add_event(request("cart", Operation::Write, 5));
+        assert_eq!(rdb.all_stream.first_seq(), 4);
+        assert_eq!(read_all(&rdb, 0, 10), vec![1, 2, 3, 4, 5]);
+        assert_eq!(read_all(&rdb, 2, 2), vec![2, 3]);
+        assert_eq!(read_all(&rdb, 3, 2), vec![3, 4]);
+
+        // The replaced events a table still holds are skipped.
+        let dir = &test_dir("read-all-evicted-latest-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = open(dir, StorageMode::Latest);
+        rdb.all_stream = AllStreamIndex::new(StorageMode::Latest, 1);
+        rdb.add_event(request("cart", Operation::Write, 1));
+        rdb.add_event(request("order", Operation::Write, 2));
+        rdb.try_memtable_compact().unwrap();
+        rdb.add_event(request("cart", Operation::Write, 3));
+        rdb.add_event(request("user", Operation::Write, 4));
+        assert_eq!(rdb.all_stream.first_seq(), 4);
+        assert_eq!(read_all(&rdb, 0, 10), vec![2, 3, 4]);
+        assert_eq!(read_all(&rdb, 0, 1), vec![2]);
+    }
+
+    #[test]
+    fn read_changes_test() {
+        let dir = &test_dir("read-changes-test");
+        let _ = fs::remove_dir_all(dir);
+        let typed = |key: &str, seq: u64, event_type: &str| {
+            let mut write = request(key, Operation::Write, seq);
+            write.metadata.mut_or_insert_default().event_type = event_type.to_string();
+            write
+        };
+        let changes =
+            |rdb: &RDeeBee, filter: &ChangeFilter, from_position: u64, limit: usize| -> Vec<u64> {
+                let responses = rdb.read_changes(filter, from_position, limit).unwrap();
+                responses.into_iter().map(|response| response.seq).collect()
+            };
+        let all = ChangeFilter::new();
+        {
+            let durability = Durability::GroupCommit {
+                interval: Duration::from_secs(3600),
+                max_bytes: 1 << 20,
+            };
+            let mut rdb = RDeeBee::new(
+                Box::new(LeveledPolicy::new(LeveledOptions::default())),
+                dir.to_string(),
+                StorageMode::History,
+                durability,
+                FilterOptions::default(),
+                CompressionOptions::default(),
+            )
+            .unwrap();
+            rdb.add_event(typed("orders/1", 1, "OrderPlaced"));
+            rdb.add_event(typed("carts/1", 2, "ItemAdded"));
+            // Nothing is delivered before it is durable.
+            assert!(changes(&rdb, &all, 0, 10).is_empty());
+            rdb.sync_wal().unwrap();
+            assert_eq!(rdb.committed_position(), 2);
+            rdb.try_memtable_compact().unwrap();
+            rdb.add_event(typed("orders/2", 5, "OrderPlaced"));
+            rdb.sync_wal().unwrap();
+            let read = rdb.read_changes(&all, 0, 10).unwrap();
+            assert_eq!(
+                read.iter().map(|response| response.seq).collect::<Vec<_>>(),
+                vec![1, 2, 5]
+            );
+            let token = read.last().unwrap().position;
+            assert_eq!(token, 3);
+
+            // Sequence numbers handed out before 5 but written after it.
+            rdb.add_event(typed("orders/1", 3, "OrderPaid"));
+            rdb.delete_event(request("orders/1", Operation::Delete, 4));
+            // Not delivered before they are durable, though 5 already is.
+            assert!(changes(&rdb, &all, token + 1, 10).is_empty());
+            rdb.sync_wal().unwrap();
+            assert_eq!(rdb.committed_position(), 5);
+
+            // Events come in commit order, and resuming from past the token doesn't skip any.
+            assert_eq!(changes(&rdb, &all, 0, 10), vec![1, 2, 5, 3, 4]);
+            assert_eq!(changes(&rdb, &all, token + 1, 10), vec![3, 4]);
+            let orders = ChangeFilter::new().key_prefix("orders/");
+            assert_eq!(changes(&rdb, &orders, 0, 10), vec![1, 5, 3, 4]);
+            let placed = orders.event_type("OrderPlaced");
+            assert_eq!(changes(&rdb, &placed, 0, 1), vec![1]);
+            assert_eq!(changes(&rdb, &placed, 2, 10), vec![5]);
+        }
+
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        assert_eq!(rdb.committed_position(), 5);
+        assert_eq!(changes(&rdb, &all, 3, 10), vec![5, 3, 4]);
+        rdb.add_event(typed("orders/3", 6, "OrderPlaced"));
+        let positions: Vec<u64> = rdb
+            .read_changes(&all, 0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|response| response.position)
+            .collect();
+        assert_eq!(positions, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            changes(&rdb, &ChangeFilter::new().event_type("OrderPlaced"), 2, 10),
+            vec![5, 6]
+        );
+    }
+
+    #[test]
+    fn read_changes_evicted_test() {
+        let dir = &test_dir("read-changes-evicted-test");
+        let _ = fs::remove_dir_all(dir);
+        let changes =
+            |rdb: &RDeeBee, filter: &ChangeFilter, from_position: u64, limit: usize| -> Vec<u64> {
+                let responses = rdb.read_changes(filter, from_position, limit).unwrap();
+                responses.into_iter().map(|response| response.seq).collect()
+            };
+        let all = ChangeFilter::new();
+        {
+            let mut rdb = open(dir, StorageMode::History);
+            // Index only the last two events, so the rest are read from the tables.
+            rdb.all_stream = AllStreamIndex::new(StorageMode::History, 2);
+            rdb.add_event(request("cart", Operation::Write, 1));
+            rdb.add_event(request("order", Operation::Write, 3));
+            rdb.try_memtable_compact().unwrap();
+            rdb.add_event(request("cart", Operation::Write, 2));
+            rdb.add_event(request("order", Operation::Write, 4));
+            rdb.add_event(request("cart", Operation::Write, 5));
+            assert_eq!(rdb.all_stream.first_position(), 4);
+            assert_eq!(changes(&rdb, &all, 0, 10), vec![1, 3, 2, 4, 5]);
+            assert_eq!(changes(&rdb, &all, 0, 2), vec![1, 3]);
+            // Resuming from before the index carries on into it.
+            assert_eq!(changes(&rdb, &all, 3, 2), vec![2, 4]);
+            let orders = ChangeFilter::new().key_prefix("order");
+            assert_eq!(changes(&rdb, &orders, 0, 10), vec![3, 4]);
+        }
+
+        // The tables keep their position index across a restart.
+        let mut rdb = open(dir, StorageMode::History);
+        rdb.recover().unwrap();
+        rdb.try_memtable_compact().unwrap();
+        rdb.all_stream = AllStreamIndex::new(StorageMode::History, 2);
+        rdb.add_event(request("cart", Operation::Write, 6));
+        rdb.add_event(request("order", Operation::Write, 7));
+        rdb.add_event(request("cart", Operation::Write, 8));
+        assert_eq!(rdb.all_stream.first_position(), 7);
+        let positions: Vec<u64> = rdb
+            .read_changes(&all, 0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|response| response.position)
+            .collect();
+        assert_eq!(positions, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(changes(&rdb, &all, 2, 10), vec![3, 2, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn read_changes_latest_test() {
+        let dir = &test_dir("read-changes-latest-test");
+        let _ = fs::remove_dir_all(dir);
+        let mut rdb = open(dir, StorageMode::Latest);
+        // The second write replaces the first before the subscriber reads, so it would never see the first.
+        rdb.add_event(request("cart", Operation::Write, 1));
+        rdb.add_event(request("cart", Operation::Write, 2));
+        assert_eq!(rdb.committed_position(), 2);
+        assert!(matches!(
+            rdb.read_changes(&ChangeFilter::new(), 0, 10),
+            Err(StorageEngineError::ChangesNeedHistory)
+        ));
+    }
+}
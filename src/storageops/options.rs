@@ -0,0 +1,139 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{Codec, Lz4, NoCompression};
+
+/// How much of a key's event stream the storage engine retains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Only the newest event per key survives a MemTable write or an SSTable merge.
+    /// A delete removes the key on the next merge.
+    Latest,
+    /// Every event appended for a key is kept, ordered by sequence number,
+    /// through MemTable flushes and SSTable merges.
+    History,
+}
+
+/// When a Wal append is considered durable.
+/// A write is only acknowledged once it is durable under the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// fsync the Wal after every append.
+    Sync,
+    /// fsync once `max_bytes` are pending or `interval` has passed since the last fsync,
+    /// whichever comes first. Writes wait for the fsync covering them.
+    GroupCommit {
+        interval: Duration,
+        max_bytes: usize,
+    },
+    /// Hand every append to the OS without an fsync.
+    /// Survives a process crash, but not an OS crash or power loss.
+    Buffered,
+}
+
+/// Sizing of the bloom filter over the keys in the database, and the false positive probability of the SSTable filters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterOptions {
+    /// The number of keys the filter is sized for.
+    /// Past it the false positive rate climbs, but the filter never gives a false negative.
+    pub capacity: usize,
+    /// The false positive probability at `capacity` keys.
+    /// The filter of every SSTable is sized from its number of IDs to keep this probability.
+    pub fp_rate: f64,
+}
+
+impl Default for FilterOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1_000_000,
+            fp_rate: 0.001,
+        }
+    }
+}
+
+/// The codecs new files are written with, and the custom codecs older files may have been written with.
+/// Changing the codecs is safe, as every file records the codec it was written with.
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    /// Compresses every data block of the SSTables.
+    pub table_codec: Arc<dyn Codec>,
+    /// Compresses every record of the Wal.
+    /// A record holds a whole batch, often of a few small events, so it usually compresses less than a block.
+    pub wal_codec: Arc<dyn Codec>,
+    /// Custom codecs to read files with, besides the two above and the built-in ones.
+    pub codecs: Vec<Arc<dyn Codec>>,
+}
+
+impl CompressionOptions {
+    /// Find the codec with the ID, among the configured and the built-in codecs.
+    pub(crate) fn codec(&self, id: u8) -> Option<Arc<dyn Codec>> {
+        let builtin: [Arc<dyn Codec>; 2] = [Arc::new(NoCompression), Arc::new(Lz4)];
+        [&self.table_codec, &self.wal_codec]
+            .into_iter()
+            .chain(&self.codecs)
+            .cloned()
+            .chain(builtin)
+            .find(|codec| codec.id() == id)
+    }
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            table_codec: Arc::new(Lz4),
+            wal_codec: Arc::new(NoCompression),
+            codecs: Vec::new(),
+        }
+    }
+}
+
+/// Tuning of `LeveledPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeveledOptions {
+    /// The MemTable size in bytes that triggers a flush.
+    pub memtable_size: usize,
+    /// The number of L0 tables that triggers a compaction into L1.
+    pub l0_trigger: usize,
+    /// The size L1 may grow to. Every level below may hold `level_size_multiplier` times more.
+    pub base_level_size: u64,
+    pub level_size_multiplier: u64,
+    /// Size of the tables written by a compaction.
+    pub target_table_size: u64,
+}
+
+impl Default for LeveledOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            l0_trigger: 4,
+            base_level_size: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+            target_table_size: 2 * 1024 * 1024,
+        }
+    }
+}
+
+/// Tuning of `SizeTieredPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeTieredOptions {
+    /// The MemTable size in bytes that triggers a flush.
+    pub memtable_size: usize,
+    /// The fewest tables of similar size merged at once.
+    pub min_threshold: usize,
+    /// The most tables merged at once.
+    pub max_threshold: usize,
+    /// A table is of similar size if it is within these fractions of the average size of the tier.
+    pub bucket_low: f64,
+    pub bucket_high: f64,
+}
+
+impl Default for SizeTieredOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            min_threshold: 4,
+            max_threshold: 32,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use uuid::Uuid;
+
+use crate::{LeveledOptions, SizeTieredOptions};
+
+/// What a `CompactionPolicy` sees of an SSTable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableStats {
+    /// Size of the data blocks in bytes.
+    pub size: u64,
+    /// The lowest and highest ID in the table, or None if it is empty.
+    pub key_range: Option<(Uuid, Uuid)>,
+}
+
+/// A compaction picked by a `CompactionPolicy`.
+/// The `inputs` of `level` are merged into `output_level`, which is either `level` or the level below.
+/// When moving down, the tables of the level below that overlap the inputs are merged in as well.
+/// The output is split into tables of about `table_size` bytes.
+///
+/// L0 tables are ordered by age, so L0 inputs must be adjacent,
+/// and a compaction from L0 into L1 must take the oldest tables.
+/// Otherwise newer data could end up behind older data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compaction {
+    pub level: usize,
+    pub inputs: Vec<usize>,
+    pub output_level: usize,
+    pub table_size: u64,
+}
+
+/// Decides when the MemTable is flushed and which SSTables are compacted.
+/// `SizeTieredPolicy` rewrites data less often, which suits write heavy workloads.
+/// `LeveledPolicy` keeps fewer tables to search per read, which suits read heavy workloads.
+pub trait CompactionPolicy: Send + Sync {
+    /// Should a MemTable of this many bytes be flushed into an SSTable?
+    fn flush_due(&self, memtable_size: usize) -> bool;
+
+    /// Pick the next compaction, or None if the tables don't need one.
+    /// `levels` describes the tables of every level,
+    /// L0 ordered oldest first and every other level ordered by ID.
+    fn pick(&mut self, levels: &[Vec<TableStats>]) -> Option<Compaction>;
+}
+
+/// Leveled compaction.
+/// L0 is compacted into L1 once it holds too many tables,
+/// every other level into the one below once it grows too large.
+/// The level with the highest score is compacted first.
+pub struct LeveledPolicy {
+    options: LeveledOptions,
+    /// The highest ID compacted out of each level, so compactions rotate through the key space.
+    compact_pointers: Vec<Option<Uuid>>,
+}
+
+impl LeveledPolicy {
+    pub fn new(options: LeveledOptions) -> Self {
+        Self {
+            options,
+            compact_pointers: Vec::new(),
+        }
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        self.options.base_level_size * self.options.level_size_multiplier.pow(level as u32 - 1)
+    }
+
+    /// How far over its limit the level is. A level needs compacting at 1 or more.
+    fn score(&self, levels: &[Vec<TableStats>], level: usize) -> f64 {
+        match level {
+            0 => levels[0].len() as f64 / self.options.l0_trigger as f64,
+            level => {
+                let size: u64 = levels[level].iter().map(|table| table.size).sum();
+                size as f64 / self.max_level_size(level) as f64
+            }
+        }
+    }
+}
+
+impl CompactionPolicy for LeveledPolicy {
+    fn flush_due(&self, memtable_size: usize) -> bool {
+        memtable_size > self.options.memtable_size
+    }
+
+    /// All of L0 is compacted at once, since its tables overlap.
+    /// From any other level, the table after the compact pointer is picked.
+    fn pick(&mut self, levels: &[Vec<TableStats>]) -> Option<Compaction> {
+        // The last level has nowhere to compact to.
+        let (level, score) = (0..levels.len().saturating_sub(1))
+            .map(|level| (level, self.score(levels, level)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        if score < 1.0 {
+            return None;
+        }
+        let inputs = match level {
+            0 => (0..levels[0].len()).collect(),
+            level => {
+                self.compact_pointers.resize(levels.len(), None);
+                let tables = &levels[level];
+                let next = self.compact_pointers[level].map_or(0, |pointer| {
+                    tables.partition_point(|table| {
+                        table.key_range.is_none_or(|(first, _)| first <= pointer)
+                    })
+                });
+                let next = if next < tables.len() { next } else { 0 };
+                if let Some((_, last)) = tables[next].key_range {
+                    self.compact_pointers[level] = Some(last);
+                }
+                vec![next]
+            }
+        };
+        Some(Compaction {
+            level,
+            inputs,
+            output_level: level + 1,
+            table_size: self.options.target_table_size,
+        })
+    }
+}
+
+/// Size-tiered compaction.
+/// Every table stays in L0, and runs of adjacent tables of similar size are merged into one.
+/// Each table is rewritten about once per tier, at the cost of more tables to search per read.
+pub struct SizeTieredPolicy {
+    options: SizeTieredOptions,
+}
+
+impl SizeTieredPolicy {
+    pub fn new(options: SizeTieredOptions) -> Self {
+        Self { options }
+    }
+
+    /// Is the table close enough to the average size of the tier to join it?
+    fn similar(&self, size: u64, average: f64) -> bool {
+        let size = size as f64;
+        size >= average * self.options.bucket_low && size <= average * self.options.bucket_high
+    }
+}
+
+impl CompactionPolicy for SizeTieredPolicy {
+    fn flush_due(&self, memtable_size: usize) -> bool {
+        memtable_size > self.options.memtable_size
+    }
+
+    /// Pick the oldest run of at least `min_threshold` adjacent tables of similar size.
+    fn pick(&mut self, levels: &[Vec<TableStats>]) -> Option<Compaction> {
+        let tables = levels.first()?;
+        for start in 0..tables.len() {
+            let mut total = 0;
+            let mut end = start;
+            while end < tables.len() && end - start < self.options.max_threshold {
+                let average = total as f64 / (end - start) as f64;
+                if end > start && !self.similar(tables[end].size, average) {
+                    break;
+                }
+                total += tables[end].size;
+                end += 1;
+            }
+            if end - start >= self.options.min_threshold.max(2) {
+                return Some(Compaction {
+                    level: 0,
+                    inputs: (start..end).collect(),
+                    output_level: 0,
+                    table_size: u64::MAX,
+                });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::{CompactionPolicy, LeveledPolicy, SizeTieredPolicy, TableStats};
+    use crate::{LeveledOptions, SizeTieredOptions};
+
+    /// Tables of the sizes, with non-overlapping key ranges in order.
+    fn tables(sizes: &[u64]) -> Vec<TableStats> {
+        let mut ids: Vec<Uuid> = (0..sizes.len() * 2).map(|_| Uuid::new_v4()).collect();
+        ids.sort();
+        sizes
+            .iter()
+            .zip(ids.chunks(2))
+            .map(|(size, ids)| TableStats {
+                size: *size,
+                key_range: Some((ids[0], ids[1])),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn size_tiered_pick_test() {
+        let mut policy = SizeTieredPolicy::new(SizeTieredOptions {
+            memtable_size: 1024,
+            min_threshold: 3,
+            ..Default::default()
+        });
+        assert!(policy.flush_due(2048));
+        assert!(!policy.flush_due(512));
+
+        // A large merged table, followed by a run of small flushes.
+        let mut levels = vec![tables(&[10_000, 100, 120])];
+        assert!(policy.pick(&levels).is_none());
+        levels[0].extend(tables(&[90]));
+        let compaction = policy.pick(&levels).unwrap();
+        assert_eq!(compaction.inputs, vec![1, 2, 3]);
+        assert_eq!(compaction.output_level, 0);
+    }
+
+    #[test]
+    fn leveled_pick_test() {
+        let mut policy = LeveledPolicy::new(LeveledOptions {
+            l0_trigger: 2,
+            base_level_size: 100,
+            ..Default::default()
+        });
+        let mut levels = vec![tables(&[10]), vec![], vec![]];
+        assert!(policy.pick(&levels).is_none());
+        levels[0].extend(tables(&[10]));
+        let compaction = policy.pick(&levels).unwrap();
+        assert_eq!((compaction.level, compaction.inputs), (0, vec![0, 1]));
+
+        // L1 is over its limit, and its tables are picked in turn.
+        levels = vec![vec![], tables(&[60, 60, 60]), vec![]];
+        for expected in [0, 1, 2, 0] {
+            let compaction = policy.pick(&levels).unwrap();
+            assert_eq!(compaction.level, 1);
+            assert_eq!(compaction.inputs, vec![expected]);
+        }
+    }
+}
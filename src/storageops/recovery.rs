@@ -1,94 +1,434 @@
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use tracing::{info, warn};
 
 use crate::{
+    storage::{record_follows, MemTable, SSTable, Version, Wal, WalIterator, HEADER_SIZE},
     storageops::errors::StorageEngineError,
-    storage::{MemTable, SSTable, Wal},
+    CompressionOptions, StorageMode,
 };
 
 /// In case there is a crash of the system and the MemTable is lost,
-/// this will recover MemTable from the latest WAL.
+/// this will recover MemTable from the live WALs.
+/// The manifest decides which files are live, see `Manifest`.
 pub(crate) struct Recovery {}
 
 impl Recovery {
-    pub(crate) fn recover_memtable(&self, dir: &str) -> Result<MemTable, StorageEngineError> {
-        let mut memtable = MemTable::new();
-        let (wal_epochs, mut wal_map) = self.recover_files(dir, true)?;
-        let wal_epochs_iter = wal_epochs.into_iter();
-        for epoch in wal_epochs_iter {
-            if let Some((_, path)) = wal_map.remove_entry(&epoch) {
-                let wal = Wal::from_path(&path)?;
-                for event in wal {
-                    memtable.insert(event);
+    /// Replay the live Wals of the version into a MemTable.
+    /// Returns the MemTable along with the Wals protecting it.
+    /// A crash can leave the last record of the last Wal written to half written,
+    /// its header or data cut short or garbled. It was never acknowledged, so the Wal is cut off there.
+    /// A bad record anywhere else is corruption, and an error.
+    pub(crate) fn recover_memtable(
+        &self,
+        dir: &str,
+        mode: StorageMode,
+        version: &Version,
+        compression: &CompressionOptions,
+    ) -> Result<(MemTable, Vec<PathBuf>), StorageEngineError> {
+        let mut memtable = MemTable::new(mode);
+        let mut live_wals = Vec::new();
+        // The Wals started after it, such as the one started when the database was opened, hold no records.
+        let last_written = version.wals.iter().rposition(|id| {
+            fs::metadata(Wal::file_path(dir, *id)).is_ok_and(|meta| meta.len() > HEADER_SIZE as u64)
+        });
+        for (index, id) in version.wals.iter().enumerate() {
+            let path = Wal::file_path(dir, *id);
+            live_wals.push(path.clone());
+            let mut torn = None;
+            for event in WalIterator::new(path.clone(), compression)? {
+                let e = match event {
+                    Ok(event) => {
+                        memtable.insert(event);
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                match e.bad_record_offset() {
+                    Some(offset)
+                        if Some(index) == last_written && !record_follows(&path, offset)? =>
+                    {
+                        torn = Some(offset);
+                        break;
+                    }
+                    _ => return Err(e),
                 }
             }
+            // Cut it off, so it isn't mistaken for corruption once later Wals hold records.
+            if let Some(offset) = torn {
+                warn!(
+                    "cutting off torn record at {} in {}",
+                    offset,
+                    path.display()
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(offset)?;
+                file.sync_all()?;
+            }
         }
-        Ok(memtable)
+        Ok((memtable, live_wals))
     }
 
-    pub(crate) fn recover_sstable(&self, dir: &str) -> Result<Vec<SSTable>, StorageEngineError> {
+    /// Open the live SSTables of the version, level by level, with L0 oldest first.
+    pub(crate) fn recover_sstable(
+        &self,
+        dir: &str,
+        version: &Version,
+        compression: &CompressionOptions,
+    ) -> Result<Vec<SSTable>, StorageEngineError> {
         let mut table_vec = Vec::new();
-        let (table_epochs, mut table_map) = self.recover_files(dir, false)?;
-        for epoch in table_epochs.into_iter() {
-            match table_map.remove_entry(&epoch) {
-                Some((_, path)) => table_vec.push(SSTable::from_file(path)?),
-                None => return Err(StorageEngineError::FailedSSTableCreation(epoch)),
+        for (level, ids) in version.levels.iter().enumerate() {
+            for id in ids {
+                table_vec.push(SSTable::from_file(dir, *id, level, compression)?);
             }
         }
         Ok(table_vec)
     }
 
-    fn recover_files(
+    /// Remove the tables, Wals and temp files the version doesn't list.
+    /// A crash can leave behind the tables an installed merge replaced,
+    /// the tables of a merge that was never installed, and flushed Wals.
+    /// Only the files of IDs the manifest reserved are removed, any other file of the kind is an error:
+    /// a directory written without a manifest is never wiped.
+    pub(crate) fn remove_obsolete_files(
         &self,
         dir: &str,
-        wal: bool,
-    ) -> Result<(Vec<u128>, HashMap<u128, PathBuf>), StorageEngineError> {
-        let dir = PathBuf::from_str(dir)?;
-        let mut wal_epochs = Vec::new();
-        let mut wal_map = HashMap::new();
-        for entry in dir.read_dir()? {
+        version: &Version,
+    ) -> Result<(), StorageEngineError> {
+        let live: HashSet<PathBuf> = version
+            .levels
+            .iter()
+            .flatten()
+            .map(|id| SSTable::file_path(Path::new(dir), *id))
+            .chain(version.wals.iter().map(|id| Wal::file_path(dir, *id)))
+            .collect();
+        for entry in Path::new(dir).read_dir()? {
             let path = entry?.path();
-            if let Some(extension) = path.extension().and_then(|s| s.to_str()) {
-                let ext = match wal {
-                    true => "wal",
-                    false => "table",
-                };
-                if extension == ext {
-                    let filename = match path.file_name().and_then(|f| f.to_str()) {
-                        Some(path) => path,
-                        None => return Err(StorageEngineError::InvalidWalFilePath(path)),
-                    };
-                    let epoch = filename
-                        .split(|c| (c == '-') || (c == '.'))
-                        .collect::<Vec<&str>>()[1]
-                        .parse::<u128>()?;
-                    wal_epochs.push(epoch);
-                    wal_map.insert(epoch, path);
+            let extension = path.extension().and_then(|s| s.to_str());
+            let obsolete = match extension {
+                Some("table") | Some("wal") => !live.contains(&path),
+                Some(extension) => extension == SSTable::TEMP_EXTENSION,
+                None => false,
+            };
+            if !obsolete {
+                continue;
+            }
+            match Self::file_id(&path) {
+                Some(id) if id < version.next_file_id => {
+                    info!("removing obsolete file {}", path.display());
+                    fs::remove_file(&path)?;
                 }
+                // The temp files of the manifest and the aggregates are rewritten before they are used.
+                None if extension == Some(SSTable::TEMP_EXTENSION) => {}
+                _ => return Err(StorageEngineError::UnknownFile(path)),
             }
         }
-        wal_epochs.sort();
-        Ok((wal_epochs, wal_map))
+        Ok(())
+    }
+
+    /// Get the ID of a table, Wal or table temp file from its name.
+    fn file_id(path: &Path) -> Option<u64> {
+        let name = path.file_name()?.to_str()?;
+        let (stem, _) = name.split_once('.')?;
+        let (_, id) = stem.rsplit_once('-')?;
+        id.parse().ok()
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::{
-        env,
         fs::{self, OpenOptions},
-        path,
-        time::UNIX_EPOCH,
+        io::Write,
+        path::Path,
+        slice,
+    };
+
+    use crate::{
+        storage::{Manifest, ManifestEdit, MemTable, MergeOutput, SSTable, TableFile, Wal},
+        Action, CompressionOptions, Durability, Event, FilterOptions, StorageEngineError,
+        StorageMode,
     };
 
     use super::Recovery;
 
+    /// An empty directory for the test, unique to this process so concurrent test runs don't share it.
+    fn test_dir(name: &str) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("rdeebee-recovery-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    /// Start a Wal and record it in the manifest.
+    fn new_wal(manifest: &mut Manifest, dir: &str) -> Wal {
+        let wal = Wal::new(
+            dir,
+            manifest.reserve_file_ids(1).unwrap().start,
+            Durability::Sync,
+            &CompressionOptions::default(),
+        )
+        .unwrap();
+        manifest
+            .append(ManifestEdit {
+                new_wals: vec![wal.id()],
+                ..Default::default()
+            })
+            .unwrap();
+        wal
+    }
+
+    /// Flush the MemTable into a table and record it in the manifest.
+    fn flush(
+        manifest: &mut Manifest,
+        dir: &str,
+        memtable: MemTable,
+        log_number: Option<u64>,
+    ) -> SSTable {
+        let codec = CompressionOptions::default().table_codec;
+        let fp_rate = FilterOptions::default().fp_rate;
+        let id = manifest.reserve_file_ids(1).unwrap().start;
+        let mut sstable = SSTable::from_memtable(dir, id, memtable, codec, fp_rate).unwrap();
+        sstable.save_to_disk().unwrap();
+        manifest
+            .append(ManifestEdit {
+                added: vec![TableFile {
+                    id: sstable.id(),
+                    level: 0,
+                }],
+                log_number,
+                ..Default::default()
+            })
+            .unwrap();
+        sstable
+    }
+
     #[test]
     fn recovery_test() {
+        let dir = &test_dir("torn");
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+        let mut wal = new_wal(&mut manifest, dir);
+        for seq in 0..3 {
+            wal.add_events(&[Event::new(Action::Write, seq)]).unwrap();
+        }
+        wal.flush().unwrap();
+
+        // Simulate a crash in the middle of appending a record.
+        let mut file = OpenOptions::new().append(true).open(wal.path()).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+
         let recovery = Recovery {};
-        let memtable = recovery.recover_memtable("/tmp").unwrap();
+        let (memtable, live_wals) = recovery
+            .recover_memtable(
+                dir,
+                StorageMode::History,
+                manifest.version(),
+                &CompressionOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(live_wals, vec![wal.path()]);
         for event in &memtable {
             println!("Event: {}", event);
         }
+        assert_eq!((&memtable).into_iter().count(), 3);
         println!("Recovery succesful");
     }
+
+    #[test]
+    fn recovery_corrupt_tail_test() {
+        let dir = &test_dir("corrupt-tail");
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+        let mut wal = new_wal(&mut manifest, dir);
+        for seq in 0..3 {
+            wal.add_events(&[Event::new(Action::Write, seq)]).unwrap();
+        }
+        wal.flush().unwrap();
+        let last_record = fs::metadata(wal.path()).unwrap().len();
+        wal.add_events(&[Event::new(Action::Write, 3)]).unwrap();
+        wal.flush().unwrap();
+        // The Wal started when the database is opened again holds no records.
+        let empty = new_wal(&mut manifest, dir);
+
+        // Simulate a crash that garbled the last record rather than cutting it short.
+        let mut bytes = fs::read(wal.path()).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(wal.path(), &bytes).unwrap();
+
+        let recovery = Recovery {};
+        let (memtable, live_wals) = recovery
+            .recover_memtable(
+                dir,
+                StorageMode::History,
+                manifest.version(),
+                &CompressionOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(live_wals, vec![wal.path(), empty.path()]);
+        assert_eq!((&memtable).into_iter().count(), 3);
+        assert_eq!(fs::metadata(wal.path()).unwrap().len(), last_record);
+    }
+
+    #[test]
+    fn recovery_corrupt_record_test() {
+        let dir = &test_dir("corrupt-record");
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+        let mut wal = new_wal(&mut manifest, dir);
+        wal.add_events(&[Event::new(Action::Write, 0)]).unwrap();
+        wal.flush().unwrap();
+        let corrupt = fs::metadata(wal.path()).unwrap().len() as usize;
+        for seq in 1..3 {
+            wal.add_events(&[Event::new(Action::Write, seq)]).unwrap();
+        }
+        wal.flush().unwrap();
+
+        // Corruption followed by valid records isn't a torn write.
+        let mut bytes = fs::read(wal.path()).unwrap();
+        bytes[corrupt + 9] ^= 0xff;
+        fs::write(wal.path(), &bytes).unwrap();
+
+        let recovery = Recovery {};
+        assert!(matches!(
+            recovery.recover_memtable(
+                dir,
+                StorageMode::History,
+                manifest.version(),
+                &CompressionOptions::default(),
+            ),
+            Err(StorageEngineError::ChecksumMismatch(_, offset)) if offset == corrupt as u64
+        ));
+        assert_eq!(fs::read(wal.path()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn recovery_flushed_wal_test() {
+        let dir = &test_dir("flushed");
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+        let mut memtable = MemTable::new(StorageMode::History);
+        let mut flushed = new_wal(&mut manifest, dir);
+        for seq in 0..3 {
+            let event = Event::new(Action::Write, seq);
+            flushed.add_events(slice::from_ref(&event)).unwrap();
+            memtable.insert(event);
+        }
+        let mut live = new_wal(&mut manifest, dir);
+        live.add_events(&[Event::new(Action::Write, 3)]).unwrap();
+
+        // Simulate a crash after the SSTable is installed, but before its wal is retired.
+        flush(&mut manifest, dir, memtable, Some(live.id()));
+
+        let recovery = Recovery {};
+        let version = Manifest::open(Path::new(dir)).unwrap().version().clone();
+        recovery.remove_obsolete_files(dir, &version).unwrap();
+        let (memtable, live_wals) = recovery
+            .recover_memtable(
+                dir,
+                StorageMode::History,
+                &version,
+                &CompressionOptions::default(),
+            )
+            .unwrap();
+        assert_eq!((&memtable).into_iter().count(), 1);
+        assert_eq!(live_wals, vec![live.path()]);
+        assert!(!flushed.path().exists());
+        assert_eq!(
+            recovery
+                .recover_sstable(dir, &version, &CompressionOptions::default())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn recovery_interrupted_merge_test() {
+        let dir = &test_dir("merge");
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+        let mut tables = Vec::new();
+        for seq in 0..2 {
+            let mut memtable = MemTable::new(StorageMode::Latest);
+            memtable.insert(Event::new(Action::Write, seq));
+            tables.push(flush(&mut manifest, dir, memtable, None));
+        }
+        let tables: Vec<&SSTable> = tables.iter().collect();
+        let mut merge = || {
+            let file_ids = manifest.reserve_file_ids(1).unwrap();
+            let codec = CompressionOptions::default().table_codec;
+            let fp_rate = FilterOptions::default().fp_rate;
+            let output = MergeOutput::new(Path::new(dir), 1, u64::MAX, file_ids, codec, fp_rate);
+            SSTable::merge(&tables, output, StorageMode::Latest, true).unwrap()
+        };
+
+        // A merge that crashed before it was installed, and one that crashed after.
+        let orphans = merge();
+        let installed = merge();
+        manifest
+            .append(ManifestEdit {
+                added: installed
+                    .iter()
+                    .map(|table| TableFile {
+                        id: table.id(),
+                        level: 1,
+                    })
+                    .collect(),
+                removed: tables.iter().map(|table| table.id()).collect(),
+                ..Default::default()
+            })
+            .unwrap();
+        let temp_id = manifest.reserve_file_ids(1).unwrap().start;
+        let temp = Path::new(dir).join(format!("rdeebee-{}.table.tmp", temp_id));
+        fs::write(&temp, b"half written").unwrap();
+
+        let recovery = Recovery {};
+        let version = Manifest::open(Path::new(dir)).unwrap().version().clone();
+        recovery.remove_obsolete_files(dir, &version).unwrap();
+        let recovered = recovery
+            .recover_sstable(dir, &version, &CompressionOptions::default())
+            .unwrap();
+        let ids: Vec<u64> = recovered.iter().map(|table| table.id()).collect();
+        let expected: Vec<u64> = installed.iter().map(|table| table.id()).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(recovered[0].level(), 1);
+        assert_eq!(recovered[0].iter().unwrap().count(), 2);
+        assert!(!temp.exists());
+        for table in orphans.iter().chain(tables) {
+            assert!(!SSTable::file_path(Path::new(dir), table.id()).exists());
+        }
+    }
+
+    #[test]
+    fn recovery_unknown_files_test() {
+        let dir = &test_dir("unknown");
+        let mut memtable = MemTable::new(StorageMode::History);
+        memtable.insert(Event::new(Action::Write, 0));
+        let codec = CompressionOptions::default().table_codec;
+        let fp_rate = FilterOptions::default().fp_rate;
+        let mut sstable = SSTable::from_memtable(dir, 1, memtable, codec, fp_rate).unwrap();
+        sstable.save_to_disk().unwrap();
+        let path = SSTable::file_path(Path::new(dir), sstable.id());
+
+        // A directory written without a manifest is left alone, every time it is opened.
+        let recovery = Recovery {};
+        for _ in 0..2 {
+            let version = Manifest::open(Path::new(dir)).unwrap().version().clone();
+            assert!(matches!(
+                recovery.remove_obsolete_files(dir, &version),
+                Err(StorageEngineError::UnknownFile(_))
+            ));
+            assert!(path.exists());
+        }
+
+        // Once the manifest reserved the ID, the table is left over from a crash.
+        fs::remove_file(Path::new(dir).join("MANIFEST")).unwrap();
+        let mut manifest = Manifest::open(Path::new(dir)).unwrap();
+        manifest.reserve_file_ids(1).unwrap();
+        recovery
+            .remove_obsolete_files(dir, manifest.version())
+            .unwrap();
+        assert!(!path.exists());
+    }
 }
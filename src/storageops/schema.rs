@@ -0,0 +1,303 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    sync::Arc,
+};
+
+use serde_json::Value;
+
+use crate::{Event, EventMetadata, StorageEngineError};
+
+/// A contract on the payloads of an event type.
+/// Register one for every version of the type in a `SchemaRegistry`.
+pub trait Schema: Debug + Send + Sync {
+    /// Check the payload, describing the first violation found.
+    fn validate(&self, payload: &[u8]) -> Result<(), String>;
+}
+
+/// Checks JSON payloads against a subset of JSON Schema:
+/// `type`, `enum`, `properties`, `required`, `additionalProperties` (as a boolean) and `items`.
+/// Other keywords are ignored.
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    schema: Value,
+}
+
+impl JsonSchema {
+    pub fn new(schema: Value) -> Self {
+        Self { schema }
+    }
+
+    pub fn parse(schema: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self::new(serde_json::from_str(schema)?))
+    }
+
+    fn check(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+        if let Some(types) = schema.get("type") {
+            let matches = match types {
+                Value::String(name) => Self::is_type(name, value),
+                Value::Array(names) => names
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|name| Self::is_type(name, value)),
+                _ => true,
+            };
+            if !matches {
+                return Err(format!("{path}: expected type {types}"));
+            }
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                return Err(format!("{path}: {value} is not allowed"));
+            }
+        }
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::Array(required)) = schema.get("required") {
+                    for name in required.iter().filter_map(Value::as_str) {
+                        if !object.contains_key(name) {
+                            return Err(format!("{path}: missing property {name}"));
+                        }
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                let closed = schema.get("additionalProperties") == Some(&Value::Bool(false));
+                for (name, field) in object {
+                    match properties.and_then(|properties| properties.get(name)) {
+                        Some(property) => Self::check(property, field, &format!("{path}.{name}"))?,
+                        None if closed => {
+                            return Err(format!("{path}: unexpected property {name}"))
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (index, item) in items.iter().enumerate() {
+                        Self::check(item_schema, item, &format!("{path}[{index}]"))?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn is_type(name: &str, value: &Value) -> bool {
+        match name {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => false,
+        }
+    }
+}
+
+impl Schema for JsonSchema {
+    fn validate(&self, payload: &[u8]) -> Result<(), String> {
+        let value: Value =
+            serde_json::from_slice(payload).map_err(|e| format!("invalid JSON: {e}"))?;
+        Self::check(&self.schema, &value, "$")
+    }
+}
+
+/// Turns the payload of an event from one version of its type into the next.
+pub type Upcaster = Arc<dyn Fn(Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync>;
+
+/// The schemas of the event types, by version, and the upcasters between the versions.
+/// Writes of a registered type are checked against the schema of their version,
+/// version 0 standing for the latest one.
+/// Reads bring older events up to the latest version, so they come back in the current shape.
+/// Event types without schemas are neither checked nor upcast.
+#[derive(Clone, Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, BTreeMap<u32, Arc<dyn Schema>>>,
+    /// By event type, then by the version upcast from.
+    upcasters: HashMap<String, HashMap<u32, Upcaster>>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the schema of a version of the event type. Versions count from 1.
+    pub fn register<S: Schema + 'static>(
+        &mut self,
+        event_type: &str,
+        version: u32,
+        schema: S,
+    ) -> &mut Self {
+        assert!(version > 0, "schema versions count from 1");
+        self.schemas
+            .entry(event_type.to_string())
+            .or_default()
+            .insert(version, Arc::new(schema));
+        self
+    }
+
+    /// Register the upcaster from a version of the event type to the next one.
+    pub fn register_upcaster<F>(
+        &mut self,
+        event_type: &str,
+        from_version: u32,
+        upcaster: F,
+    ) -> &mut Self
+    where
+        F: Fn(Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    {
+        self.upcasters
+            .entry(event_type.to_string())
+            .or_default()
+            .insert(from_version, Arc::new(upcaster));
+        self
+    }
+
+    /// Get the latest version registered for the event type.
+    pub fn latest_version(&self, event_type: &str) -> Option<u32> {
+        let versions = self.schemas.get(event_type)?;
+        versions.keys().next_back().copied()
+    }
+
+    /// Check the payload of a write against the schema of its type and version,
+    /// setting version 0 to the latest one.
+    pub(crate) fn validate(
+        &self,
+        metadata: &mut EventMetadata,
+        payload: &[u8],
+    ) -> Result<(), String> {
+        let latest = match self.latest_version(&metadata.event_type) {
+            Some(latest) => latest,
+            None => return Ok(()),
+        };
+        if metadata.schema_version == 0 {
+            metadata.schema_version = latest;
+        }
+        match self.schemas[&metadata.event_type].get(&metadata.schema_version) {
+            Some(schema) => schema.validate(payload),
+            None => Err(format!(
+                "no schema for version {} of {}",
+                metadata.schema_version, metadata.event_type
+            )),
+        }
+    }
+
+    /// Bring the payload of the event up to the latest version of its type, one upcaster at a time.
+    /// Stops short at a version without an upcaster.
+    pub(crate) fn upcast(&self, event: &mut Event) -> Result<(), StorageEngineError> {
+        let mut metadata = event.metadata().clone();
+        let latest = self.latest_version(&metadata.event_type).unwrap_or(0);
+        // Events written before the type was registered carry no version.
+        if metadata.schema_version == 0 || metadata.schema_version >= latest {
+            return Ok(());
+        }
+        let (upcasters, mut payload) =
+            match (self.upcasters.get(&metadata.event_type), event.payload()) {
+                (Some(upcasters), Some(payload)) => (upcasters, payload),
+                _ => return Ok(()),
+            };
+        while metadata.schema_version < latest {
+            let upcaster = match upcasters.get(&metadata.schema_version) {
+                Some(upcaster) => upcaster,
+                None => break,
+            };
+            payload = upcaster(payload).map_err(|e| {
+                StorageEngineError::UpcastFailed(
+                    metadata.event_type.clone(),
+                    metadata.schema_version,
+                    e,
+                )
+            })?;
+            metadata.schema_version += 1;
+        }
+        event.set_payload(Some(payload));
+        event.set_metadata(metadata);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+
+    use super::{JsonSchema, Schema, SchemaRegistry};
+    use crate::{Action, Event, EventMetadata};
+
+    #[test]
+    fn json_schema_test() {
+        let schema = JsonSchema::new(json!({
+            "type": "object",
+            "required": ["id", "items"],
+            "additionalProperties": false,
+            "properties": {
+                "id": {"type": "integer"},
+                "status": {"enum": ["open", "paid"]},
+                "items": {"type": "array", "items": {"type": "string"}},
+            },
+        }));
+        assert!(schema
+            .validate(br#"{"id": 1, "items": ["book"], "status": "paid"}"#)
+            .is_ok());
+        for payload in [
+            &br#"{"id": 1}"#[..],
+            br#"{"id": "1", "items": []}"#,
+            br#"{"id": 1, "items": [2]}"#,
+            br#"{"id": 1, "items": [], "status": "lost"}"#,
+            br#"{"id": 1, "items": [], "note": ""}"#,
+            b"not json",
+        ] {
+            assert!(schema.validate(payload).is_err());
+        }
+    }
+
+    #[test]
+    fn upcast_test() {
+        let mut registry = SchemaRegistry::new();
+        registry
+            .register("Renamed", 1, JsonSchema::new(json!({"required": ["name"]})))
+            .register(
+                "Renamed",
+                2,
+                JsonSchema::new(json!({"required": ["first", "last"]})),
+            )
+            .register_upcaster("Renamed", 1, |payload| {
+                let v1: Value = serde_json::from_slice(&payload).map_err(|e| e.to_string())?;
+                let name = v1["name"].as_str().unwrap_or_default();
+                let (first, last) = name.split_once(' ').unwrap_or((name, ""));
+                Ok(json!({"first": first, "last": last})
+                    .to_string()
+                    .into_bytes())
+            });
+
+        let mut metadata = EventMetadata {
+            event_type: "Renamed".to_string(),
+            schema_version: 1,
+            ..Default::default()
+        };
+        let payload = br#"{"name": "Ada Lovelace"}"#.to_vec();
+        registry.validate(&mut metadata, &payload).unwrap();
+        let mut event = Event::new(Action::Write, 1);
+        event.set_payload(Some(payload));
+        event.set_metadata(metadata);
+
+        registry.upcast(&mut event).unwrap();
+        assert_eq!(event.metadata().schema_version, 2);
+        let v2: Value = serde_json::from_slice(&event.payload().unwrap()).unwrap();
+        assert_eq!(v2, json!({"first": "Ada", "last": "Lovelace"}));
+
+        // Version 0 is the latest one.
+        let mut metadata = EventMetadata {
+            event_type: "Renamed".to_string(),
+            ..Default::default()
+        };
+        assert!(registry
+            .validate(&mut metadata, br#"{"name": "Ada"}"#)
+            .is_err());
+        assert_eq!(metadata.schema_version, 2);
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use im::OrdMap;
+use protobuf::EnumOrUnknown;
+use tracing::error;
+use uuid::Uuid;
+
+use crate::{
+    event_response,
+    storage::{MemTable, Order},
+    wire_format::operation::{Response, Status},
+    Action, Event, EventSource, IndexEntry, KeyStream, Levels, OrderSource, OrderedEntries, Pages,
+    SchemaRegistry, StorageEngineError,
+};
+
+/// The MemTables and SSTables a read goes through.
+/// Events come back upcast to the latest version of their type.
+pub(crate) struct ReadView<'a> {
+    /// Oldest first.
+    pub(crate) memtables: Vec<&'a MemTable>,
+    pub(crate) levels: &'a Levels,
+    pub(crate) schemas: &'a Arc<SchemaRegistry>,
+}
+
+impl ReadView<'_> {
+    /// Find the latest event of the ID, checking the newest data first.
+    pub(crate) fn get(&self, id: Uuid) -> Result<Option<Event>, StorageEngineError> {
+        let mut latest = None;
+        for memtable in self.memtables.iter().rev() {
+            latest = memtable.get_event(id);
+            if latest.is_some() {
+                break;
+            }
+        }
+        if latest.is_none() {
+            for table in self.levels.candidates(id).into_iter().rev() {
+                latest = table.get(id)?;
+                if latest.is_some() {
+                    break;
+                }
+            }
+        }
+        if let Some(event) = latest.as_mut() {
+            self.schemas.upcast(event)?;
+        }
+        Ok(latest)
+    }
+
+    /// Find the latest event of the ID with a sequence number up to `seq`, one lookup in every source.
+    /// Events can be written out of sequence order, so a newer source may hold a lower sequence number:
+    /// the highest sequence number wins, and the newest source breaks ties, as in `KeyStream`.
+    pub(crate) fn get_up_to(
+        &self,
+        id: Uuid,
+        seq: u64,
+    ) -> Result<Option<Event>, StorageEngineError> {
+        let mut latest: Option<Event> = None;
+        let mut keep = |event: Option<Event>| {
+            if let Some(event) = event {
+                if latest
+                    .as_ref()
+                    .is_none_or(|latest| event.sequence_num() > latest.sequence_num())
+                {
+                    latest = Some(event);
+                }
+            }
+        };
+        for memtable in self.memtables.iter().rev() {
+            keep(memtable.get_event_up_to(id, seq));
+        }
+        for table in self.levels.candidates(id).into_iter().rev() {
+            keep(table.get_up_to(id, seq)?);
+        }
+        if let Some(event) = latest.as_mut() {
+            self.schemas.upcast(event)?;
+        }
+        Ok(latest)
+    }
+
+    /// Find the event of the ID with the sequence number and commit position, checking the newest data first.
+    pub(crate) fn get_at(
+        &self,
+        id: Uuid,
+        seq: u64,
+        position: u64,
+    ) -> Result<Option<Event>, StorageEngineError> {
+        let mut found = self
+            .memtables
+            .iter()
+            .rev()
+            .find_map(|memtable| memtable.get_event_at(id, seq, position));
+        if found.is_none() {
+            for table in self.levels.candidates(id).into_iter().rev() {
+                found = table.get_at(id, seq, position)?;
+                if found.is_some() {
+                    break;
+                }
+            }
+        }
+        if let Some(event) = found.as_mut() {
+            self.schemas.upcast(event)?;
+        }
+        Ok(found)
+    }
+
+    /// Lazily stream the events of the ID with sequence numbers within the range.
+    /// The SSTables are opened up front, so the stream survives later flushes and merges.
+    pub(crate) fn stream<R: RangeBounds<u64>>(
+        &self,
+        key: &str,
+        id: Uuid,
+        range: R,
+    ) -> Result<KeyStream, StorageEngineError> {
+        let from_seq = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let mut sources: Vec<EventSource> = Vec::new();
+        for table in self.levels.candidates(id) {
+            sources.push(Box::new(table.stream(id, from_seq)?));
+        }
+        // The MemTable events are already in memory, so copying them is bounded by the MemTable size.
+        for memtable in &self.memtables {
+            sources.push(Box::new(memtable.get_stream(id).into_iter().map(Ok)));
+        }
+        Ok(KeyStream::new(key, sources, range)?.upcast_with(self.schemas.clone()))
+    }
+
+    /// Lazily merge the order indexes of the MemTables and SSTables, from the key on.
+    /// The SSTables are opened up front, the MemTable entries are copied and sorted.
+    pub(crate) fn ordered(
+        &self,
+        order: Order,
+        from: (u64, u64),
+    ) -> Result<OrderedEntries, StorageEngineError> {
+        let mut sources: Vec<OrderSource> = Vec::new();
+        for table in self.levels.iter() {
+            sources.push(Box::new(table.ordered(order, from)?));
+        }
+        for memtable in &self.memtables {
+            sources.push(Box::new(memtable.ordered(order, from).into_iter().map(Ok)));
+        }
+        OrderedEntries::new(order, sources)
+    }
+
+    /// Read the events the index entries point at, in the order given, one point read each.
+    /// Events no longer stored, such as those a merge dropped, are skipped.
+    pub(crate) fn read_indexed(
+        &self,
+        entries: &[IndexEntry],
+    ) -> Result<Vec<Response>, StorageEngineError> {
+        let mut responses = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if let Some(event) = self.get_at(entry.id, entry.seq, entry.position)? {
+                responses.push(event_response(entry.key, &event));
+            }
+        }
+        Ok(responses)
+    }
+}
+
+/// A consistent view of the database, handed out by `RDeeBee::snapshot`.
+/// It pins the MemTables, the SSTables and the key directory as they were,
+/// so reads through it see none of the writes, flushes or compactions since.
+/// The MemTable and key directory are persistent maps, so pinning them costs the writes made meanwhile
+/// a copy of the path to their entries, not of the whole maps.
+/// The SSTables it pins stay on disk until it is dropped.
+#[derive(Clone)]
+pub struct Snapshot {
+    seq: u64,
+    key_to_id_map: OrdMap<String, Uuid>,
+    /// Oldest first.
+    memtables: Vec<Arc<MemTable>>,
+    levels: Levels,
+    schemas: Arc<SchemaRegistry>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        seq: u64,
+        key_to_id_map: OrdMap<String, Uuid>,
+        memtables: Vec<Arc<MemTable>>,
+        levels: Levels,
+        schemas: Arc<SchemaRegistry>,
+    ) -> Self {
+        Self {
+            seq,
+            key_to_id_map,
+            memtables,
+            levels,
+            schemas,
+        }
+    }
+
+    /// Get the highest sequence number written before the snapshot was taken.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    fn view(&self) -> ReadView<'_> {
+        ReadView {
+            memtables: self
+                .memtables
+                .iter()
+                .map(|memtable| memtable.as_ref())
+                .collect(),
+            levels: &self.levels,
+            schemas: &self.schemas,
+        }
+    }
+
+    /// Get the latest event of the key as of the snapshot.
+    pub fn get_event_by_key(&self, key: &str) -> Response {
+        let mut response = Response::new();
+        response.key = key.to_string();
+        let event = match self.key_to_id_map.get(key) {
+            Some(id) => self.view().get(*id),
+            None => Ok(None),
+        };
+        match event {
+            Ok(Some(event)) => return event_response(key, &event),
+            Ok(None) => response.status = EnumOrUnknown::new(Status::Invalid_Key),
+            Err(e) => {
+                error!("failed to read table: {}", e);
+                response.status = EnumOrUnknown::new(Status::Server_Error);
+            }
+        }
+        response
+    }
+
+    /// Lazily stream the events of a key as of the snapshot, with sequence numbers within the range.
+    /// Returns None if the key didn't exist.
+    pub fn stream_by_key<R: RangeBounds<u64>>(
+        &self,
+        key: &str,
+        range: R,
+    ) -> Result<Option<KeyStream>, StorageEngineError> {
+        match self.key_to_id_map.get(key) {
+            Some(id) => Ok(Some(self.view().stream(key, *id, range)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Scan the keys within the range in ascending order, as of the snapshot.
+    /// Yields the latest event of every key, skipping the deleted ones.
+    /// Every key in the range costs a point read, deleted ones included, see `KeyScan`.
+    pub fn scan<'a, R: RangeBounds<&'a str>>(&self, range: R) -> KeyScan {
+        let start = range.start_bound().map(|key| key.to_string());
+        let end = range.end_bound().map(|key| key.to_string());
+        KeyScan::new(self.clone(), start, end, None)
+    }
+
+    /// Scan the keys starting with the prefix in ascending order, as of the snapshot.
+    /// Like `scan`, every key with the prefix costs a point read.
+    pub fn scan_prefix(&self, prefix: &str) -> KeyScan {
+        let start = Bound::Included(prefix.to_string());
+        KeyScan::new(
+            self.clone(),
+            start,
+            Bound::Unbounded,
+            Some(prefix.to_string()),
+        )
+    }
+}
+
+/// A lazy scan over a range of keys, in ascending order of keys.
+/// It owns a snapshot, so it sees none of the writes made after it was opened.
+/// The keys come from the key directory, the tables are sorted by ID, so every key is a point read:
+/// a filter check and at most a block read in each table that may hold the ID, newest first, until one does.
+/// A scan of n keys costs n lookups rather than a single pass over the tables.
+/// A read error is returned once and ends the scan.
+pub struct KeyScan {
+    snapshot: Snapshot,
+    /// The bounds of the keys left, moved past every key returned.
+    start: Bound<String>,
+    end: Bound<String>,
+    prefix: Option<String>,
+}
+
+impl KeyScan {
+    fn new(
+        snapshot: Snapshot,
+        start: Bound<String>,
+        end: Bound<String>,
+        prefix: Option<String>,
+    ) -> Self {
+        Self {
+            snapshot,
+            start,
+            end,
+            prefix,
+        }
+    }
+
+    /// Group the scan into pages of `page_size` responses.
+    pub fn pages(self, page_size: usize) -> Pages<KeyScan> {
+        Pages::new(self, page_size)
+    }
+
+    /// Take the next key in the bounds, if any.
+    fn next_key(&mut self) -> Option<(String, Uuid)> {
+        let start = self.start.as_ref().map(String::as_str);
+        let end = self.end.as_ref().map(String::as_str);
+        // Bounds that cross select no keys.
+        let empty = match (start, end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start >= end,
+            _ => false,
+        };
+        if empty {
+            return None;
+        }
+        let (key, id) = self
+            .snapshot
+            .key_to_id_map
+            .range::<_, str>((start, end))
+            .next()?;
+        if let Some(prefix) = &self.prefix {
+            if !key.starts_with(prefix.as_str()) {
+                return None;
+            }
+        }
+        let (key, id) = (key.clone(), *id);
+        self.start = Bound::Excluded(key.clone());
+        Some((key, id))
+    }
+}
+
+impl Iterator for KeyScan {
+    type Item = Result<Response, StorageEngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((key, id)) = self.next_key() {
+            match self.snapshot.view().get(id) {
+                Ok(Some(event)) if event.action() != &Action::Delete => {
+                    return Some(Ok(event_response(&key, &event)))
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    self.end = Bound::Excluded(key);
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
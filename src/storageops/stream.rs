@@ -0,0 +1,350 @@
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use futures_util::{stream, Stream};
+use protobuf::{EnumOrUnknown, MessageField};
+
+use crate::{
+    storage::{Order, OrderEntry},
+    wire_format::operation::{Operation, Response, Status},
+    Action, Event, SchemaRegistry, StorageEngineError,
+};
+
+pub(crate) type EventSource = Box<dyn Iterator<Item = Result<Event, StorageEngineError>> + Send>;
+pub(crate) type OrderSource =
+    Box<dyn Iterator<Item = Result<OrderEntry, StorageEngineError>> + Send>;
+
+/// A lazy stream of the events of one key, in ascending order of sequence numbers.
+/// It merges the MemTable and the SSTables, reading the tables one event at a time.
+/// Only events with a sequence number within the bounds, and past the version to skip, are returned.
+/// A read error is returned once and ends the stream.
+pub struct KeyStream {
+    key: String,
+    sources: Vec<EventSource>,
+    heads: Vec<Option<Event>>,
+    start: Bound<u64>,
+    end: Bound<u64>,
+    /// Events up to this version of the stream are skipped.
+    after_version: Option<u64>,
+    schemas: Arc<SchemaRegistry>,
+}
+
+impl KeyStream {
+    /// Sources are ordered from oldest to newest.
+    /// When two sources hold the same sequence number, the older one is returned first.
+    pub(crate) fn new<R: RangeBounds<u64>>(
+        key: &str,
+        mut sources: Vec<EventSource>,
+        range: R,
+    ) -> Result<Self, StorageEngineError> {
+        let mut heads = Vec::with_capacity(sources.len());
+        for source in sources.iter_mut() {
+            heads.push(source.next().transpose()?);
+        }
+        Ok(Self {
+            key: key.to_string(),
+            sources,
+            heads,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            after_version: None,
+            schemas: Arc::default(),
+        })
+    }
+
+    /// Upcast the events to the latest version of their type.
+    pub(crate) fn upcast_with(mut self, schemas: Arc<SchemaRegistry>) -> Self {
+        self.schemas = schemas;
+        self
+    }
+
+    /// Skip the events up to the version of the stream, such as those an aggregate snapshot covers.
+    pub(crate) fn after_version(mut self, version: u64) -> Self {
+        self.after_version = Some(version);
+        self
+    }
+
+    /// Group the stream into pages of `page_size` responses.
+    pub fn pages(self, page_size: usize) -> KeyStreamPages {
+        Pages::new(self, page_size)
+    }
+
+    /// Take the head with the lowest sequence number and refill it from its source.
+    fn next_event(&mut self) -> Result<Option<Event>, StorageEngineError> {
+        let mut next: Option<(usize, u64)> = None;
+        for (index, head) in self.heads.iter().enumerate() {
+            if let Some(event) = head {
+                let seq = event.sequence_num();
+                match next {
+                    Some((_, lowest)) if lowest <= seq => {}
+                    _ => next = Some((index, seq)),
+                }
+            }
+        }
+        let index = match next {
+            Some((index, _)) => index,
+            None => return Ok(None),
+        };
+        let refill = self.sources[index].next().transpose()?;
+        Ok(std::mem::replace(&mut self.heads[index], refill))
+    }
+
+    /// Drop every source, ending the stream.
+    fn finish(&mut self) {
+        self.sources.clear();
+        self.heads.clear();
+    }
+
+    fn before_start(&self, seq: u64) -> bool {
+        match self.start {
+            Bound::Included(start) => seq < start,
+            Bound::Excluded(start) => seq <= start,
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn after_end(&self, seq: u64) -> bool {
+        match self.end {
+            Bound::Included(end) => seq > end,
+            Bound::Excluded(end) => seq >= end,
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl Iterator for KeyStream {
+    type Item = Result<Response, StorageEngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let mut event = match self.next_event() {
+                Ok(event) => event?,
+                Err(e) => {
+                    self.finish();
+                    return Some(Err(e));
+                }
+            };
+            let seq = event.sequence_num();
+            if self.before_start(seq) {
+                continue;
+            }
+            if self.after_end(seq) {
+                // Everything left is past the end bound as well.
+                self.finish();
+                return None;
+            }
+            if self
+                .after_version
+                .is_some_and(|version| event.version() <= version)
+            {
+                continue;
+            }
+            if let Err(e) = self.schemas.upcast(&mut event) {
+                self.finish();
+                return Some(Err(e));
+            }
+            return Some(Ok(event_response(&self.key, &event)));
+        }
+    }
+}
+
+/// A lazy merge of the order indexes of the MemTables and SSTables, sorted in the order.
+/// An event held by more than one source is returned once.
+/// A read error is returned once and ends the merge.
+pub(crate) struct OrderedEntries {
+    order: Order,
+    sources: Vec<OrderSource>,
+    heads: Vec<Option<OrderEntry>>,
+    last: Option<OrderEntry>,
+}
+
+impl OrderedEntries {
+    pub(crate) fn new(
+        order: Order,
+        mut sources: Vec<OrderSource>,
+    ) -> Result<Self, StorageEngineError> {
+        let mut heads = Vec::with_capacity(sources.len());
+        for source in sources.iter_mut() {
+            heads.push(source.next().transpose()?);
+        }
+        Ok(Self {
+            order,
+            sources,
+            heads,
+            last: None,
+        })
+    }
+
+    /// Take the head with the lowest key and refill it from its source.
+    fn next_entry(&mut self) -> Result<Option<OrderEntry>, StorageEngineError> {
+        let order = self.order;
+        let index = match self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(index, head)| head.as_ref().map(|entry| (index, order.key(entry))))
+            .min_by_key(|(_, key)| *key)
+        {
+            Some((index, _)) => index,
+            None => return Ok(None),
+        };
+        let refill = self.sources[index].next().transpose()?;
+        Ok(std::mem::replace(&mut self.heads[index], refill))
+    }
+}
+
+impl Iterator for OrderedEntries {
+    type Item = Result<OrderEntry, StorageEngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_entry() {
+                Ok(Some(entry)) if self.last == Some(entry) => continue,
+                Ok(entry) => {
+                    self.last = entry;
+                    return entry.map(Ok);
+                }
+                Err(e) => {
+                    self.sources.clear();
+                    self.heads.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Pages of a stream of responses, such as a `KeyStream` or a `KeyScan`.
+/// Every page but the last one holds exactly `page_size` responses.
+pub struct Pages<I> {
+    stream: I,
+    page_size: usize,
+}
+
+/// Pages of a `KeyStream`.
+pub type KeyStreamPages = Pages<KeyStream>;
+
+impl<I> Pages<I> {
+    pub(crate) fn new(stream: I, page_size: usize) -> Self {
+        Self {
+            stream,
+            page_size: page_size.max(1),
+        }
+    }
+}
+
+impl<I> Pages<I>
+where
+    I: Iterator<Item = Result<Response, StorageEngineError>> + Send + 'static,
+{
+    /// Turn the pages into an async `Stream`.
+    /// Each page is read on the blocking thread pool, so the SSTable reads do not stall the runtime.
+    pub fn into_async(self) -> impl Stream<Item = Result<Vec<Response>, StorageEngineError>> {
+        stream::unfold(Some(self), |pages| async move {
+            let mut pages = pages?;
+            let (page, pages) = tokio::task::spawn_blocking(move || (pages.next(), pages))
+                .await
+                .ok()?;
+            page.map(|page| (page, Some(pages)))
+        })
+    }
+}
+
+impl<I> Iterator for Pages<I>
+where
+    I: Iterator<Item = Result<Response, StorageEngineError>>,
+{
+    type Item = Result<Vec<Response>, StorageEngineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let page = self
+            .stream
+            .by_ref()
+            .take(self.page_size)
+            .collect::<Result<Vec<Response>, StorageEngineError>>();
+        match page {
+            Ok(page) if page.is_empty() => None,
+            page => Some(page),
+        }
+    }
+}
+
+/// Build the response carrying an event of the key.
+pub(crate) fn event_response(key: &str, event: &Event) -> Response {
+    let mut response = Response::new();
+    response.key = key.to_string();
+    response.status = EnumOrUnknown::new(Status::Ok);
+    response.seq = event.sequence_num();
+    response.version = event.version();
+    response.position = event.position();
+    response.metadata = MessageField::some(event.metadata().into());
+    response.op = match event.action() {
+        Action::Read => EnumOrUnknown::new(Operation::Read),
+        Action::Write => EnumOrUnknown::new(Operation::Write),
+        Action::Delete => EnumOrUnknown::new(Operation::Delete),
+    };
+    if let Some(payload) = event.payload() {
+        response.payload = payload;
+    }
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::{EventSource, KeyStream};
+    use crate::{Action, Event};
+
+    fn source(id: Uuid, seqs: &[u64]) -> EventSource {
+        let events: Vec<Event> = seqs
+            .iter()
+            .map(|seq| Event::with_id(id, Action::Write, *seq))
+            .collect();
+        Box::new(events.into_iter().map(Ok))
+    }
+
+    #[test]
+    fn key_stream_merge_test() {
+        let id = Uuid::new_v4();
+        let sources = vec![
+            source(id, &[1, 4, 9]),
+            source(id, &[2, 3]),
+            source(id, &[5, 10]),
+        ];
+        let seqs: Vec<u64> = KeyStream::new("key", sources, ..)
+            .unwrap()
+            .map(|res| res.unwrap().seq)
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5, 9, 10]);
+    }
+
+    #[test]
+    fn key_stream_bounds_test() {
+        let id = Uuid::new_v4();
+        let sources = vec![
+            source(id, &[1, 4, 9]),
+            source(id, &[2, 3]),
+            source(id, &[5, 10]),
+        ];
+        let seqs: Vec<u64> = KeyStream::new("key", sources, 3..=9)
+            .unwrap()
+            .map(|res| res.unwrap().seq)
+            .collect();
+        assert_eq!(seqs, vec![3, 4, 5, 9]);
+    }
+
+    #[test]
+    fn key_stream_pages_test() {
+        let id = Uuid::new_v4();
+        let sources = vec![source(id, &[1, 2, 3, 4, 5, 6, 7])];
+        let pages: Vec<usize> = KeyStream::new("key", sources, 2..)
+            .unwrap()
+            .pages(4)
+            .map(|page| page.unwrap().len())
+            .collect();
+        assert_eq!(pages, vec![4, 2]);
+    }
+}
@@ -0,0 +1,43 @@
+use crate::wire_format::operation::Response;
+
+/// Selects the events a change data capture subscription receives, see `RDeeBee::read_changes`.
+/// The default filter selects every event.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeFilter {
+    key_prefix: String,
+    event_type: Option<String>,
+}
+
+impl ChangeFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only select the events of the keys starting with the prefix.
+    pub fn key_prefix(mut self, prefix: &str) -> Self {
+        self.key_prefix = prefix.to_string();
+        self
+    }
+
+    /// Only select the events of the type.
+    pub fn event_type(mut self, event_type: &str) -> Self {
+        self.event_type = Some(event_type.to_string());
+        self
+    }
+
+    /// Can the events of the key be selected? Checked before the events are read.
+    pub(crate) fn matches_key(&self, key: &str) -> bool {
+        key.starts_with(&self.key_prefix)
+    }
+
+    pub(crate) fn matches(&self, response: &Response) -> bool {
+        let type_matches = match &self.event_type {
+            Some(wanted) => response
+                .metadata
+                .as_ref()
+                .is_some_and(|metadata| &metadata.event_type == wanted),
+            None => true,
+        };
+        type_matches && self.matches_key(&response.key)
+    }
+}